@@ -1,6 +1,7 @@
 use bevy::ecs::reflect::ReflectComponent;
-
+use bevy::math::{Vec2, Vec3};
 use bevy::reflect::Reflect;
+use bevy::window::WindowId;
 
 #[derive(Debug, Clone, Reflect)]
 #[reflect(Component)]
@@ -19,3 +20,210 @@ impl Default for PerspectiveCamera {
         }
     }
 }
+
+/// A camera with parallel rays, for top and side views in editors or isometric games.
+/// The rays start on the plane through the camera and go down its -Z axis.
+#[derive(Debug, Clone, Reflect)]
+#[reflect(Component)]
+pub struct OrthographicCamera {
+    /// Height of the view in world units. The width follows the aspect ratio of the viewport.
+    pub height: f32,
+    pub near: f32,
+    pub far: f32,
+}
+
+impl Default for OrthographicCamera {
+    fn default() -> Self {
+        Self {
+            height: 64.0,
+            near: 0.0,
+            far: 1000.0,
+        }
+    }
+}
+
+// The projection of either camera component. Perspective cameras win over orthographic ones
+// on the same entity.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Projection {
+    Perspective {
+        tan_half_fov: f32,
+        near: f32,
+        far: f32,
+    },
+    Orthographic {
+        half_height: f32,
+        near: f32,
+        far: f32,
+    },
+}
+
+impl Projection {
+    pub fn from_components(
+        perspective: Option<&PerspectiveCamera>,
+        orthographic: Option<&OrthographicCamera>,
+    ) -> Option<Self> {
+        match (perspective, orthographic) {
+            (Some(camera), _) => Some(Projection::Perspective {
+                tan_half_fov: (camera.fov / 2.0).tan(),
+                near: camera.near,
+                far: camera.far,
+            }),
+            (None, Some(camera)) => Some(Projection::Orthographic {
+                half_height: camera.height / 2.0,
+                near: camera.near,
+                far: camera.far,
+            }),
+            (None, None) => None,
+        }
+    }
+    // Rays are clipped to the distance from `near` to `far` along the -Z axis of the camera.
+    pub fn near(&self) -> f32 {
+        match *self {
+            Projection::Perspective { near, .. } | Projection::Orthographic { near, .. } => near,
+        }
+    }
+    pub fn far(&self) -> f32 {
+        match *self {
+            Projection::Perspective { far, .. } | Projection::Orthographic { far, .. } => far,
+        }
+    }
+    // Camera space origin and direction of the ray through a point of the viewport,
+    // matching the ray generation shader. The point is from -1 to 1, with y up.
+    // Perspective rays are not normalized.
+    pub fn camera_ray(&self, ndc: Vec2, aspect_ratio: f32) -> (Vec3, Vec3) {
+        let ndc = Vec2::new(ndc.x * aspect_ratio, ndc.y);
+        match *self {
+            Projection::Perspective { tan_half_fov, .. } => (
+                Vec3::ZERO,
+                Vec3::new(ndc.x * tan_half_fov, ndc.y * tan_half_fov, -1.0),
+            ),
+            Projection::Orthographic { half_height, .. } => (
+                Vec3::new(ndc.x * half_height, ndc.y * half_height, 0.0),
+                -Vec3::Z,
+            ),
+        }
+    }
+}
+
+/// The part of a window a camera renders to. Put it next to the camera component.
+/// Cameras without this component fill the primary window.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Viewport {
+    /// The window the camera renders to. Every window renders its own cameras.
+    pub window: WindowId,
+    /// Top left corner of the viewport, in fractions of the window size from the top left corner.
+    pub position: Vec2,
+    /// Size of the viewport, in fractions of the window size.
+    pub size: Vec2,
+    /// Viewports with a higher order are drawn over the ones with a lower order,
+    /// for example a minimap over the main view.
+    pub order: i32,
+}
+
+impl Default for Viewport {
+    fn default() -> Self {
+        Self {
+            window: WindowId::primary(),
+            position: Vec2::ZERO,
+            size: Vec2::ONE,
+            order: 0,
+        }
+    }
+}
+
+impl Viewport {
+    /// The viewport over the given fractions of the primary window.
+    pub fn new(position: Vec2, size: Vec2) -> Self {
+        Self {
+            position,
+            size,
+            ..Default::default()
+        }
+    }
+    /// The viewport over all of the given window.
+    pub fn full_window(window: WindowId) -> Self {
+        Self {
+            window,
+            ..Default::default()
+        }
+    }
+    /// Whether the point, in fractions of the window size from the top left corner, is inside.
+    pub fn contains(&self, point: Vec2) -> bool {
+        let max = self.position + self.size;
+        point.x >= self.position.x
+            && point.y >= self.position.y
+            && point.x < max.x
+            && point.y < max.y
+    }
+    /// Offset and extent of the viewport in pixels on a window of the given size.
+    /// None if the viewport doesn't cover any pixel.
+    pub fn physical_rect(&self, width: u32, height: u32) -> Option<([u32; 2], [u32; 2])> {
+        let window_size = Vec2::new(width as f32, height as f32);
+        let min = (self.position * window_size)
+            .round()
+            .clamp(Vec2::ZERO, window_size);
+        let max = ((self.position + self.size) * window_size)
+            .round()
+            .clamp(Vec2::ZERO, window_size);
+        if max.x <= min.x || max.y <= min.y {
+            return None;
+        }
+        let extent = max - min;
+        Some((
+            [min.x as u32, min.y as u32],
+            [extent.x as u32, extent.y as u32],
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Projection, Viewport};
+    use bevy::math::{Vec2, Vec3};
+
+    #[test]
+    fn test_camera_ray() {
+        let perspective = Projection::Perspective {
+            tan_half_fov: 0.5,
+            near: 0.1,
+            far: 100.0,
+        };
+        // Rays of a perspective camera start at the camera and spread out with the field of view.
+        let (origin, dir) = perspective.camera_ray(Vec2::ZERO, 2.0);
+        assert_eq!((origin, dir), (Vec3::ZERO, -Vec3::Z));
+        let (origin, dir) = perspective.camera_ray(Vec2::new(1.0, -1.0), 2.0);
+        assert_eq!((origin, dir), (Vec3::ZERO, Vec3::new(1.0, -0.5, -1.0)));
+
+        // Rays of an orthographic camera are parallel and start on the view plane.
+        let orthographic = Projection::Orthographic {
+            half_height: 4.0,
+            near: 0.0,
+            far: 100.0,
+        };
+        let (origin, dir) = orthographic.camera_ray(Vec2::ZERO, 2.0);
+        assert_eq!((origin, dir), (Vec3::ZERO, -Vec3::Z));
+        let (origin, dir) = orthographic.camera_ray(Vec2::new(1.0, -1.0), 2.0);
+        assert_eq!((origin, dir), (Vec3::new(8.0, -4.0, 0.0), -Vec3::Z));
+    }
+
+    #[test]
+    fn test_viewport() {
+        let full = Viewport::default();
+        assert_eq!(full.physical_rect(1280, 720), Some(([0, 0], [1280, 720])));
+
+        // Right half of a split screen.
+        let right = Viewport::new(Vec2::new(0.5, 0.0), Vec2::new(0.5, 1.0));
+        assert_eq!(right.physical_rect(1279, 720), Some(([640, 0], [639, 720])));
+        assert!(right.contains(Vec2::new(0.75, 0.5)));
+        assert!(!right.contains(Vec2::new(0.25, 0.5)));
+
+        // Partially outside of the window.
+        let minimap = Viewport::new(Vec2::new(0.75, -0.25), Vec2::new(0.5, 0.5));
+        assert_eq!(minimap.physical_rect(100, 100), Some(([75, 0], [25, 25])));
+
+        // Too small to cover a pixel.
+        let tiny = Viewport::new(Vec2::new(0.5, 0.5), Vec2::new(0.001, 0.001));
+        assert_eq!(tiny.physical_rect(100, 100), None);
+    }
+}
@@ -16,9 +16,14 @@ mod raytrace;
 mod render;
 mod util;
 use ash::vk;
-pub use camera::PerspectiveCamera;
+pub use camera::{OrthographicCamera, PerspectiveCamera, Viewport};
 
-pub use raytrace::VoxelModel;
+pub use raytrace::{
+    AllocError, AnimationFrame, ArenaLayout, BlockAllocator, CameraLayers, CsgOperation,
+    MemoryBudget, PickHit, PickResult, Svdag, SvdagFileError, SvdagSnapshot, SystemBlockAllocator,
+    VisibilityLayers, VoxLoader, VoxPlugin, VoxelAnimationPlayer, VoxelEdit, VoxelEditOp,
+    VoxelModel, VoxelModelEvicted, VoxelResidency,
+};
 
 use device_info::DeviceInfo;
 
@@ -27,7 +32,7 @@ use bevy::prelude::*;
 pub type Allocator = gpu_alloc::GpuAllocator<ash::vk::DeviceMemory>;
 pub type MemoryBlock = gpu_alloc::MemoryBlock<ash::vk::DeviceMemory>;
 pub use queues::Queues;
-pub use raytrace::Raytraced;
+pub use raytrace::{InstanceCulling, ModelBlasSettings, Raytraced};
 
 #[derive(Default)]
 pub struct DustPlugin;
@@ -47,6 +52,12 @@ pub trait VulkanAllocator {
         buffer: vk::Buffer,
         usage: gpu_alloc::UsageFlags,
     ) -> MemoryBlock;
+    unsafe fn alloc_for_image(
+        &mut self,
+        device: &ash::Device,
+        image: vk::Image,
+        usage: gpu_alloc::UsageFlags,
+    ) -> MemoryBlock;
     unsafe fn alloc_with_device(
         &mut self,
         device: &ash::Device,
@@ -79,6 +90,31 @@ impl VulkanAllocator for Allocator {
             .unwrap();
         mem
     }
+    unsafe fn alloc_for_image(
+        &mut self,
+        device: &ash::Device,
+        image: vk::Image,
+        usage: gpu_alloc::UsageFlags,
+    ) -> MemoryBlock {
+        use gpu_alloc::Request;
+        use gpu_alloc_ash::AshMemoryDevice;
+        let requirements = device.get_image_memory_requirements(image);
+        let mem = self
+            .alloc(
+                AshMemoryDevice::wrap(device),
+                Request {
+                    size: requirements.size,
+                    align_mask: requirements.alignment,
+                    memory_types: requirements.memory_type_bits,
+                    usage,
+                },
+            )
+            .unwrap();
+        device
+            .bind_image_memory(image, *mem.memory(), mem.offset())
+            .unwrap();
+        mem
+    }
     unsafe fn alloc_with_device(
         &mut self,
         device: &ash::Device,
@@ -30,17 +30,13 @@ fn setup(mut commands: Commands, asset_server: Res<AssetServer>) {
 
     commands
         .spawn()
-        .insert(Raytraced {
-            aabb_extent: bevy::math::Vec3::new(128.0, 128.0, 128.0),
-        })
+        .insert(Raytraced::with_voxel_size(1.0))
         .insert(scene_handle)
         .insert(GlobalTransform::default())
         .insert(Transform::from_xyz(10.0, 10.0, 10.0));
     commands
         .spawn()
-        .insert(Raytraced {
-            aabb_extent: bevy::math::Vec3::new(4.0, 4.0, 4.0),
-        })
+        .insert(Raytraced::with_extent(bevy::math::Vec3::new(4.0, 4.0, 4.0)))
         .insert(watertank_handle)
         .insert(GlobalTransform::default())
         .insert(Watertank)
@@ -57,9 +53,7 @@ fn setup(mut commands: Commands, asset_server: Res<AssetServer>) {
     /*
     commands
         .spawn()
-        .insert(Raytraced {
-            aabb_extent: bevy::math::Vec3::new(1.0, 1.0, 1.0),
-        })
+        .insert(Raytraced::with_extent(bevy::math::Vec3::new(1.0, 1.0, 1.0)))
         .insert(GlobalTransform::default())
         .insert(Transform::from_xyz(1.0, 2.0, 3.0));
         */
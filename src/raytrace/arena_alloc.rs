@@ -1,16 +1,24 @@
-use super::block_alloc::{BlockAllocation, BlockAllocator, BlockAllocatorAddressSpace};
+use super::block_alloc::{
+    AllocError, BlockAllocation, BlockAllocator, BlockAllocatorAddressSpace, ResidencyTicket,
+};
 
+#[cfg(debug_assertions)]
+use std::collections::BTreeMap;
 use std::mem::{size_of, ManuallyDrop};
 
+use std::ops::Range;
+#[cfg(debug_assertions)]
+use std::panic::Location;
 use std::ptr::NonNull;
 use std::sync::Arc;
 
+// The default layout. See ArenaLayout::DEFAULT.
 pub const BLOCK_MASK_DEGREE: u32 = 22;
 pub const NUM_SLOTS_IN_BLOCK: u32 = 1 << BLOCK_MASK_DEGREE;
 pub const BLOCK_SIZE: u64 = NUM_SLOTS_IN_BLOCK as u64 * 4;
-pub const BLOCK_MASK: u32 = NUM_SLOTS_IN_BLOCK - 1;
+pub const MAX_SEGMENT_LEN: u32 = 9;
 
-#[derive(Copy, Clone, Debug, Ord, PartialOrd, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Ord, PartialOrd, Eq, PartialEq, Hash)]
 pub struct Handle(u32);
 impl Handle {
     #[inline]
@@ -26,30 +34,170 @@ impl Handle {
         Handle(self.0 + n)
     }
     #[inline]
-    pub fn get_slot_num(&self) -> u32 {
-        self.0 & BLOCK_MASK
+    pub fn get_value(&self) -> u32 {
+        self.0
+    }
+    #[inline]
+    pub fn from_value(value: u32) -> Handle {
+        Handle(value)
+    }
+}
+
+impl Default for Handle {
+    fn default() -> Self {
+        Handle::none()
+    }
+}
+
+pub type ArenaBlockAllocator = dyn BlockAllocator;
+
+/// How an arena splits its handles into a chunk number and a slot number,
+/// and which segment sizes it serves.
+/// Small blocks suit small models, while a smaller `block_mask_degree` leaves more bits
+/// for the chunk number, so huge worlds can address more blocks.
+/// Since chunk n is placed at n * block size in the address space, a handle is also the
+/// index of its slot in the device buffer, so the shaders work with any layout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ArenaLayout {
+    // Number of low handle bits addressing a slot inside a block.
+    block_mask_degree: u32,
+    // Segments of 1..=max_segment_len slots may be allocated. Each size has its own freelist.
+    max_segment_len: u32,
+}
+
+impl ArenaLayout {
+    pub const DEFAULT: ArenaLayout = ArenaLayout {
+        block_mask_degree: BLOCK_MASK_DEGREE,
+        max_segment_len: MAX_SEGMENT_LEN,
+    };
+    pub fn new(block_mask_degree: u32, max_segment_len: u32) -> Self {
+        assert!(
+            0 < block_mask_degree && block_mask_degree < 32,
+            "Block mask degree must be within 1..32"
+        );
+        assert!(max_segment_len > 0, "Segments need at least one slot");
+        // The newspace has to be able to serve a segment of any size.
+        assert!(
+            max_segment_len < 1 << block_mask_degree,
+            "Segments must be smaller than a block"
+        );
+        ArenaLayout {
+            block_mask_degree,
+            max_segment_len,
+        }
     }
     #[inline]
-    pub fn get_chunk_num(&self) -> u32 {
-        self.0 >> BLOCK_MASK_DEGREE
+    pub fn block_mask_degree(&self) -> u32 {
+        self.block_mask_degree
     }
     #[inline]
-    pub fn from_index(chunk_index: u32, block_index: u32) -> Handle {
-        Handle(chunk_index << BLOCK_MASK_DEGREE | block_index)
+    pub fn max_segment_len(&self) -> u32 {
+        self.max_segment_len
     }
     #[inline]
-    pub fn get_value(&self) -> u32 {
-        self.0
+    pub fn num_slots_in_block(&self) -> u32 {
+        1 << self.block_mask_degree
+    }
+    #[inline]
+    pub fn block_mask(&self) -> u32 {
+        self.num_slots_in_block() - 1
+    }
+    // The last chunk is never used, since its last slot would collide with Handle::none().
+    #[inline]
+    pub fn max_num_blocks(&self) -> u32 {
+        (1 << (32 - self.block_mask_degree)) - 1
+    }
+    // Size in bytes of a block holding slots of `slot_size` bytes.
+    #[inline]
+    pub fn block_size(&self, slot_size: usize) -> u64 {
+        self.num_slots_in_block() as u64 * slot_size as u64
+    }
+    #[inline]
+    pub fn slot_num(&self, handle: Handle) -> u32 {
+        handle.0 & self.block_mask()
+    }
+    #[inline]
+    pub fn chunk_num(&self, handle: Handle) -> u32 {
+        handle.0 >> self.block_mask_degree
+    }
+    #[inline]
+    pub fn handle(&self, chunk_index: u32, slot_index: u32) -> Handle {
+        Handle(chunk_index << self.block_mask_degree | slot_index)
     }
 }
 
-impl Default for Handle {
+impl Default for ArenaLayout {
     fn default() -> Self {
-        Handle::none()
+        Self::DEFAULT
     }
 }
 
-pub type ArenaBlockAllocator = dyn BlockAllocator;
+/// The bookkeeping state of an arena. Together with the content of its blocks, this is
+/// everything needed to reopen an arena stored by a persistent block allocator.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArenaMetadata {
+    layout: ArenaLayout,
+    freelist_heads: Vec<Handle>,
+    newspace_top: Handle,
+    size: u32,
+    num_segments: u32,
+    num_chunks: u32,
+}
+
+impl ArenaMetadata {
+    // Words preceding the freelist heads.
+    const NUM_FIXED_WORDS: usize = 6;
+    pub fn layout(&self) -> ArenaLayout {
+        self.layout
+    }
+    pub fn num_words(&self) -> usize {
+        Self::NUM_FIXED_WORDS + self.freelist_heads.len()
+    }
+    pub fn to_words(&self) -> Vec<u32> {
+        let mut words = vec![
+            self.layout.block_mask_degree,
+            self.layout.max_segment_len,
+            self.newspace_top.get_value(),
+            self.size,
+            self.num_segments,
+            self.num_chunks,
+        ];
+        words.extend(self.freelist_heads.iter().map(|head| head.get_value()));
+        words
+    }
+    // Read the metadata from the start of `words`. Trailing words are ignored.
+    // Returns None if there are too few words, or if they don't describe a valid arena.
+    pub fn from_words(words: &[u32]) -> Option<Self> {
+        let fixed_words = words.get(..Self::NUM_FIXED_WORDS)?;
+        let (block_mask_degree, max_segment_len) = (fixed_words[0], fixed_words[1]);
+        // The conditions asserted by ArenaLayout::new.
+        let layout_valid = 0 < block_mask_degree
+            && block_mask_degree < 32
+            && 0 < max_segment_len
+            && max_segment_len < 1 << block_mask_degree;
+        if !layout_valid {
+            return None;
+        }
+        let layout = ArenaLayout::new(block_mask_degree, max_segment_len);
+        let freelist_words =
+            words.get(Self::NUM_FIXED_WORDS..Self::NUM_FIXED_WORDS + max_segment_len as usize)?;
+        let num_chunks = fixed_words[5];
+        if num_chunks > layout.max_num_blocks() {
+            return None;
+        }
+        Some(ArenaMetadata {
+            layout,
+            freelist_heads: freelist_words.iter().map(|word| Handle(*word)).collect(),
+            newspace_top: Handle(fixed_words[2]),
+            size: fixed_words[3],
+            num_segments: fixed_words[4],
+            num_chunks,
+        })
+    }
+    pub fn num_chunks(&self) -> u32 {
+        self.num_chunks
+    }
+}
 
 #[repr(C)]
 struct FreeSlot {
@@ -66,12 +214,19 @@ pub trait ArenaAllocated: Sized + Default {}
 pub struct ArenaAllocator<T: ArenaAllocated> {
     block_allocator: Arc<ArenaBlockAllocator>,
     block_allocator_address_space: BlockAllocatorAddressSpace,
+    layout: ArenaLayout,
     chunks: Vec<(NonNull<ArenaSlot<T>>, BlockAllocation)>,
-    freelist_heads: [Handle; 9],
-    newspace_top: Handle, // new space to be allocated
-    size: u32,            // number of allocated slots
-    num_segments: u32,    // number of allocated segments
-    num_blocks: u32,      // number of blocks allocated from block_allocator
+    // Slot ranges written by the host since the last flush, one list per chunk.
+    dirty_ranges: Vec<Vec<Range<u32>>>,
+    // The device memory of all chunks is resident once this ticket is reached.
+    residency_ticket: ResidencyTicket,
+    freelist_heads: Vec<Handle>, // one freelist for each segment size
+    newspace_top: Handle,        // new space to be allocated
+    size: u32,                   // number of allocated slots
+    num_segments: u32,           // number of allocated segments
+    num_blocks: u32,             // number of blocks allocated from block_allocator
+    #[cfg(debug_assertions)]
+    validator: Option<Box<HandleValidator>>,
 }
 
 // ArenaAllocator contains NunNull which makes it !Send and !Sync.
@@ -83,80 +238,140 @@ unsafe impl<T: ArenaAllocated> Sync for ArenaAllocator<T> {}
 
 impl<T: ArenaAllocated> ArenaAllocator<T> {
     pub fn new(block_allocator: Arc<ArenaBlockAllocator>) -> Self {
-        assert_eq!(block_allocator.get_blocksize(), BLOCK_SIZE);
+        Self::with_layout(block_allocator, ArenaLayout::DEFAULT)
+    }
+    pub fn with_layout(block_allocator: Arc<ArenaBlockAllocator>, layout: ArenaLayout) -> Self {
+        assert_eq!(
+            block_allocator.get_blocksize(),
+            layout.block_size(size_of::<ArenaSlot<T>>())
+        );
         debug_assert!(size_of::<T>() >= size_of::<FreeSlot>(),);
         let address_space = unsafe { block_allocator.create_address_space() };
         Self {
             block_allocator,
             block_allocator_address_space: address_space,
+            layout,
             chunks: vec![],
-            freelist_heads: [Handle::none(); 9],
-            // Space pointed by this is guaranteed to have free space > 8
+            dirty_ranges: vec![],
+            residency_ticket: ResidencyTicket::RESIDENT,
+            freelist_heads: vec![Handle::none(); layout.max_segment_len as usize],
+            // Space pointed by this is guaranteed to have free space >= max_segment_len
             newspace_top: Handle::none(),
             size: 0,
             num_segments: 0,
             num_blocks: 0,
+            #[cfg(debug_assertions)]
+            validator: None,
         }
     }
     #[cfg(test)]
     pub fn potato() -> Self {
+        Self::potato_with_layout(ArenaLayout::DEFAULT)
+    }
+    #[cfg(test)]
+    pub fn potato_with_layout(layout: ArenaLayout) -> Self {
         use super::block_alloc::SystemBlockAllocator;
         let block_allocator: Arc<dyn BlockAllocator> = Arc::new(SystemBlockAllocator::new(
-            NUM_SLOTS_IN_BLOCK as usize * std::mem::size_of::<ArenaSlot<T>>(),
+            layout.block_size(size_of::<ArenaSlot<T>>()) as usize,
         ));
         let address_space = unsafe { block_allocator.create_address_space() };
         Self {
             block_allocator,
             block_allocator_address_space: address_space,
+            layout,
             chunks: vec![],
-            freelist_heads: [Handle::none(); 9],
-            // Space pointed by this is guaranteed to have free space > 8
+            dirty_ranges: vec![],
+            residency_ticket: ResidencyTicket::RESIDENT,
+            freelist_heads: vec![Handle::none(); layout.max_segment_len as usize],
+            // Space pointed by this is guaranteed to have free space >= max_segment_len
             newspace_top: Handle::none(),
             size: 0,
             num_segments: 0,
             num_blocks: 0,
+            #[cfg(debug_assertions)]
+            validator: None,
         }
     }
 
-    unsafe fn alloc_block(&mut self) -> Handle {
+    unsafe fn alloc_block(&mut self) -> Result<Handle, AllocError> {
         let chunk_index = self.chunks.len() as u32;
-        let (chunk, allocation) = self
+        if chunk_index >= self.layout.max_num_blocks() {
+            return Err(AllocError::TooManyObjects);
+        }
+        let (chunk, allocation, ticket) = self
             .block_allocator
-            .allocate_block(&self.block_allocator_address_space)
-            .unwrap();
+            .allocate_block(&self.block_allocator_address_space)?;
+        self.residency_ticket = self.residency_ticket.max(ticket);
         self.chunks
             .push((NonNull::new_unchecked(chunk as _), allocation));
+        self.dirty_ranges.push(Vec::new());
         self.num_blocks += 1;
-        Handle::from_index(chunk_index, 0)
+        Ok(self.layout.handle(chunk_index, 0))
     }
-    pub unsafe fn alloc(&mut self, len: u32) -> Handle {
-        assert!(0 < len && len <= 9, "Only supports block size between 1-8!");
-        self.size += len;
-        self.num_segments += 1;
+    // Make sure that the next allocations totaling `num_slots` slots can be served without
+    // requesting more memory from the block allocator, whatever their segment sizes.
+    // Callers performing a multi-step edit can reserve up front so that running out of
+    // memory never leaves their data structure half updated.
+    pub unsafe fn reserve(&mut self, num_slots: u32) -> Result<(), AllocError> {
+        let num_slots_in_block = self.layout.num_slots_in_block();
+        assert!(
+            num_slots + self.layout.max_segment_len < num_slots_in_block,
+            "Can't reserve {} slots in a block",
+            num_slots
+        );
+        let remaining_space = if self.newspace_top.is_none() {
+            0
+        } else {
+            num_slots_in_block - self.layout.slot_num(self.newspace_top)
+        };
+        // `alloc` gives up the newspace once no more than max_segment_len slots are left,
+        // pushing them onto the freelist of that one size. Keeping more than that after
+        // the reserved slots means the newspace serves all of them.
+        if remaining_space > num_slots + self.layout.max_segment_len {
+            return Ok(());
+        }
+        let alloc_head = self.alloc_block()?;
+        // Recycle what's left of the current newspace into the freelists.
+        let mut remaining_space = remaining_space;
+        while remaining_space > 0 {
+            let n = remaining_space.min(self.layout.max_segment_len);
+            self.freelist_push(n, self.newspace_top);
+            self.newspace_top = self.newspace_top.offset(n);
+            remaining_space -= n;
+        }
+        self.newspace_top = alloc_head;
+        Ok(())
+    }
+    #[track_caller]
+    pub unsafe fn alloc(&mut self, len: u32) -> Result<Handle, AllocError> {
+        assert!(
+            0 < len && len <= self.layout.max_segment_len,
+            "Only supports segment sizes between 1 and {}",
+            self.layout.max_segment_len
+        );
 
         // Retrieve the head of the freelist
-        let sized_head = self.freelist_pop(len as u8);
+        let sized_head = self.freelist_pop(len);
         let handle: Handle = if sized_head.is_none() {
             // If the head is none, it means we need to allocate some new slots
             if self.newspace_top.is_none() {
                 // We've run out of newspace.
                 // Allocate a new memory chunk from the underlying block allocator.
-                let alloc_head = self.alloc_block();
-                self.newspace_top = Handle::from_index(alloc_head.get_chunk_num(), len);
+                let alloc_head = self.alloc_block()?;
+                self.newspace_top = alloc_head.offset(len);
                 alloc_head
             } else {
                 // There's still space remains to be allocated in the current chunk.
                 let handle = self.newspace_top;
-                let slot_index = handle.get_slot_num();
-                let chunk_index = handle.get_chunk_num();
-                let remaining_space = NUM_SLOTS_IN_BLOCK - slot_index - len;
+                let slot_index = self.layout.slot_num(handle);
+                let remaining_space = self.layout.num_slots_in_block() - slot_index - len;
 
-                let new_handle = Handle::from_index(chunk_index, slot_index + len);
-                if remaining_space > 9 {
+                let new_handle = handle.offset(len);
+                if remaining_space > self.layout.max_segment_len {
                     self.newspace_top = new_handle;
                 } else {
                     if remaining_space > 0 {
-                        self.freelist_push(remaining_space as u8, new_handle);
+                        self.freelist_push(remaining_space, new_handle);
                     }
                     self.newspace_top = Handle::none();
                 }
@@ -166,10 +381,17 @@ impl<T: ArenaAllocated> ArenaAllocator<T> {
             // There's previously used blocks stored in the freelist. Use them first.
             sized_head
         };
+        self.size += len;
+        self.num_segments += 1;
+        #[cfg(debug_assertions)]
+        if let Some(validator) = self.validator.as_mut() {
+            validator.on_alloc(&self.layout, handle, len, Location::caller());
+        }
 
         // initialize to zero
-        let slot_index = handle.get_slot_num();
-        let chunk_index = handle.get_chunk_num();
+        let slot_index = self.layout.slot_num(handle);
+        let chunk_index = self.layout.chunk_num(handle);
+        self.mark_dirty(handle, len);
         unsafe {
             let base = self.chunks[chunk_index as usize]
                 .0
@@ -180,21 +402,26 @@ impl<T: ArenaAllocated> ArenaAllocator<T> {
                 i.occupied = Default::default();
             }
         }
-        handle
+        Ok(handle)
     }
-    pub unsafe fn free(&mut self, handle: Handle, block_size: u8) {
-        debug_assert!(0 < block_size && block_size <= 9);
-        self.freelist_push(block_size, handle);
-        self.size -= block_size as u32;
+    #[track_caller]
+    pub unsafe fn free(&mut self, handle: Handle, len: u32) {
+        #[cfg(debug_assertions)]
+        if let Some(validator) = self.validator.as_mut() {
+            validator.on_free(&self.layout, handle, len, Location::caller());
+        }
+        self.freelist_push(len, handle);
+        self.size -= len;
         self.num_segments -= 1;
     }
-    unsafe fn freelist_push(&mut self, n: u8, handle: Handle) {
-        debug_assert!(0 < n && n <= 9);
+    unsafe fn freelist_push(&mut self, n: u32, handle: Handle) {
+        debug_assert!(0 < n && n <= self.layout.max_segment_len);
+        self.mark_dirty(handle, 1);
         self.get_slot_mut(handle).free.next = self.freelist_heads[(n - 1) as usize];
         self.freelist_heads[(n - 1) as usize] = handle;
     }
-    unsafe fn freelist_pop(&mut self, n: u8) -> Handle {
-        debug_assert!(0 < n && n <= 9);
+    unsafe fn freelist_pop(&mut self, n: u32) -> Handle {
+        debug_assert!(0 < n && n <= self.layout.max_segment_len);
         let sized_head = self.freelist_heads[(n - 1) as usize];
         if !sized_head.is_none() {
             self.freelist_heads[(n - 1) as usize] = self.get_slot(sized_head).free.next;
@@ -203,8 +430,8 @@ impl<T: ArenaAllocated> ArenaAllocator<T> {
     }
     #[inline]
     unsafe fn get_slot(&self, handle: Handle) -> &ArenaSlot<T> {
-        let slot_index = handle.get_slot_num();
-        let chunk_index = handle.get_chunk_num();
+        let slot_index = self.layout.slot_num(handle);
+        let chunk_index = self.layout.chunk_num(handle);
         unsafe {
             let base = self.chunks[chunk_index as usize].0.as_ptr();
             &*base.add(slot_index as usize)
@@ -212,48 +439,404 @@ impl<T: ArenaAllocated> ArenaAllocator<T> {
     }
     #[inline]
     unsafe fn get_slot_mut(&mut self, handle: Handle) -> &mut ArenaSlot<T> {
-        let slot_index = handle.get_slot_num();
-        let chunk_index = handle.get_chunk_num();
+        let slot_index = self.layout.slot_num(handle);
+        let chunk_index = self.layout.chunk_num(handle);
         unsafe {
             let base = self.chunks[chunk_index as usize].0.as_ptr();
             &mut *base.add(slot_index as usize)
         }
     }
     #[inline]
+    #[track_caller]
     pub fn get(&self, index: Handle) -> &T {
+        #[cfg(debug_assertions)]
+        if let Some(validator) = self.validator.as_ref() {
+            validator.check_access(&self.layout, index);
+        }
         unsafe {
             let slot = self.get_slot(index);
             &slot.occupied
         }
     }
     #[inline]
+    #[track_caller]
     pub fn get_mut(&mut self, index: Handle) -> &mut T {
+        #[cfg(debug_assertions)]
+        if let Some(validator) = self.validator.as_ref() {
+            validator.check_access(&self.layout, index);
+        }
+        self.mark_dirty(index, 1);
         unsafe {
             let slot = self.get_slot_mut(index);
             &mut slot.occupied
         }
     }
 
+    // Track every allocated slot and panic on double frees, frees with the wrong size and
+    // accesses through dangling handles. Does nothing in release builds.
+    // Must be called before anything is allocated.
+    pub fn enable_handle_validation(&mut self) {
+        #[cfg(debug_assertions)]
+        {
+            assert_eq!(
+                self.num_segments, 0,
+                "Handle validation must be enabled on an empty arena"
+            );
+            self.validator = Some(Box::new(HandleValidator::default()));
+        }
+    }
+
     #[inline]
     pub fn get_size(&self) -> u32 {
         self.size
     }
+    #[inline]
+    pub fn layout(&self) -> ArenaLayout {
+        self.layout
+    }
 
-    pub fn flush_all(&self) {
+    // Record that the host wrote to `len` slots starting at `handle`.
+    // The ranges of each chunk are kept sorted and merged on the spot, so the list never holds
+    // more entries than there are disjoint runs of dirty slots, however the edits interleave.
+    #[inline]
+    fn mark_dirty(&mut self, handle: Handle, len: u32) {
+        let start = self.layout.slot_num(handle);
+        let ranges = &mut self.dirty_ranges[self.layout.chunk_num(handle) as usize];
+        insert_range(ranges, start..start + len);
+    }
+
+    pub fn flush_all(&mut self) -> Result<(), AllocError> {
         let block_size = self.block_allocator.get_blocksize() as u32;
         let mut iterator = self
             .chunks
             .iter()
             .map(|chunk| (&self.block_allocator_address_space, &chunk.1, 0..block_size));
         unsafe {
-            self.block_allocator.flush(&mut iterator);
+            self.block_allocator.flush(&mut iterator)?;
+        }
+        for ranges in self.dirty_ranges.iter_mut() {
+            ranges.clear();
+        }
+        Ok(())
+    }
+
+    // Flush only the slots written since the last flush.
+    // Returns false if the block allocator is busy. In that case, and on errors,
+    // the dirty ranges are kept and will be submitted by the next call.
+    pub fn flush_dirty(&mut self) -> Result<bool, AllocError> {
+        if self.dirty_ranges.iter().all(|ranges| ranges.is_empty()) {
+            return Ok(true);
         }
+        if !self.block_allocator.can_flush()? {
+            return Ok(false);
+        }
+        let slot_size = size_of::<ArenaSlot<T>>() as u32;
+        let mut flush_ranges: Vec<(usize, Range<u32>)> = Vec::new();
+        for (chunk_index, ranges) in self.dirty_ranges.iter().enumerate() {
+            for range in ranges.iter() {
+                flush_ranges.push((chunk_index, range.start * slot_size..range.end * slot_size));
+            }
+        }
+        let mut iterator = flush_ranges.into_iter().map(|(chunk_index, range)| {
+            (
+                &self.block_allocator_address_space,
+                &self.chunks[chunk_index].1,
+                range,
+            )
+        });
+        unsafe {
+            self.block_allocator.flush(&mut iterator)?;
+        }
+        for ranges in self.dirty_ranges.iter_mut() {
+            ranges.clear();
+        }
+        Ok(true)
+    }
+
+    // Bytes committed by the block allocator for this arena.
+    pub fn memory_usage(&self) -> u64 {
+        self.num_blocks as u64 * self.block_allocator.get_blocksize()
+    }
+
+    // Returns true once the device memory of every chunk is resident.
+    pub fn is_resident(&self) -> bool {
+        self.block_allocator.is_resident(self.residency_ticket)
+    }
+
+    pub fn block_allocator(&self) -> &Arc<ArenaBlockAllocator> {
+        &self.block_allocator
+    }
+
+    // False if the arena lives in host memory only. See `BlockAllocator::has_device_buffer`.
+    pub fn has_device_buffer(&self) -> bool {
+        self.block_allocator.has_device_buffer()
     }
 
     pub fn get_buffer_device_address(&self) -> ash::vk::DeviceAddress {
         self.block_allocator
             .get_buffer_device_address(&self.block_allocator_address_space)
     }
+
+    pub fn metadata(&self) -> ArenaMetadata {
+        ArenaMetadata {
+            layout: self.layout,
+            freelist_heads: self.freelist_heads.clone(),
+            newspace_top: self.newspace_top,
+            size: self.size,
+            num_segments: self.num_segments,
+            num_chunks: self.chunks.len() as u32,
+        }
+    }
+
+    // Rebuild an arena from blocks that already exist, for example in a reopened file.
+    // `map_chunk(i)` returns the host pointer and the allocation of chunk i.
+    pub unsafe fn from_metadata(
+        block_allocator: Arc<ArenaBlockAllocator>,
+        metadata: &ArenaMetadata,
+        mut map_chunk: impl FnMut(u32) -> Result<(*mut u8, BlockAllocation), AllocError>,
+    ) -> Result<Self, AllocError> {
+        let layout = metadata.layout;
+        assert_eq!(
+            block_allocator.get_blocksize(),
+            layout.block_size(size_of::<ArenaSlot<T>>())
+        );
+        let address_space = block_allocator.create_address_space();
+        let mut arena = Self {
+            block_allocator,
+            block_allocator_address_space: address_space,
+            layout,
+            chunks: Vec::with_capacity(metadata.num_chunks as usize),
+            dirty_ranges: Vec::with_capacity(metadata.num_chunks as usize),
+            residency_ticket: ResidencyTicket::RESIDENT,
+            freelist_heads: metadata.freelist_heads.clone(),
+            newspace_top: metadata.newspace_top,
+            size: metadata.size,
+            num_segments: metadata.num_segments,
+            num_blocks: 0,
+            // The allocation history of reopened arenas is unknown.
+            #[cfg(debug_assertions)]
+            validator: None,
+        };
+        for chunk_index in 0..metadata.num_chunks {
+            // On failure, the chunks mapped so far are returned when `arena` drops.
+            let (chunk, allocation) = map_chunk(chunk_index)?;
+            arena
+                .chunks
+                .push((NonNull::new_unchecked(chunk as _), allocation));
+            arena.dirty_ranges.push(Vec::new());
+            arena.num_blocks += 1;
+        }
+        Ok(arena)
+    }
+
+    // Copy all chunks into a new address space of another block allocator.
+    // Handles remain valid in the copy. All chunks of the copy are marked dirty,
+    // so the next flush will push the entire arena to the device.
+    pub fn copy_to(&self, block_allocator: Arc<ArenaBlockAllocator>) -> Result<Self, AllocError> {
+        let block_size = self.block_allocator.get_blocksize();
+        assert_eq!(block_allocator.get_blocksize(), block_size);
+        let address_space = unsafe { block_allocator.create_address_space() };
+        let mut arena = Self {
+            block_allocator,
+            block_allocator_address_space: address_space,
+            layout: self.layout,
+            chunks: Vec::with_capacity(self.chunks.len()),
+            dirty_ranges: Vec::with_capacity(self.chunks.len()),
+            residency_ticket: ResidencyTicket::RESIDENT,
+            freelist_heads: self.freelist_heads.clone(),
+            newspace_top: self.newspace_top,
+            size: self.size,
+            num_segments: self.num_segments,
+            num_blocks: 0,
+            #[cfg(debug_assertions)]
+            validator: self.validator.clone(),
+        };
+        for (chunk, _) in self.chunks.iter() {
+            unsafe {
+                // On failure, the blocks copied so far are returned when `arena` drops.
+                arena.alloc_block()?;
+                let (dst, _) = arena.chunks.last().unwrap();
+                std::ptr::copy_nonoverlapping(
+                    chunk.as_ptr() as *const u8,
+                    dst.as_ptr() as *mut u8,
+                    block_size as usize,
+                );
+            }
+            arena
+                .dirty_ranges
+                .last_mut()
+                .unwrap()
+                .push(0..self.layout.num_slots_in_block());
+        }
+        Ok(arena)
+    }
+}
+
+impl<T: ArenaAllocated> Drop for ArenaAllocator<T> {
+    fn drop(&mut self) {
+        unsafe {
+            for (_, allocation) in self.chunks.drain(..) {
+                self.block_allocator
+                    .deallocate_block(&self.block_allocator_address_space, allocation);
+            }
+            // The address space is never used again after this point.
+            let address_space = std::ptr::read(&self.block_allocator_address_space);
+            self.block_allocator.destroy_address_space(address_space);
+        }
+    }
+}
+
+// Allocation state of every slot, kept by arenas with handle validation enabled.
+#[cfg(debug_assertions)]
+#[derive(Clone, Default)]
+struct HandleValidator {
+    // One bit per slot for each chunk, set while the slot belongs to an allocated segment.
+    bitmaps: Vec<Vec<u64>>,
+    // Segments by the value of their first handle. Freed segments are kept for diagnostics
+    // until their slots are handed out again.
+    segments: BTreeMap<u32, ValidatedSegment>,
+}
+
+#[cfg(debug_assertions)]
+#[derive(Clone, Copy)]
+struct ValidatedSegment {
+    len: u32,
+    allocated_at: &'static Location<'static>,
+    freed_at: Option<&'static Location<'static>>,
+}
+
+#[cfg(debug_assertions)]
+impl HandleValidator {
+    fn is_allocated(&self, layout: &ArenaLayout, handle: Handle) -> bool {
+        let slot = layout.slot_num(handle) as usize;
+        self.bitmaps
+            .get(layout.chunk_num(handle) as usize)
+            .map_or(false, |bitmap| bitmap[slot / 64] & (1 << (slot % 64)) != 0)
+    }
+    fn set_allocated(&mut self, layout: &ArenaLayout, handle: Handle, len: u32, allocated: bool) {
+        let chunk = layout.chunk_num(handle) as usize;
+        if self.bitmaps.len() <= chunk {
+            let num_words = (layout.num_slots_in_block() as usize + 63) / 64;
+            self.bitmaps.resize(chunk + 1, vec![0; num_words]);
+        }
+        let bitmap = &mut self.bitmaps[chunk];
+        for slot in layout.slot_num(handle)..layout.slot_num(handle) + len {
+            let slot = slot as usize;
+            if allocated {
+                bitmap[slot / 64] |= 1 << (slot % 64);
+            } else {
+                bitmap[slot / 64] &= !(1 << (slot % 64));
+            }
+        }
+    }
+    // The segment containing `handle`, if any. May be a freed segment.
+    fn segment_containing(&self, handle: Handle) -> Option<(u32, &ValidatedSegment)> {
+        self.segments
+            .range(..=handle.0)
+            .next_back()
+            .filter(|(start, segment)| handle.0 < **start + segment.len)
+            .map(|(start, segment)| (*start, segment))
+    }
+    fn describe(&self, layout: &ArenaLayout, handle: Handle) -> String {
+        let mut description = format!(
+            "{:?} (chunk {}, slot {})",
+            handle,
+            layout.chunk_num(handle),
+            layout.slot_num(handle)
+        );
+        match self.segment_containing(handle) {
+            Some((start, segment)) => {
+                description += &format!(
+                    ", in a segment of {} slots starting at {:?} allocated at {}",
+                    segment.len,
+                    Handle(start),
+                    segment.allocated_at
+                );
+                if let Some(freed_at) = segment.freed_at {
+                    description += &format!(" and freed at {}", freed_at);
+                }
+            }
+            None => description += ", which was never allocated",
+        }
+        description
+    }
+    fn on_alloc(
+        &mut self,
+        layout: &ArenaLayout,
+        handle: Handle,
+        len: u32,
+        location: &'static Location<'static>,
+    ) {
+        // Forget the freed segments whose slots are being reused.
+        while let Some(start) = self
+            .segments
+            .range(..handle.0 + len)
+            .next_back()
+            .filter(|(start, segment)| handle.0 < **start + segment.len)
+            .map(|(start, _)| *start)
+        {
+            debug_assert!(self.segments[&start].freed_at.is_some());
+            self.segments.remove(&start);
+        }
+        self.segments.insert(
+            handle.0,
+            ValidatedSegment {
+                len,
+                allocated_at: location,
+                freed_at: None,
+            },
+        );
+        self.set_allocated(layout, handle, len, true);
+    }
+    fn on_free(
+        &mut self,
+        layout: &ArenaLayout,
+        handle: Handle,
+        len: u32,
+        location: &'static Location<'static>,
+    ) {
+        let segment = match self.segments.get_mut(&handle.0) {
+            Some(segment) => segment,
+            None => panic!(
+                "Freeing {}, which is not the start of a segment",
+                self.describe(layout, handle)
+            ),
+        };
+        if segment.freed_at.is_some() {
+            panic!("Double free of {}", self.describe(layout, handle));
+        }
+        if segment.len != len {
+            panic!(
+                "Freeing {} with block_size {}",
+                self.describe(layout, handle),
+                len
+            );
+        }
+        segment.freed_at = Some(location);
+        self.set_allocated(layout, handle, len, false);
+    }
+    fn check_access(&self, layout: &ArenaLayout, handle: Handle) {
+        if !self.is_allocated(layout, handle) {
+            panic!("Access to unallocated {}", self.describe(layout, handle));
+        }
+    }
+}
+
+// Insert `range` into a sorted list of disjoint ranges,
+// merging it with every range it overlaps or touches.
+fn insert_range(ranges: &mut Vec<Range<u32>>, range: Range<u32>) {
+    // The first range that ends at or after the start of the new one.
+    let first = ranges.partition_point(|r| r.end < range.start);
+    // One past the last range that starts at or before the end of the new one.
+    let last = first + ranges[first..].partition_point(|r| r.start <= range.end);
+    if first == last {
+        ranges.insert(first, range);
+    } else {
+        let start = ranges[first].start.min(range.start);
+        let end = ranges[last - 1].end.max(range.end);
+        ranges[first] = start..end;
+        ranges.drain(first + 1..last);
+    }
 }
 
 #[cfg(test)]
@@ -268,26 +851,26 @@ mod tests {
         unsafe {
             // Allocate until we have 9 slots left
             for i in 0..NUM_SLOTS_IN_BLOCK - 9 {
-                let handle = arena.alloc(1);
-                assert_eq!(handle.get_slot_num(), i);
-                assert_eq!(handle.get_chunk_num(), 0);
+                let handle = arena.alloc(1).unwrap();
+                assert_eq!(arena.layout.slot_num(handle), i);
+                assert_eq!(arena.layout.chunk_num(handle), 0);
             }
             // At this point there shouldn't be any extra allocations
             assert_eq!(arena.num_blocks, 1);
 
             // Allocate one more
-            let handle = arena.alloc(1);
+            let handle = arena.alloc(1).unwrap();
 
             // This new slot should be in a new chunk
-            assert_eq!(handle.get_slot_num(), 0);
-            assert_eq!(handle.get_chunk_num(), 1);
+            assert_eq!(arena.layout.slot_num(handle), 0);
+            assert_eq!(arena.layout.chunk_num(handle), 1);
             // A new chunk was allocated
             assert_eq!(arena.num_blocks, 2);
 
             // The remaining 9 slot was put into the freelist
-            let handle = arena.alloc(9);
-            assert_eq!(handle.get_slot_num(), NUM_SLOTS_IN_BLOCK - 9);
-            assert_eq!(handle.get_chunk_num(), 0);
+            let handle = arena.alloc(9).unwrap();
+            assert_eq!(arena.layout.slot_num(handle), NUM_SLOTS_IN_BLOCK - 9);
+            assert_eq!(arena.layout.chunk_num(handle), 0);
         }
     }
 
@@ -295,15 +878,266 @@ mod tests {
     fn test_free() {
         let mut arena: ArenaAllocator<u128> = ArenaAllocator::potato();
         unsafe {
-            let handles: Vec<Handle> = (0..8).map(|_| arena.alloc(4)).collect();
+            let handles: Vec<Handle> = (0..8).map(|_| arena.alloc(4).unwrap()).collect();
             for handle in handles.iter().rev() {
                 unsafe { arena.free(*handle, 4) };
             }
-            assert_eq!(arena.alloc(1), Handle(8 * 4));
+            assert_eq!(arena.alloc(1).unwrap(), Handle(8 * 4));
             for handle in handles.iter() {
-                let new_handle = arena.alloc(4);
+                let new_handle = arena.alloc(4).unwrap();
                 assert_eq!(*handle, new_handle);
             }
         }
     }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Double free of Handle(4)")]
+    fn test_double_free() {
+        let mut arena: ArenaAllocator<u128> = ArenaAllocator::potato();
+        arena.enable_handle_validation();
+        unsafe {
+            arena.alloc(4).unwrap();
+            let handle = arena.alloc(4).unwrap();
+            arena.free(handle, 4);
+            arena.free(handle, 4);
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "with block_size 2")]
+    fn test_free_wrong_size() {
+        let mut arena: ArenaAllocator<u128> = ArenaAllocator::potato();
+        arena.enable_handle_validation();
+        unsafe {
+            let handle = arena.alloc(3).unwrap();
+            arena.free(handle, 2);
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "not the start of a segment")]
+    fn test_free_inside_segment() {
+        let mut arena: ArenaAllocator<u128> = ArenaAllocator::potato();
+        arena.enable_handle_validation();
+        unsafe {
+            let handle = arena.alloc(3).unwrap();
+            arena.free(handle.offset(1), 2);
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Access to unallocated Handle(2) (chunk 0, slot 2)")]
+    fn test_use_after_free() {
+        let mut arena: ArenaAllocator<u128> = ArenaAllocator::potato();
+        arena.enable_handle_validation();
+        unsafe {
+            let handle = arena.alloc(3).unwrap();
+            arena.free(handle, 3);
+            arena.get(handle.offset(2));
+        }
+    }
+
+    #[test]
+    fn test_validation_after_reuse() {
+        let mut arena: ArenaAllocator<u128> = ArenaAllocator::potato();
+        arena.enable_handle_validation();
+        unsafe {
+            let a = arena.alloc(2).unwrap();
+            arena.free(a, 2);
+            let b = arena.alloc(2).unwrap();
+            assert_eq!(a, b);
+            *arena.get_mut(b.offset(1)) = 1;
+            arena.free(b, 2);
+        }
+    }
+
+    #[test]
+    fn test_insert_range() {
+        let mut ranges = vec![];
+        for range in [8..10, 0..2, 2..4, 9..12, 20..21] {
+            insert_range(&mut ranges, range);
+        }
+        assert_eq!(ranges, vec![0..4, 8..12, 20..21]);
+        insert_range(&mut ranges, 5..6);
+        assert_eq!(ranges, vec![0..4, 5..6, 8..12, 20..21]);
+        insert_range(&mut ranges, 3..9);
+        assert_eq!(ranges, vec![0..12, 20..21]);
+
+        // Interleaved edits only grow the list by the number of disjoint runs.
+        let mut ranges = vec![];
+        for i in 0..100 {
+            insert_range(&mut ranges, i..i + 1);
+            insert_range(&mut ranges, 1000 + i..1001 + i);
+        }
+        assert_eq!(ranges, vec![0..100, 1000..1100]);
+    }
+
+    #[test]
+    fn test_dirty_ranges() {
+        let mut arena: ArenaAllocator<u128> = ArenaAllocator::potato();
+        unsafe {
+            let a = arena.alloc(2).unwrap();
+            let b = arena.alloc(3).unwrap();
+            assert_eq!(arena.dirty_ranges[0], vec![0..5]);
+            assert!(arena.flush_dirty().unwrap());
+            assert!(arena.dirty_ranges[0].is_empty());
+
+            *arena.get_mut(b.offset(2)) = 1;
+            arena.free(a, 2);
+            assert_eq!(arena.dirty_ranges[0], vec![0..1, 4..5]);
+            assert!(arena.flush_dirty().unwrap());
+            assert!(arena.dirty_ranges[0].is_empty());
+        }
+    }
+
+    #[test]
+    fn test_reserve() {
+        let mut arena: ArenaAllocator<u128> = ArenaAllocator::potato();
+        unsafe {
+            arena.reserve(20).unwrap();
+            assert_eq!(arena.num_blocks, 1);
+            for _ in 0..(NUM_SLOTS_IN_BLOCK - 12) {
+                arena.alloc(1).unwrap();
+            }
+            // 12 slots remain in the newspace. Reserving more moves on to a new block.
+            arena.reserve(20).unwrap();
+            assert_eq!(arena.num_blocks, 2);
+            let handle = arena.alloc(5).unwrap();
+            assert_eq!(arena.layout.chunk_num(handle), 1);
+            assert_eq!(arena.layout.slot_num(handle), 0);
+
+            // The leftovers of the first block were put into the freelist.
+            let handle = arena.alloc(9).unwrap();
+            assert_eq!(arena.layout.chunk_num(handle), 0);
+            assert_eq!(arena.layout.slot_num(handle), NUM_SLOTS_IN_BLOCK - 12);
+            let handle = arena.alloc(3).unwrap();
+            assert_eq!(arena.layout.chunk_num(handle), 0);
+            assert_eq!(arena.layout.slot_num(handle), NUM_SLOTS_IN_BLOCK - 3);
+        }
+    }
+
+    #[test]
+    fn test_reserve_mixed_segment_sizes() {
+        use super::super::block_alloc::MockBlockAllocator;
+        let layout = ArenaLayout::new(6, 9);
+        let block_allocator = Arc::new(MockBlockAllocator::new(
+            layout.block_size(size_of::<ArenaSlot<u128>>()) as usize,
+        ));
+        let mut arena: ArenaAllocator<u128> =
+            ArenaAllocator::with_layout(block_allocator.clone(), layout);
+        unsafe {
+            for _ in 0..5 {
+                arena.alloc(9).unwrap();
+            }
+            arena.alloc(1).unwrap();
+            // Exactly 18 slots remain in the newspace, but an alloc(9) would leave only 9 of
+            // them there, which go to the freelist of 9 slot segments.
+            arena.reserve(18).unwrap();
+            block_allocator.inject_failure(0, AllocError::OutOfDeviceMemory);
+            arena.alloc(9).unwrap();
+            arena.alloc(2).unwrap();
+            arena.alloc(7).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_copy_to() {
+        let mut arena: ArenaAllocator<u128> = ArenaAllocator::potato();
+        unsafe {
+            let handles: Vec<Handle> = (0..16).map(|_| arena.alloc(3).unwrap()).collect();
+            for (i, handle) in handles.iter().enumerate() {
+                *arena.get_mut(*handle) = i as u128;
+            }
+            arena.free(handles[3], 3);
+
+            let mut copy = arena.copy_to(arena.block_allocator.clone()).unwrap();
+            for (i, handle) in handles.iter().enumerate() {
+                if i != 3 {
+                    assert_eq!(*copy.get(*handle), i as u128);
+                }
+            }
+            assert_eq!(copy.get_size(), arena.get_size());
+            assert_eq!(copy.dirty_ranges, vec![vec![0..NUM_SLOTS_IN_BLOCK]]);
+            // The freelists were copied as well.
+            assert_eq!(copy.alloc(3).unwrap(), handles[3]);
+        }
+    }
+
+    #[test]
+    fn test_metadata_words() {
+        let mut arena: ArenaAllocator<u128> = ArenaAllocator::potato();
+        unsafe {
+            let handle = arena.alloc(4).unwrap();
+            arena.alloc(2).unwrap();
+            arena.free(handle, 4);
+        }
+        let metadata = arena.metadata();
+        assert_eq!(metadata.num_chunks, 1);
+        assert_eq!(metadata.freelist_heads[3], Handle(0));
+        let mut words = metadata.to_words();
+        assert_eq!(words.len(), metadata.num_words());
+        words.push(42);
+        assert_eq!(ArenaMetadata::from_words(&words), Some(metadata));
+
+        // Truncated words and invalid layouts are rejected.
+        assert_eq!(ArenaMetadata::from_words(&words[..words.len() - 2]), None);
+        assert_eq!(ArenaMetadata::from_words(&[]), None);
+        words[1] = 0;
+        assert_eq!(ArenaMetadata::from_words(&words), None);
+    }
+
+    #[test]
+    fn test_small_layout() {
+        // 16 slots per block, segments of up to 4 slots.
+        let layout = ArenaLayout::new(4, 4);
+        assert_eq!(layout.max_num_blocks(), (1 << 28) - 1);
+        let mut arena: ArenaAllocator<u128> = ArenaAllocator::potato_with_layout(layout);
+        unsafe {
+            let handles: Vec<Handle> = (0..4).map(|_| arena.alloc(3).unwrap()).collect();
+            assert_eq!(arena.num_blocks, 1);
+            assert_eq!(handles[3], layout.handle(0, 9));
+            // The last 4 slots of the first block went into the freelist.
+            assert_eq!(arena.alloc(4).unwrap(), layout.handle(0, 12));
+            assert_eq!(arena.num_blocks, 1);
+            assert_eq!(arena.alloc(4).unwrap(), layout.handle(1, 0));
+            assert_eq!(arena.num_blocks, 2);
+        }
+        let metadata = arena.metadata();
+        assert_eq!(metadata.layout(), layout);
+        assert_eq!(metadata.num_words(), 10);
+        assert_eq!(
+            ArenaMetadata::from_words(&metadata.to_words()),
+            Some(metadata)
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_segment_too_large() {
+        let mut arena: ArenaAllocator<u128> =
+            ArenaAllocator::potato_with_layout(ArenaLayout::new(4, 4));
+        unsafe {
+            arena.alloc(5).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_shader_handle_none() {
+        // The shaders skip empty models by comparing their root with ARENA_HANDLE_NONE.
+        let header = include_str!("shaders/arena.glsl");
+        let value = header
+            .lines()
+            .find_map(|line| line.strip_prefix("#define ARENA_HANDLE_NONE 0x"))
+            .unwrap()
+            .trim()
+            .trim_end_matches('u');
+        assert_eq!(
+            u32::from_str_radix(value, 16).unwrap(),
+            Handle::none().get_value()
+        );
+    }
 }
@@ -0,0 +1,118 @@
+/// A point on the timeline of a block allocator.
+/// The memory of a block becomes resident on the device once the timeline reaches the ticket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ResidencyTicket(pub u64);
+
+impl ResidencyTicket {
+    // The memory is resident as soon as the allocation returns.
+    pub const RESIDENT: ResidencyTicket = ResidencyTicket(0);
+
+    pub fn is_reached(&self, timeline_value: u64) -> bool {
+        self.0 <= timeline_value
+    }
+}
+
+// Queues up bind requests so that they can be submitted in batches.
+// Each submitted batch signals the next value on the timeline.
+// Requests pushed before a batch is taken will be resident once that batch's value is reached.
+pub struct BindBatcher<T> {
+    pending: Vec<T>,
+    // The value signaled by the last submitted batch.
+    submitted_value: u64,
+}
+
+impl<T> BindBatcher<T> {
+    pub fn new() -> Self {
+        BindBatcher {
+            pending: Vec::new(),
+            submitted_value: 0,
+        }
+    }
+    pub fn push(&mut self, request: T) -> ResidencyTicket {
+        self.pending.push(request);
+        ResidencyTicket(self.submitted_value + 1)
+    }
+    // Remove a request that hasn't been submitted yet.
+    pub fn cancel(&mut self, mut predicate: impl FnMut(&T) -> bool) -> Option<T> {
+        let index = self.pending.iter().position(|request| predicate(request))?;
+        Some(self.pending.remove(index))
+    }
+    // Take all pending requests, together with the timeline value to signal once they were bound.
+    pub fn take_batch(&mut self) -> Option<(Vec<T>, u64)> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        self.submitted_value += 1;
+        Some((std::mem::take(&mut self.pending), self.submitted_value))
+    }
+    // Give back the batch taken last, after its submission failed.
+    // Its requests go first into the next batch, which signals the same value.
+    pub fn return_batch(&mut self, mut requests: Vec<T>) {
+        self.submitted_value -= 1;
+        requests.append(&mut self.pending);
+        self.pending = requests;
+    }
+    pub fn submitted_value(&self) -> u64 {
+        self.submitted_value
+    }
+    pub fn is_submitted(&self, ticket: ResidencyTicket) -> bool {
+        ticket.is_reached(self.submitted_value)
+    }
+    pub fn num_pending(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BindBatcher, ResidencyTicket};
+
+    #[test]
+    fn test_batches() {
+        let mut batcher: BindBatcher<u32> = BindBatcher::new();
+        assert!(batcher.take_batch().is_none());
+
+        let a = batcher.push(1);
+        let b = batcher.push(2);
+        assert_eq!(a, ResidencyTicket(1));
+        assert_eq!(a, b);
+        assert!(!batcher.is_submitted(a));
+
+        assert_eq!(batcher.take_batch(), Some((vec![1, 2], 1)));
+        assert!(batcher.is_submitted(a));
+        assert!(batcher.take_batch().is_none());
+
+        let c = batcher.push(3);
+        assert_eq!(c, ResidencyTicket(2));
+        assert!(!c.is_reached(1));
+        assert!(a.is_reached(1));
+        assert_eq!(batcher.take_batch(), Some((vec![3], 2)));
+        assert!(c.is_reached(2));
+        assert!(ResidencyTicket::RESIDENT.is_reached(0));
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut batcher: BindBatcher<u32> = BindBatcher::new();
+        batcher.push(1);
+        batcher.push(2);
+        batcher.push(3);
+        assert_eq!(batcher.cancel(|request| *request == 2), Some(2));
+        assert_eq!(batcher.cancel(|request| *request == 2), None);
+        assert_eq!(batcher.num_pending(), 2);
+        assert_eq!(batcher.take_batch(), Some((vec![1, 3], 1)));
+    }
+
+    #[test]
+    fn test_return_batch() {
+        let mut batcher: BindBatcher<u32> = BindBatcher::new();
+        let a = batcher.push(1);
+        let (requests, value) = batcher.take_batch().unwrap();
+        assert!(batcher.is_submitted(a));
+        batcher.return_batch(requests);
+        assert!(!batcher.is_submitted(a));
+        let b = batcher.push(2);
+        assert_eq!(a, b);
+        assert_eq!(batcher.take_batch(), Some((vec![1, 2], value)));
+    }
+}
@@ -0,0 +1,158 @@
+use super::{
+    AllocError, BlockAllocation, BlockAllocator, BlockAllocatorAddressSpace, ResidencyTicket,
+};
+use ash::vk;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Tracks the bytes committed by a block allocator across all of its address spaces.
+/// The budget is soft: allocations beyond the budget still succeed, and it is up to the
+/// application to unload data until `excess` drops back to zero.
+pub struct MemoryBudget {
+    budget: AtomicU64,
+    committed: AtomicU64,
+}
+
+impl MemoryBudget {
+    pub fn new(budget: u64) -> Self {
+        MemoryBudget {
+            budget: AtomicU64::new(budget),
+            committed: AtomicU64::new(0),
+        }
+    }
+    pub fn unlimited() -> Self {
+        Self::new(u64::MAX)
+    }
+    pub fn budget(&self) -> u64 {
+        self.budget.load(Ordering::Relaxed)
+    }
+    pub fn set_budget(&self, budget: u64) {
+        self.budget.store(budget, Ordering::Relaxed);
+    }
+    pub fn committed(&self) -> u64 {
+        self.committed.load(Ordering::Relaxed)
+    }
+    // Number of bytes that need to be freed to get back within the budget.
+    pub fn excess(&self) -> u64 {
+        self.committed().saturating_sub(self.budget())
+    }
+    pub fn is_exceeded(&self) -> bool {
+        self.excess() > 0
+    }
+}
+
+/// Wraps a block allocator and accounts all of its blocks against a `MemoryBudget`.
+pub struct BudgetedBlockAllocator<A: BlockAllocator> {
+    inner: A,
+    budget: Arc<MemoryBudget>,
+}
+
+impl<A: BlockAllocator> BudgetedBlockAllocator<A> {
+    pub fn new(inner: A, budget: Arc<MemoryBudget>) -> Self {
+        BudgetedBlockAllocator { inner, budget }
+    }
+    pub fn budget(&self) -> &Arc<MemoryBudget> {
+        &self.budget
+    }
+}
+
+impl<A: BlockAllocator> BlockAllocator for BudgetedBlockAllocator<A> {
+    unsafe fn create_address_space(&self) -> BlockAllocatorAddressSpace {
+        self.inner.create_address_space()
+    }
+    unsafe fn destroy_address_space(&self, address_space: BlockAllocatorAddressSpace) {
+        self.inner.destroy_address_space(address_space)
+    }
+    unsafe fn allocate_block(
+        &self,
+        address_space: &BlockAllocatorAddressSpace,
+    ) -> Result<(*mut u8, BlockAllocation, ResidencyTicket), AllocError> {
+        let block = self.inner.allocate_block(address_space)?;
+        self.budget
+            .committed
+            .fetch_add(self.inner.get_blocksize(), Ordering::Relaxed);
+        Ok(block)
+    }
+    unsafe fn deallocate_block(
+        &self,
+        address_space: &BlockAllocatorAddressSpace,
+        block: BlockAllocation,
+    ) {
+        self.inner.deallocate_block(address_space, block);
+        self.budget
+            .committed
+            .fetch_sub(self.inner.get_blocksize(), Ordering::Relaxed);
+    }
+    unsafe fn flush(
+        &self,
+        ranges: &mut dyn Iterator<
+            Item = (&BlockAllocatorAddressSpace, &BlockAllocation, Range<u32>),
+        >,
+    ) -> Result<(), AllocError> {
+        self.inner.flush(ranges)
+    }
+    fn can_flush(&self) -> Result<bool, AllocError> {
+        self.inner.can_flush()
+    }
+    fn is_resident(&self, ticket: ResidencyTicket) -> bool {
+        self.inner.is_resident(ticket)
+    }
+    fn bind_timeline(&self) -> Option<(vk::Semaphore, u64)> {
+        self.inner.bind_timeline()
+    }
+    fn has_device_buffer(&self) -> bool {
+        self.inner.has_device_buffer()
+    }
+    fn get_blocksize(&self) -> u64 {
+        self.inner.get_blocksize()
+    }
+    fn get_device_buffer_size(&self) -> u64 {
+        self.inner.get_device_buffer_size()
+    }
+    fn get_buffer(&self, address_space: &BlockAllocatorAddressSpace) -> vk::Buffer {
+        self.inner.get_buffer(address_space)
+    }
+    fn get_buffer_device_address(
+        &self,
+        address_space: &BlockAllocatorAddressSpace,
+    ) -> vk::DeviceAddress {
+        self.inner.get_buffer_device_address(address_space)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BudgetedBlockAllocator, MemoryBudget};
+    use crate::raytrace::block_alloc::{BlockAllocator, SystemBlockAllocator};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_accounting() {
+        let budget = Arc::new(MemoryBudget::new(2048));
+        let allocator =
+            BudgetedBlockAllocator::new(SystemBlockAllocator::new(1024), budget.clone());
+        unsafe {
+            let address_space = allocator.create_address_space();
+            let (_, a, _) = allocator.allocate_block(&address_space).unwrap();
+            let (_, b, _) = allocator.allocate_block(&address_space).unwrap();
+            assert_eq!(budget.committed(), 2048);
+            assert!(!budget.is_exceeded());
+
+            let (_, c, _) = allocator.allocate_block(&address_space).unwrap();
+            assert_eq!(budget.excess(), 1024);
+            budget.set_budget(4096);
+            assert!(!budget.is_exceeded());
+            budget.set_budget(1024);
+            assert_eq!(budget.excess(), 2048);
+
+            allocator.deallocate_block(&address_space, a);
+            allocator.deallocate_block(&address_space, b);
+            assert_eq!(budget.committed(), 1024);
+            assert!(!budget.is_exceeded());
+            allocator.deallocate_block(&address_space, c);
+            assert_eq!(budget.committed(), 0);
+            allocator.destroy_address_space(address_space);
+        }
+    }
+}
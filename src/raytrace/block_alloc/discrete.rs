@@ -1,16 +1,26 @@
+use super::bind_batch::BindBatcher;
 use super::{
     AllocError, AllocatorCreateInfo, BlockAllocation, BlockAllocator, BlockAllocatorAddressSpace,
+    ResidencyTicket,
 };
 use ash::vk;
+use bevy::log::error;
 use crossbeam::queue::SegQueue;
 use std::ops::Range;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
 
 pub struct DiscreteBlock {
     system_mem: vk::DeviceMemory,
     system_buf: vk::Buffer,
     device_mem: vk::DeviceMemory,
     offset: u64,
+    ticket: ResidencyTicket,
+}
+
+struct PendingBind {
+    buffer: vk::Buffer,
+    bind: vk::SparseMemoryBind,
 }
 
 struct DiscreteAddressSpace {
@@ -32,6 +42,12 @@ pub struct DiscreteBlockAllocator {
     command_buffer: vk::CommandBuffer,
     copy_completion_fence: vk::Fence,
     memory_properties: vk::PhysicalDeviceMemoryProperties,
+
+    // Sparse binding requests are queued up and submitted in batches on flush.
+    // Each batch signals the next value on the timeline semaphore.
+    // Blocks therefore only become resident after the flush following their allocation.
+    binds: Mutex<BindBatcher<PendingBind>>,
+    bind_timeline_semaphore: vk::Semaphore,
 }
 unsafe impl Send for DiscreteBlockAllocator {}
 unsafe impl Sync for DiscreteBlockAllocator {}
@@ -73,6 +89,19 @@ impl DiscreteBlockAllocator {
                 None,
             )
             .unwrap();
+        let bind_timeline_semaphore = device
+            .create_semaphore(
+                &vk::SemaphoreCreateInfo::builder()
+                    .push_next(
+                        &mut vk::SemaphoreTypeCreateInfo::builder()
+                            .semaphore_type(vk::SemaphoreType::TIMELINE)
+                            .initial_value(0)
+                            .build(),
+                    )
+                    .build(),
+                None,
+            )
+            .unwrap();
 
         let device_buffer_size =
             (create_info.max_storage_buffer_size / create_info.block_size) * create_info.block_size;
@@ -85,10 +114,131 @@ impl DiscreteBlockAllocator {
             command_buffer,
             copy_completion_fence,
             memory_properties: memory_properties.clone(),
+            binds: Mutex::new(BindBatcher::new()),
+            bind_timeline_semaphore,
             device,
             device_buffer_size,
         }
     }
+
+    // Allocate the staging and device memory for a block.
+    // All resources created so far are released on failure.
+    unsafe fn allocate_block_memory(
+        &self,
+        address_space: &DiscreteAddressSpace,
+    ) -> Result<(*mut u8, vk::Buffer, vk::DeviceMemory, vk::DeviceMemory), AllocError> {
+        let system_buf = self
+            .device
+            .create_buffer(
+                &vk::BufferCreateInfo::builder()
+                    .size(self.block_size)
+                    .usage(vk::BufferUsageFlags::TRANSFER_SRC)
+                    .sharing_mode(vk::SharingMode::EXCLUSIVE)
+                    .build(),
+                None,
+            )
+            .map_err(AllocError::from)?;
+
+        let system_buf_requirements = self.device.get_buffer_memory_requirements(system_buf);
+        let system_memtype =
+            select_system_memtype(&self.memory_properties, &system_buf_requirements);
+        let system_mem = match self.device.allocate_memory(
+            &vk::MemoryAllocateInfo::builder()
+                .memory_type_index(system_memtype)
+                .allocation_size(self.block_size)
+                .build(),
+            None,
+        ) {
+            Ok(mem) => mem,
+            Err(err) => {
+                self.device.destroy_buffer(system_buf, None);
+                return Err(err.into());
+            }
+        };
+        let release_system = || {
+            self.device.destroy_buffer(system_buf, None);
+            self.device.free_memory(system_mem, None);
+        };
+        if let Err(err) = self.device.bind_buffer_memory(system_buf, system_mem, 0) {
+            release_system();
+            return Err(err.into());
+        }
+        let ptr =
+            match self
+                .device
+                .map_memory(system_mem, 0, vk::WHOLE_SIZE, vk::MemoryMapFlags::empty())
+            {
+                Ok(ptr) => ptr as *mut u8,
+                Err(err) => {
+                    release_system();
+                    return Err(err.into());
+                }
+            };
+
+        let device_mem = match self.device.allocate_memory(
+            &vk::MemoryAllocateInfo::builder()
+                .memory_type_index(address_space.device_memtype)
+                .allocation_size(self.block_size)
+                .build(),
+            None,
+        ) {
+            Ok(mem) => mem,
+            Err(err) => {
+                release_system();
+                return Err(err.into());
+            }
+        };
+        Ok((ptr, system_buf, system_mem, device_mem))
+    }
+
+    // Submit all queued sparse binding requests in a single batch.
+    // Returns the timeline value signaled once all blocks allocated so far are resident.
+    // On failure, the requests stay queued for the next flush.
+    unsafe fn submit_binds(&self) -> Result<u64, AllocError> {
+        let mut binds = self.binds.lock().unwrap();
+        let (requests, signal_value) = match binds.take_batch() {
+            Some(batch) => batch,
+            None => return Ok(binds.submitted_value()),
+        };
+        // Group the requests by buffer. Each address space has its own buffer.
+        let mut buffers: Vec<(vk::Buffer, Vec<vk::SparseMemoryBind>)> = Vec::new();
+        for request in requests.iter() {
+            match buffers
+                .iter_mut()
+                .find(|(buffer, _)| *buffer == request.buffer)
+            {
+                Some((_, memory_binds)) => memory_binds.push(request.bind),
+                None => buffers.push((request.buffer, vec![request.bind])),
+            }
+        }
+        let buffer_binds: Vec<vk::SparseBufferMemoryBindInfo> = buffers
+            .iter()
+            .map(|(buffer, memory_binds)| {
+                vk::SparseBufferMemoryBindInfo::builder()
+                    .buffer(*buffer)
+                    .binds(memory_binds)
+                    .build()
+            })
+            .collect();
+        let result = self.device.queue_bind_sparse(
+            self.bind_transfer_queue,
+            &[vk::BindSparseInfo::builder()
+                .buffer_binds(&buffer_binds)
+                .signal_semaphores(&[self.bind_timeline_semaphore])
+                .push_next(
+                    &mut vk::TimelineSemaphoreSubmitInfo::builder()
+                        .signal_semaphore_values(&[signal_value])
+                        .build(),
+                )
+                .build()],
+            vk::Fence::null(),
+        );
+        if let Err(err) = result {
+            binds.return_batch(requests);
+            return Err(err.into());
+        }
+        Ok(signal_value)
+    }
 }
 
 impl BlockAllocator for DiscreteBlockAllocator {
@@ -138,7 +288,7 @@ impl BlockAllocator for DiscreteBlockAllocator {
     unsafe fn allocate_block(
         &self,
         address_space: &BlockAllocatorAddressSpace,
-    ) -> Result<(*mut u8, BlockAllocation), AllocError> {
+    ) -> Result<(*mut u8, BlockAllocation, ResidencyTicket), AllocError> {
         let address_space: &DiscreteAddressSpace =
             &*(address_space.0 as *const DiscreteAddressSpace);
         let resource_offset = address_space
@@ -146,88 +296,35 @@ impl BlockAllocator for DiscreteBlockAllocator {
             .pop()
             .unwrap_or_else(|| address_space.current_offset.fetch_add(1, Ordering::Relaxed));
 
-        let system_buf = self
-            .device
-            .create_buffer(
-                &vk::BufferCreateInfo::builder()
-                    .size(self.block_size)
-                    .usage(vk::BufferUsageFlags::TRANSFER_SRC)
-                    .sharing_mode(vk::SharingMode::EXCLUSIVE)
-                    .build(),
-                None,
-            )
-            .unwrap();
-
-        let system_buf_requirements = self.device.get_buffer_memory_requirements(system_buf);
-        let system_memtype =
-            select_system_memtype(&self.memory_properties, &system_buf_requirements);
-        let system_mem = self
-            .device
-            .allocate_memory(
-                &vk::MemoryAllocateInfo::builder()
-                    .memory_type_index(system_memtype)
-                    .allocation_size(self.block_size)
-                    .build(),
-                None,
-            )
-            .map_err(super::AllocError::from)?;
-        self.device
-            .bind_buffer_memory(system_buf, system_mem, 0)
-            .unwrap();
-        let ptr = self
-            .device
-            .map_memory(system_mem, 0, vk::WHOLE_SIZE, vk::MemoryMapFlags::empty())
-            .map_err(super::AllocError::from)? as *mut u8;
-
-        let device_mem = self
-            .device
-            .allocate_memory(
-                &vk::MemoryAllocateInfo::builder()
-                    .memory_type_index(address_space.device_memtype)
-                    .allocation_size(self.block_size)
-                    .build(),
-                None,
-            )
-            .map_err(super::AllocError::from)?;
+        let block = self.allocate_block_memory(address_space);
+        if block.is_err() {
+            // Return the offset so that it may be reused by the next allocation.
+            address_space.free_offsets.push(resource_offset);
+        }
+        let (ptr, system_buf, system_mem, device_mem) = block?;
 
-        // Immediately submit the request
-        let fence = self
-            .device
-            .create_fence(&vk::FenceCreateInfo::default(), None)
-            .unwrap();
-        self.device
-            .queue_bind_sparse(
-                self.bind_transfer_queue,
-                &[vk::BindSparseInfo::builder()
-                    .buffer_binds(&[vk::SparseBufferMemoryBindInfo::builder()
-                        .buffer(address_space.device_buffer)
-                        .binds(&[vk::SparseMemoryBind {
-                            resource_offset: resource_offset * self.block_size as u64,
-                            size: self.block_size,
-                            memory: device_mem,
-                            memory_offset: 0,
-                            flags: vk::SparseMemoryBindFlags::empty(),
-                        }])
-                        .build()])
-                    .build()],
-                fence,
-            )
-            .map_err(super::AllocError::from)
-            .unwrap();
-        println!("Sparse Binded");
-        self.device
-            .wait_for_fences(&[fence], true, u64::MAX)
-            .unwrap();
-        self.device.destroy_fence(fence, None);
+        // The binding is submitted with the next batch, at the latest before the next flush.
+        // The host memory can be written to in the meantime.
+        let ticket = self.binds.lock().unwrap().push(PendingBind {
+            buffer: address_space.device_buffer,
+            bind: vk::SparseMemoryBind {
+                resource_offset: resource_offset * self.block_size as u64,
+                size: self.block_size,
+                memory: device_mem,
+                memory_offset: 0,
+                flags: vk::SparseMemoryBindFlags::empty(),
+            },
+        });
         let block = DiscreteBlock {
             system_mem,
             device_mem,
             system_buf,
             offset: resource_offset,
+            ticket,
         };
         let block = Box::new(block);
         let allocation = BlockAllocation(Box::into_raw(block) as u64);
-        Ok((ptr, allocation))
+        Ok((ptr, allocation, ticket))
     }
 
     unsafe fn deallocate_block(
@@ -240,6 +337,26 @@ impl BlockAllocator for DiscreteBlockAllocator {
         let block = allocation.0 as *mut DiscreteBlock;
         let block = Box::from_raw(block);
 
+        let cancelled = self
+            .binds
+            .lock()
+            .unwrap()
+            .cancel(|request| request.bind.memory == block.device_mem)
+            .is_some();
+        if !cancelled {
+            // The binding was already submitted. Wait for it before freeing the memory.
+            let result = self.device.wait_semaphores(
+                &vk::SemaphoreWaitInfo::builder()
+                    .semaphores(&[self.bind_timeline_semaphore])
+                    .values(&[block.ticket.0])
+                    .build(),
+                u64::MAX,
+            );
+            // Once the device is lost nothing executes anymore, so the memory may be freed.
+            if let Err(err) = result {
+                error!("Waiting for a block binding failed: {}", err);
+            }
+        }
         self.device.destroy_buffer(block.system_buf, None);
         self.device.free_memory(block.system_mem, None);
         self.device.free_memory(block.device_mem, None);
@@ -252,18 +369,18 @@ impl BlockAllocator for DiscreteBlockAllocator {
         ranges: &mut dyn Iterator<
             Item = (&BlockAllocatorAddressSpace, &BlockAllocation, Range<u32>),
         >,
-    ) {
-        self.device
-            .reset_command_pool(self.command_pool, vk::CommandPoolResetFlags::empty())
-            .unwrap();
+    ) -> Result<(), AllocError> {
+        // The copies may only start once the blocks they write into are bound.
+        let bind_value = self.submit_binds()?;
+
         self.device
-            .begin_command_buffer(
-                self.command_buffer,
-                &vk::CommandBufferBeginInfo::builder()
-                    .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT)
-                    .build(),
-            )
-            .unwrap();
+            .reset_command_pool(self.command_pool, vk::CommandPoolResetFlags::empty())?;
+        self.device.begin_command_buffer(
+            self.command_buffer,
+            &vk::CommandBufferBeginInfo::builder()
+                .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT)
+                .build(),
+        )?;
 
         for (address_space, block_allocation, range) in ranges {
             // TODO: revisit for effiency improvements.
@@ -284,36 +401,55 @@ impl BlockAllocator for DiscreteBlockAllocator {
                 }],
             );
         }
-        self.device.end_command_buffer(self.command_buffer).unwrap();
+        self.device.end_command_buffer(self.command_buffer)?;
 
-        self.device
-            .reset_fences(&[self.copy_completion_fence])
-            .unwrap();
+        self.device.reset_fences(&[self.copy_completion_fence])?;
         let command_buffers = [self.command_buffer];
+        let wait_semaphores = [self.bind_timeline_semaphore];
+        let wait_values = [bind_value];
+        let mut timeline_info = vk::TimelineSemaphoreSubmitInfo::builder()
+            .wait_semaphore_values(&wait_values)
+            .build();
         let submit_info = vk::SubmitInfo::builder()
             .command_buffers(&command_buffers)
+            .wait_semaphores(&wait_semaphores)
+            .wait_dst_stage_mask(&[vk::PipelineStageFlags::TRANSFER])
+            .push_next(&mut timeline_info)
             .build();
-        self.device
-            .queue_submit(
-                self.bind_transfer_queue,
-                &[submit_info],
-                self.copy_completion_fence,
-            )
-            .unwrap();
+        self.device.queue_submit(
+            self.bind_transfer_queue,
+            &[submit_info],
+            self.copy_completion_fence,
+        )?;
+        Ok(())
     }
-    fn can_flush(&self) -> bool {
+    fn can_flush(&self) -> Result<bool, AllocError> {
         // If the previous copy hasn't completed: simply signal that we're busy at the moment.
         // The changes are going to be submitted to the queue in the next frame.
-        let copy_completed = unsafe {
-            self.device
-                .get_fence_status(self.copy_completion_fence)
-                .unwrap()
-        };
+        let copy_completed = unsafe { self.device.get_fence_status(self.copy_completion_fence)? };
 
         // Note that it's ok to have a copy command and a sparse binding command
         // in the queue at the same time. The copy command won't reference the newly
         // allocated memory ranges.
-        copy_completed
+        Ok(copy_completed)
+    }
+    fn is_resident(&self, ticket: ResidencyTicket) -> bool {
+        let value = unsafe {
+            self.device
+                .get_semaphore_counter_value(self.bind_timeline_semaphore)
+        };
+        match value {
+            Ok(value) => ticket.is_reached(value),
+            Err(err) => {
+                // A lost device never makes the block resident.
+                error!("Reading the bind timeline failed: {}", err);
+                false
+            }
+        }
+    }
+    fn bind_timeline(&self) -> Option<(vk::Semaphore, u64)> {
+        let submitted_value = self.binds.lock().unwrap().submitted_value();
+        Some((self.bind_timeline_semaphore, submitted_value))
     }
     fn get_blocksize(&self) -> u64 {
         self.block_size
@@ -0,0 +1,301 @@
+use super::{
+    AllocError, BlockAllocation, BlockAllocator, BlockAllocatorAddressSpace, ResidencyTicket,
+};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::ops::Range;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+// The header is stored in the first HEADER_SIZE bytes of the file.
+// Blocks follow, with block n located at HEADER_SIZE + n * block_size.
+const HEADER_SIZE: u64 = 1 << 16;
+const MAGIC: u64 = u64::from_le_bytes(*b"DUSTSVDG");
+
+#[repr(C)]
+struct FileHeader {
+    magic: u64,
+    block_size: u64,
+    num_blocks: u64,
+    metadata_len: u64,
+}
+const MAX_METADATA_LEN: usize = HEADER_SIZE as usize - std::mem::size_of::<FileHeader>();
+
+struct FileBlock {
+    ptr: *mut u8,
+    index: u64,
+}
+
+/// A block allocator storing its blocks in a memory mapped file.
+/// Data may exceed the size of RAM, and a file can be reopened later without rebuilding its content.
+/// Each file holds exactly one address space.
+/// Blocks are never removed from the file. Deallocating a block only unmaps it.
+/// The blocks live in host memory only. Models stored in a file are not rendered
+/// until they are uploaded into a device-backed allocator with `Svdag::upload`.
+pub struct FileBlockAllocator {
+    file: File,
+    block_size: u64,
+    page_size: usize,
+    header: *mut FileHeader,
+    // Serializes file growth and header updates.
+    lock: Mutex<()>,
+    has_address_space: AtomicBool,
+}
+unsafe impl Send for FileBlockAllocator {}
+unsafe impl Sync for FileBlockAllocator {}
+
+impl FileBlockAllocator {
+    /// Create a new file at `path`, discarding any existing content.
+    pub fn create(path: impl AsRef<Path>, block_size: u64) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len(HEADER_SIZE)?;
+        let allocator = Self::from_file(file, block_size)?;
+        assert_eq!(block_size % allocator.page_size as u64, 0);
+        unsafe {
+            allocator.header.write(FileHeader {
+                magic: MAGIC,
+                block_size,
+                num_blocks: 0,
+                metadata_len: 0,
+            });
+        }
+        allocator.sync_header()?;
+        Ok(allocator)
+    }
+
+    /// Open a file previously written by a FileBlockAllocator.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let file_len = file.metadata()?.len();
+        if file_len < HEADER_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "File too small"));
+        }
+        let mut allocator = Self::from_file(file, 0)?;
+        let header = unsafe { &*allocator.header };
+        let invalid = |message| Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        if header.magic != MAGIC {
+            return invalid("Not a block file");
+        }
+        if header.block_size == 0 || header.block_size % allocator.page_size as u64 != 0 {
+            return invalid("Invalid block size");
+        }
+        if header.metadata_len > MAX_METADATA_LEN as u64 {
+            return invalid("Invalid metadata length");
+        }
+        let blocks_end = (header.num_blocks.checked_mul(header.block_size))
+            .and_then(|len| len.checked_add(HEADER_SIZE));
+        if blocks_end.map_or(true, |end| end > file_len) {
+            return invalid("File truncated");
+        }
+        allocator.block_size = header.block_size;
+        Ok(allocator)
+    }
+
+    fn from_file(file: File, block_size: u64) -> io::Result<Self> {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        assert_eq!(HEADER_SIZE as usize % page_size, 0);
+        let header = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                HEADER_SIZE as usize,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if header == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(FileBlockAllocator {
+            file,
+            block_size,
+            page_size,
+            header: header as *mut FileHeader,
+            lock: Mutex::new(()),
+            has_address_space: AtomicBool::new(false),
+        })
+    }
+
+    fn sync_header(&self) -> io::Result<()> {
+        unsafe { msync(self.header as *mut u8, HEADER_SIZE as usize) }
+    }
+
+    /// Number of blocks stored in the file.
+    pub fn num_blocks(&self) -> u64 {
+        let _guard = self.lock.lock().unwrap();
+        unsafe { (*self.header).num_blocks }
+    }
+
+    /// The metadata last written with `set_metadata`.
+    pub fn metadata(&self) -> Vec<u8> {
+        let _guard = self.lock.lock().unwrap();
+        unsafe {
+            let len = (*self.header).metadata_len as usize;
+            let ptr = (self.header as *const u8).add(std::mem::size_of::<FileHeader>());
+            std::slice::from_raw_parts(ptr, len).to_vec()
+        }
+    }
+
+    /// Store application data in the file header, for example the chunk table of an arena.
+    pub fn set_metadata(&self, metadata: &[u8]) -> io::Result<()> {
+        assert!(metadata.len() <= MAX_METADATA_LEN, "Metadata too large");
+        {
+            let _guard = self.lock.lock().unwrap();
+            unsafe {
+                let ptr = (self.header as *mut u8).add(std::mem::size_of::<FileHeader>());
+                std::ptr::copy_nonoverlapping(metadata.as_ptr(), ptr, metadata.len());
+                (*self.header).metadata_len = metadata.len() as u64;
+            }
+        }
+        self.sync_header()
+    }
+
+    /// Map a block already stored in the file. Used when reopening a file.
+    pub unsafe fn map_block(&self, index: u64) -> Result<(*mut u8, BlockAllocation), AllocError> {
+        assert!(
+            index < self.num_blocks(),
+            "Block {} is not in the file",
+            index
+        );
+        self.map_block_unchecked(index)
+    }
+
+    unsafe fn map_block_unchecked(
+        &self,
+        index: u64,
+    ) -> Result<(*mut u8, BlockAllocation), AllocError> {
+        let ptr = libc::mmap(
+            std::ptr::null_mut(),
+            self.block_size as usize,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            self.file.as_raw_fd(),
+            (HEADER_SIZE + index * self.block_size) as libc::off_t,
+        );
+        if ptr == libc::MAP_FAILED {
+            return Err(AllocError::MappingFailed);
+        }
+        let ptr = ptr as *mut u8;
+        let block = Box::new(FileBlock { ptr, index });
+        Ok((ptr, BlockAllocation(Box::into_raw(block) as u64)))
+    }
+}
+
+// Write the pages in `ptr..ptr + len` back to the file. `ptr` has to be page aligned.
+unsafe fn msync(ptr: *mut u8, len: usize) -> io::Result<()> {
+    if libc::msync(ptr as *mut _, len, libc::MS_SYNC) != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl Drop for FileBlockAllocator {
+    fn drop(&mut self) {
+        // Every header update was synced when it was made.
+        unsafe {
+            libc::munmap(self.header as *mut _, HEADER_SIZE as usize);
+        }
+    }
+}
+
+impl BlockAllocator for FileBlockAllocator {
+    unsafe fn create_address_space(&self) -> BlockAllocatorAddressSpace {
+        let existed = self.has_address_space.swap(true, Ordering::Relaxed);
+        assert!(
+            !existed,
+            "FileBlockAllocator only supports one address space"
+        );
+        BlockAllocatorAddressSpace(0)
+    }
+    unsafe fn destroy_address_space(&self, _address_space: BlockAllocatorAddressSpace) {
+        self.has_address_space.store(false, Ordering::Relaxed);
+    }
+    unsafe fn allocate_block(
+        &self,
+        _address_space: &BlockAllocatorAddressSpace,
+    ) -> Result<(*mut u8, BlockAllocation, ResidencyTicket), AllocError> {
+        let _guard = self.lock.lock().unwrap();
+        let index = (*self.header).num_blocks;
+        let old_len = HEADER_SIZE + index * self.block_size;
+        self.file
+            .set_len(old_len + self.block_size)
+            .map_err(AllocError::Io)?;
+        // The block only counts as stored once it is mapped and the header is synced,
+        // so a failure leaves the file as it was.
+        let result = self
+            .map_block_unchecked(index)
+            .and_then(|(ptr, allocation)| {
+                (*self.header).num_blocks = index + 1;
+                if let Err(err) = self.sync_header() {
+                    (*self.header).num_blocks = index;
+                    self.deallocate_block(&BlockAllocatorAddressSpace(0), allocation);
+                    return Err(AllocError::Io(err));
+                }
+                Ok((ptr, allocation, ResidencyTicket::RESIDENT))
+            });
+        if result.is_err() {
+            // Best effort, the file is only longer than it needs to be otherwise.
+            let _ = self.file.set_len(old_len);
+        }
+        result
+    }
+
+    unsafe fn deallocate_block(
+        &self,
+        _address_space: &BlockAllocatorAddressSpace,
+        block: BlockAllocation,
+    ) {
+        let block = Box::from_raw(block.0 as *mut FileBlock);
+        // Writes since the last flush are not referenced by any saved metadata,
+        // so the block is unmapped without syncing it.
+        libc::munmap(block.ptr as *mut _, self.block_size as usize);
+    }
+
+    unsafe fn flush(
+        &self,
+        ranges: &mut dyn Iterator<
+            Item = (&BlockAllocatorAddressSpace, &BlockAllocation, Range<u32>),
+        >,
+    ) -> Result<(), AllocError> {
+        for (_address_space, allocation, range) in ranges {
+            let block = &*(allocation.0 as *const FileBlock);
+            debug_assert!(
+                (HEADER_SIZE + block.index * self.block_size) % self.page_size as u64 == 0
+            );
+            // msync requires the address to be page aligned.
+            let start = range.start as usize / self.page_size * self.page_size;
+            msync(block.ptr.add(start), range.end as usize - start).map_err(AllocError::Io)?;
+        }
+        Ok(())
+    }
+
+    fn can_flush(&self) -> Result<bool, AllocError> {
+        Ok(true)
+    }
+    fn has_device_buffer(&self) -> bool {
+        false
+    }
+    fn get_blocksize(&self) -> u64 {
+        self.block_size
+    }
+    fn get_buffer(&self, _address_space: &BlockAllocatorAddressSpace) -> ash::vk::Buffer {
+        panic!("FileBlockAllocator does not have a device buffer, check has_device_buffer first")
+    }
+    fn get_device_buffer_size(&self) -> u64 {
+        panic!("FileBlockAllocator does not have a device buffer, check has_device_buffer first")
+    }
+    fn get_buffer_device_address(
+        &self,
+        _address_space: &BlockAllocatorAddressSpace,
+    ) -> ash::vk::DeviceAddress {
+        panic!("FileBlockAllocator does not have a device buffer, check has_device_buffer first")
+    }
+}
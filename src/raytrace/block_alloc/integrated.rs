@@ -2,6 +2,7 @@ use ash::vk;
 
 use super::{
     AllocError, AllocatorCreateInfo, BlockAllocation, BlockAllocator, BlockAllocatorAddressSpace,
+    ResidencyTicket,
 };
 use crossbeam::queue::SegQueue;
 use std::ops::Range;
@@ -86,46 +87,61 @@ impl BlockAllocator for IntegratedBlockAllocator {
     unsafe fn allocate_block(
         &self,
         address_space: &BlockAllocatorAddressSpace,
-    ) -> Result<(*mut u8, BlockAllocation), AllocError> {
+    ) -> Result<(*mut u8, BlockAllocation, ResidencyTicket), AllocError> {
         let address_space = &*(address_space.0 as *const IntegratedAddressSpace);
         let resource_offset = address_space
             .free_offsets
             .pop()
             .unwrap_or_else(|| address_space.current_offset.fetch_add(1, Ordering::Relaxed));
-        let mem = self
-            .device
-            .allocate_memory(
-                &vk::MemoryAllocateInfo::builder()
-                    .allocation_size(self.block_size)
-                    .memory_type_index(address_space.memtype)
-                    .build(),
-                None,
-            )
-            .unwrap();
-        let ptr = self
+        let mem = match self.device.allocate_memory(
+            &vk::MemoryAllocateInfo::builder()
+                .allocation_size(self.block_size)
+                .memory_type_index(address_space.memtype)
+                .build(),
+            None,
+        ) {
+            Ok(mem) => mem,
+            Err(err) => {
+                address_space.free_offsets.push(resource_offset);
+                return Err(err.into());
+            }
+        };
+        let release = || {
+            self.device.free_memory(mem, None);
+            address_space.free_offsets.push(resource_offset);
+        };
+        let ptr = match self
             .device
             .map_memory(mem, 0, vk::WHOLE_SIZE, vk::MemoryMapFlags::empty())
-            .map_err(super::AllocError::from)? as *mut u8;
-        self.device
-            .queue_bind_sparse(
-                self.bind_transfer_queue,
-                &[vk::BindSparseInfo::builder()
-                    .buffer_binds(&[vk::SparseBufferMemoryBindInfo::builder()
-                        .buffer(address_space.buffer)
-                        .binds(&[vk::SparseMemoryBind {
-                            resource_offset: resource_offset * self.block_size as u64,
-                            size: self.block_size,
-                            memory: mem,
-                            memory_offset: 0,
-                            flags: vk::SparseMemoryBindFlags::empty(),
-                        }])
-                        .build()])
-                    .build()],
-                vk::Fence::null(),
-            )
-            .map_err(super::AllocError::from)?;
+        {
+            Ok(ptr) => ptr as *mut u8,
+            Err(err) => {
+                release();
+                return Err(err.into());
+            }
+        };
+        let result = self.device.queue_bind_sparse(
+            self.bind_transfer_queue,
+            &[vk::BindSparseInfo::builder()
+                .buffer_binds(&[vk::SparseBufferMemoryBindInfo::builder()
+                    .buffer(address_space.buffer)
+                    .binds(&[vk::SparseMemoryBind {
+                        resource_offset: resource_offset * self.block_size as u64,
+                        size: self.block_size,
+                        memory: mem,
+                        memory_offset: 0,
+                        flags: vk::SparseMemoryBindFlags::empty(),
+                    }])
+                    .build()])
+                .build()],
+            vk::Fence::null(),
+        );
+        if let Err(err) = result {
+            release();
+            return Err(err.into());
+        }
         let allocation = BlockAllocation(std::mem::transmute(mem));
-        Ok((ptr, allocation))
+        Ok((ptr, allocation, ResidencyTicket::RESIDENT))
     }
 
     unsafe fn deallocate_block(
@@ -142,25 +158,24 @@ impl BlockAllocator for IntegratedBlockAllocator {
         ranges: &mut dyn Iterator<
             Item = (&BlockAllocatorAddressSpace, &BlockAllocation, Range<u32>),
         >,
-    ) {
+    ) -> Result<(), AllocError> {
         // TODO: only do this for non-coherent memory
-        self.device
-            .flush_mapped_memory_ranges(
-                &ranges
-                    .map(|(_address_space, allocation, range)| {
-                        let memory: vk::DeviceMemory = std::mem::transmute(allocation.0);
-                        vk::MappedMemoryRange::builder()
-                            .memory(memory)
-                            .offset(range.start as u64)
-                            .size((range.end - range.start) as u64)
-                            .build()
-                    })
-                    .collect::<Vec<_>>(),
-            )
-            .unwrap();
+        self.device.flush_mapped_memory_ranges(
+            &ranges
+                .map(|(_address_space, allocation, range)| {
+                    let memory: vk::DeviceMemory = std::mem::transmute(allocation.0);
+                    vk::MappedMemoryRange::builder()
+                        .memory(memory)
+                        .offset(range.start as u64)
+                        .size((range.end - range.start) as u64)
+                        .build()
+                })
+                .collect::<Vec<_>>(),
+        )?;
+        Ok(())
     }
-    fn can_flush(&self) -> bool {
-        true
+    fn can_flush(&self) -> Result<bool, AllocError> {
+        Ok(true)
     }
     fn get_blocksize(&self) -> u64 {
         self.block_size
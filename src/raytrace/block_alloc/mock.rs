@@ -0,0 +1,132 @@
+use super::{
+    AllocError, BlockAllocation, BlockAllocator, BlockAllocatorAddressSpace, ResidencyTicket,
+};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MockEvent {
+    AllocateBlock(u64),
+    DeallocateBlock(u64),
+    // Block id and the flushed byte range within the block.
+    Flush(u64, Range<u32>),
+}
+
+#[derive(Default)]
+struct MockState {
+    events: Vec<MockEvent>,
+    blocks: Vec<Option<Box<[u8]>>>,
+    // Calls to allocate_block remaining before the injected failure, and the error to return.
+    injected_failure: Option<(usize, AllocError)>,
+}
+
+/// A host memory block allocator for tests.
+/// It records every allocation, deallocation and flushed range, can pretend to be busy,
+/// and can fail allocations on demand.
+/// Block ids are assigned in allocation order, starting from 0.
+pub struct MockBlockAllocator {
+    block_size: usize,
+    can_flush: AtomicBool,
+    state: Mutex<MockState>,
+}
+
+impl MockBlockAllocator {
+    pub fn new(block_size: usize) -> Self {
+        MockBlockAllocator {
+            block_size,
+            can_flush: AtomicBool::new(true),
+            state: Mutex::new(MockState::default()),
+        }
+    }
+    pub fn events(&self) -> Vec<MockEvent> {
+        self.state.lock().unwrap().events.clone()
+    }
+    pub fn take_events(&self) -> Vec<MockEvent> {
+        std::mem::take(&mut self.state.lock().unwrap().events)
+    }
+    pub fn set_can_flush(&self, can_flush: bool) {
+        self.can_flush.store(can_flush, Ordering::Relaxed);
+    }
+    // Make the allocation `after` calls from now fail with `error`.
+    // With `after == 0`, the very next call to allocate_block fails.
+    pub fn inject_failure(&self, after: usize, error: AllocError) {
+        self.state.lock().unwrap().injected_failure = Some((after, error));
+    }
+    pub fn num_live_blocks(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.blocks.iter().filter(|block| block.is_some()).count()
+    }
+}
+
+impl BlockAllocator for MockBlockAllocator {
+    unsafe fn create_address_space(&self) -> BlockAllocatorAddressSpace {
+        BlockAllocatorAddressSpace(0)
+    }
+    unsafe fn destroy_address_space(&self, _address_space: BlockAllocatorAddressSpace) {}
+    unsafe fn allocate_block(
+        &self,
+        _address_space: &BlockAllocatorAddressSpace,
+    ) -> Result<(*mut u8, BlockAllocation, ResidencyTicket), AllocError> {
+        let mut state = self.state.lock().unwrap();
+        match state.injected_failure.take() {
+            Some((0, error)) => return Err(error),
+            Some((after, error)) => state.injected_failure = Some((after - 1, error)),
+            None => (),
+        }
+        let id = state.blocks.len() as u64;
+        let mut block = vec![0_u8; self.block_size].into_boxed_slice();
+        let ptr = block.as_mut_ptr();
+        state.blocks.push(Some(block));
+        state.events.push(MockEvent::AllocateBlock(id));
+        Ok((ptr, BlockAllocation(id), ResidencyTicket::RESIDENT))
+    }
+
+    unsafe fn deallocate_block(
+        &self,
+        _address_space: &BlockAllocatorAddressSpace,
+        block: BlockAllocation,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        let freed = state.blocks[block.0 as usize].take();
+        assert!(freed.is_some(), "Block {} was deallocated twice", block.0);
+        state.events.push(MockEvent::DeallocateBlock(block.0));
+    }
+
+    unsafe fn flush(
+        &self,
+        ranges: &mut dyn Iterator<
+            Item = (&BlockAllocatorAddressSpace, &BlockAllocation, Range<u32>),
+        >,
+    ) -> Result<(), AllocError> {
+        assert!(self.can_flush().unwrap(), "Flushed while busy");
+        let mut state = self.state.lock().unwrap();
+        for (_address_space, block, range) in ranges {
+            assert!(range.end as usize <= self.block_size);
+            state.events.push(MockEvent::Flush(block.0, range));
+        }
+        Ok(())
+    }
+
+    fn can_flush(&self) -> Result<bool, AllocError> {
+        Ok(self.can_flush.load(Ordering::Relaxed))
+    }
+    fn has_device_buffer(&self) -> bool {
+        false
+    }
+    fn get_blocksize(&self) -> u64 {
+        self.block_size as u64
+    }
+    fn get_buffer(&self, _address_space: &BlockAllocatorAddressSpace) -> ash::vk::Buffer {
+        panic!("MockBlockAllocator does not have a device buffer, check has_device_buffer first")
+    }
+    fn get_device_buffer_size(&self) -> u64 {
+        panic!("MockBlockAllocator does not have a device buffer, check has_device_buffer first")
+    }
+    fn get_buffer_device_address(
+        &self,
+        _address_space: &BlockAllocatorAddressSpace,
+    ) -> ash::vk::DeviceAddress {
+        panic!("MockBlockAllocator does not have a device buffer, check has_device_buffer first")
+    }
+}
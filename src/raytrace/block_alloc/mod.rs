@@ -1,13 +1,23 @@
+mod bind_batch;
+mod budget;
 mod discrete;
+#[cfg(unix)]
+mod file;
 mod integrated;
-
 #[cfg(test)]
+mod mock;
+mod retire;
 mod system;
 
+pub use bind_batch::ResidencyTicket;
+pub use budget::{BudgetedBlockAllocator, MemoryBudget};
 pub use discrete::DiscreteBlockAllocator;
+#[cfg(unix)]
+pub use file::FileBlockAllocator;
 pub use integrated::IntegratedBlockAllocator;
-
 #[cfg(test)]
+pub use mock::{MockBlockAllocator, MockEvent};
+pub use retire::{RetiredBlocks, RetiringBlockAllocator};
 pub use system::SystemBlockAllocator;
 
 use ash::vk;
@@ -21,6 +31,9 @@ pub enum AllocError {
     OutOfDeviceMemory,
     MappingFailed,
     TooManyObjects,
+    DeviceLost,
+    // The storage backing a persistent block allocator failed.
+    Io(std::io::Error),
 }
 
 impl From<vk::Result> for AllocError {
@@ -30,38 +43,75 @@ impl From<vk::Result> for AllocError {
             vk::Result::ERROR_OUT_OF_HOST_MEMORY => AllocError::OutOfHostMemory,
             vk::Result::ERROR_MEMORY_MAP_FAILED => AllocError::MappingFailed,
             vk::Result::ERROR_TOO_MANY_OBJECTS => AllocError::TooManyObjects,
+            vk::Result::ERROR_DEVICE_LOST => AllocError::DeviceLost,
             _ => panic!("{:?}", result),
         }
     }
 }
 
+impl std::fmt::Display for AllocError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let message = match self {
+            AllocError::OutOfHostMemory => "out of host memory",
+            AllocError::OutOfDeviceMemory => "out of device memory",
+            AllocError::MappingFailed => "memory mapping failed",
+            AllocError::TooManyObjects => "too many objects",
+            AllocError::DeviceLost => "device lost",
+            AllocError::Io(err) => return write!(f, "I/O error: {}", err),
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for AllocError {}
+
 pub struct BlockAllocation(pub u64);
 pub struct BlockAllocatorAddressSpace(usize);
 
 pub trait BlockAllocator: Send + Sync {
     unsafe fn create_address_space(&self) -> BlockAllocatorAddressSpace;
     unsafe fn destroy_address_space(&self, address_space: BlockAllocatorAddressSpace);
-    // Allocate a block. Returns the host pointer to the block, and an allocation token which needs to be returned.
+    // Allocate a block. Returns the host pointer to the block, an allocation token which needs to be returned,
+    // and a ticket for when the device memory of the block becomes resident.
+    // The host pointer may be written to immediately. Allocators may defer making the block resident
+    // until the next flush, so the ticket is only guaranteed to be reached after flushing.
     unsafe fn allocate_block(
         &self,
         address_space: &BlockAllocatorAddressSpace,
-    ) -> Result<(*mut u8, BlockAllocation), AllocError>;
+    ) -> Result<(*mut u8, BlockAllocation, ResidencyTicket), AllocError>;
     unsafe fn deallocate_block(
         &self,
         address_space: &BlockAllocatorAddressSpace,
         block: BlockAllocation,
     );
 
-    // Flush all host writes to the device.
+    // Flush all host writes to the device, or to the storage backing the blocks.
     unsafe fn flush(
         &self,
         ranges: &mut dyn Iterator<
             Item = (&BlockAllocatorAddressSpace, &BlockAllocation, Range<u32>),
         >,
-    );
+    ) -> Result<(), AllocError>;
 
     // Returns false if the async copy is still busy.
-    fn can_flush(&self) -> bool;
+    fn can_flush(&self) -> Result<bool, AllocError>;
+
+    // Returns true once the device memory of an allocated block is resident.
+    fn is_resident(&self, _ticket: ResidencyTicket) -> bool {
+        true
+    }
+    // The timeline semaphore signaled when block memory becomes resident, and the last value submitted.
+    // Queue submissions reading the device buffer should wait on it.
+    fn bind_timeline(&self) -> Option<(vk::Semaphore, u64)> {
+        None
+    }
+
+    // Host-only allocators return false. Their blocks can't be read by the device, so the buffer
+    // getters below must not be called on them. Models have to be uploaded into a device-backed
+    // allocator before they can be rendered.
+    fn has_device_buffer(&self) -> bool {
+        true
+    }
 
     fn get_blocksize(&self) -> u64;
     fn get_device_buffer_size(&self) -> u64;
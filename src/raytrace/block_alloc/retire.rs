@@ -0,0 +1,188 @@
+use super::{
+    AllocError, BlockAllocation, BlockAllocator, BlockAllocatorAddressSpace, ResidencyTicket,
+};
+use ash::vk;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+enum Retired {
+    Block(BlockAllocatorAddressSpace, BlockAllocation),
+    AddressSpace(BlockAllocatorAddressSpace),
+}
+
+/// The blocks and address spaces freed through a `RetiringBlockAllocator`, not yet returned
+/// to the wrapped allocator.
+pub struct RetiredBlocks {
+    inner: Arc<dyn BlockAllocator>,
+    queue: Mutex<Vec<Retired>>,
+}
+
+impl RetiredBlocks {
+    /// Take everything freed since the last call. The returned closure frees it for real.
+    /// Call it once the frames in flight that may still read the blocks are finished.
+    pub fn take(&self) -> Option<impl FnOnce() + Send + Sync + 'static> {
+        let retired = std::mem::take(&mut *self.queue.lock().unwrap());
+        if retired.is_empty() {
+            return None;
+        }
+        let inner = self.inner.clone();
+        Some(move || unsafe {
+            // In the order they were freed, so blocks go before their address space.
+            for retired in retired {
+                match retired {
+                    Retired::Block(address_space, block) => {
+                        inner.deallocate_block(&address_space, block)
+                    }
+                    Retired::AddressSpace(address_space) => {
+                        inner.destroy_address_space(address_space)
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// Wraps a block allocator and defers freeing blocks and address spaces until `RetiredBlocks::take`.
+/// The device may still be reading a DAG after it was edited or dropped on the host,
+/// for example when a hot reloaded model replaces the old one.
+pub struct RetiringBlockAllocator {
+    retired: Arc<RetiredBlocks>,
+}
+
+impl RetiringBlockAllocator {
+    pub fn new(inner: Arc<dyn BlockAllocator>) -> Self {
+        RetiringBlockAllocator {
+            retired: Arc::new(RetiredBlocks {
+                inner,
+                queue: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+    pub fn retired_blocks(&self) -> Arc<RetiredBlocks> {
+        self.retired.clone()
+    }
+    fn inner(&self) -> &dyn BlockAllocator {
+        &*self.retired.inner
+    }
+}
+
+impl BlockAllocator for RetiringBlockAllocator {
+    unsafe fn create_address_space(&self) -> BlockAllocatorAddressSpace {
+        self.inner().create_address_space()
+    }
+    unsafe fn destroy_address_space(&self, address_space: BlockAllocatorAddressSpace) {
+        self.retired
+            .queue
+            .lock()
+            .unwrap()
+            .push(Retired::AddressSpace(address_space));
+    }
+    unsafe fn allocate_block(
+        &self,
+        address_space: &BlockAllocatorAddressSpace,
+    ) -> Result<(*mut u8, BlockAllocation, ResidencyTicket), AllocError> {
+        self.inner().allocate_block(address_space)
+    }
+    unsafe fn deallocate_block(
+        &self,
+        address_space: &BlockAllocatorAddressSpace,
+        block: BlockAllocation,
+    ) {
+        self.retired.queue.lock().unwrap().push(Retired::Block(
+            BlockAllocatorAddressSpace(address_space.0),
+            block,
+        ));
+    }
+    unsafe fn flush(
+        &self,
+        ranges: &mut dyn Iterator<
+            Item = (&BlockAllocatorAddressSpace, &BlockAllocation, Range<u32>),
+        >,
+    ) -> Result<(), AllocError> {
+        self.inner().flush(ranges)
+    }
+    fn can_flush(&self) -> Result<bool, AllocError> {
+        self.inner().can_flush()
+    }
+    fn is_resident(&self, ticket: ResidencyTicket) -> bool {
+        self.inner().is_resident(ticket)
+    }
+    fn bind_timeline(&self) -> Option<(vk::Semaphore, u64)> {
+        self.inner().bind_timeline()
+    }
+    fn has_device_buffer(&self) -> bool {
+        self.inner().has_device_buffer()
+    }
+    fn get_blocksize(&self) -> u64 {
+        self.inner().get_blocksize()
+    }
+    fn get_device_buffer_size(&self) -> u64 {
+        self.inner().get_device_buffer_size()
+    }
+    fn get_buffer(&self, address_space: &BlockAllocatorAddressSpace) -> vk::Buffer {
+        self.inner().get_buffer(address_space)
+    }
+    fn get_buffer_device_address(
+        &self,
+        address_space: &BlockAllocatorAddressSpace,
+    ) -> vk::DeviceAddress {
+        self.inner().get_buffer_device_address(address_space)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RetiringBlockAllocator;
+    use crate::raytrace::arena_alloc::BLOCK_SIZE;
+    use crate::raytrace::block_alloc::{BlockAllocator, MockBlockAllocator, MockEvent};
+    use crate::raytrace::svdag::Svdag;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_retiring() {
+        let mock = Arc::new(MockBlockAllocator::new(BLOCK_SIZE as usize));
+        let allocator = RetiringBlockAllocator::new(mock.clone());
+        let retired = allocator.retired_blocks();
+        assert!(retired.take().is_none());
+        unsafe {
+            let address_space = allocator.create_address_space();
+            let (_, a, _) = allocator.allocate_block(&address_space).unwrap();
+            let (_, b, _) = allocator.allocate_block(&address_space).unwrap();
+            allocator.deallocate_block(&address_space, a);
+            assert_eq!(mock.num_live_blocks(), 2);
+
+            let free = retired.take().unwrap();
+            allocator.deallocate_block(&address_space, b);
+            allocator.destroy_address_space(address_space);
+            free();
+            assert_eq!(mock.num_live_blocks(), 1);
+            retired.take().unwrap()();
+            assert_eq!(mock.num_live_blocks(), 0);
+        }
+        assert_eq!(
+            mock.events(),
+            vec![
+                MockEvent::AllocateBlock(0),
+                MockEvent::AllocateBlock(1),
+                MockEvent::DeallocateBlock(0),
+                MockEvent::DeallocateBlock(1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dropped_dag_is_retired() {
+        let mock = Arc::new(MockBlockAllocator::new(BLOCK_SIZE as usize));
+        let allocator = Arc::new(RetiringBlockAllocator::new(mock.clone()));
+        let retired = allocator.retired_blocks();
+        let mut dag = Svdag::new(allocator, 1);
+        dag.get_grid_accessor_mut(4, 0).set(1, 2, 3, true).unwrap();
+        assert!(mock.num_live_blocks() > 0);
+
+        // Replacing a model drops its DAG, but the device may still read its blocks.
+        drop(dag);
+        assert!(mock.num_live_blocks() > 0);
+        retired.take().unwrap()();
+        assert_eq!(mock.num_live_blocks(), 0);
+    }
+}
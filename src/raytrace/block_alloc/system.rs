@@ -1,18 +1,26 @@
-use super::{AllocError, BlockAllocation, BlockAllocator};
+use super::{AllocError, BlockAllocation, BlockAllocator, ResidencyTicket};
 use std::alloc::{Allocator, Global, Layout};
 use std::ops::Range;
 use std::ptr::NonNull;
 
+/// A block allocator backed by host memory only.
+/// Useful for building voxel models without a Vulkan device, for example in command line tools
+/// and asset pipelines. The models may later be uploaded into a device-backed allocator.
 pub struct SystemBlockAllocator<A: Allocator = Global> {
     allocator: A,
-    block_size: usize,
+    block_layout: Layout,
 }
 
+// Blocks are page aligned like device memory mappings, which satisfies the alignment of
+// any slot type stored in them.
+const BLOCK_ALIGNMENT: usize = 4096;
+
 impl SystemBlockAllocator {
     pub fn new(block_size: usize) -> SystemBlockAllocator<Global> {
         SystemBlockAllocator {
             allocator: Global,
-            block_size,
+            block_layout: Layout::from_size_align(block_size, BLOCK_ALIGNMENT)
+                .expect("Invalid block size"),
         }
     }
 }
@@ -25,13 +33,17 @@ impl BlockAllocator for SystemBlockAllocator {
     unsafe fn allocate_block(
         &self,
         _address_space: &super::BlockAllocatorAddressSpace,
-    ) -> Result<(*mut u8, BlockAllocation), AllocError> {
+    ) -> Result<(*mut u8, BlockAllocation, ResidencyTicket), AllocError> {
         let mem = self
             .allocator
-            .allocate(Layout::from_size_align_unchecked(self.block_size, 1))
+            .allocate(self.block_layout)
             .map_err(|_| AllocError::OutOfHostMemory)?;
         let ptr = mem.as_mut_ptr();
-        Ok((mem.as_mut_ptr(), BlockAllocation(ptr as u64)))
+        Ok((
+            mem.as_mut_ptr(),
+            BlockAllocation(ptr as u64),
+            ResidencyTicket::RESIDENT,
+        ))
     }
 
     unsafe fn deallocate_block(
@@ -39,11 +51,8 @@ impl BlockAllocator for SystemBlockAllocator {
         _address_space: &super::BlockAllocatorAddressSpace,
         block: BlockAllocation,
     ) {
-        let _layout = Layout::new::<u8>().repeat(self.block_size).unwrap();
-        self.allocator.deallocate(
-            NonNull::new(block.0 as *mut u8).unwrap(),
-            Layout::from_size_align_unchecked(self.block_size, 1),
-        );
+        self.allocator
+            .deallocate(NonNull::new(block.0 as *mut u8).unwrap(), self.block_layout);
         std::mem::forget(block);
     }
 
@@ -56,25 +65,29 @@ impl BlockAllocator for SystemBlockAllocator {
                 Range<u32>,
             ),
         >,
-    ) {
+    ) -> Result<(), AllocError> {
+        Ok(())
     }
 
-    fn can_flush(&self) -> bool {
-        true
+    fn can_flush(&self) -> Result<bool, AllocError> {
+        Ok(true)
+    }
+    fn has_device_buffer(&self) -> bool {
+        false
     }
     fn get_blocksize(&self) -> u64 {
-        self.block_size as u64
+        self.block_layout.size() as u64
     }
     fn get_buffer(&self, _address_space: &super::BlockAllocatorAddressSpace) -> ash::vk::Buffer {
-        unimplemented!()
+        panic!("SystemBlockAllocator does not have a device buffer, check has_device_buffer first")
     }
     fn get_device_buffer_size(&self) -> u64 {
-        unimplemented!()
+        panic!("SystemBlockAllocator does not have a device buffer, check has_device_buffer first")
     }
     fn get_buffer_device_address(
         &self,
         _address_space: &super::BlockAllocatorAddressSpace,
     ) -> ash::vk::DeviceAddress {
-        unimplemented!()
+        panic!("SystemBlockAllocator does not have a device buffer, check has_device_buffer first")
     }
 }
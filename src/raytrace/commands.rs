@@ -1,8 +1,17 @@
+use super::uniform::PreparedViews;
 use crate::raytrace::RayShaders;
 use ash::vk;
 use bevy::ecs::prelude::*;
 
-use crate::render::RenderState;
+use crate::render::{Frame, RenderState, SwapchainImage};
+
+const COLOR_SUBRESOURCE_RANGE: vk::ImageSubresourceRange = vk::ImageSubresourceRange {
+    aspect_mask: vk::ImageAspectFlags::COLOR,
+    base_mip_level: 0,
+    level_count: 1,
+    base_array_layer: 0,
+    layer_count: 1,
+};
 
 pub(super) fn record_raytracing_commands_system(
     device: Res<ash::Device>,
@@ -12,112 +21,257 @@ pub(super) fn record_raytracing_commands_system(
     raytracing_pipeline_loader: Res<ash::extensions::khr::RayTracingPipeline>,
     queues: Res<crate::Queues>,
     tlas_state: Res<super::TlasState>,
+    prepared_views: Res<PreparedViews>,
 ) {
+    let render_state = &mut *render_state;
     let current_frame = render_state.current_frame().clone();
-    if !current_frame.command_buffer_needs_update {
-        return;
+    if current_frame.command_buffer_needs_update {
+        render_state.current_frame_mut().command_buffer_needs_update = false;
+        unsafe {
+            record_upload_commands(
+                &device,
+                &current_frame,
+                render_state.device_uniform_buffer,
+                &entity_mapping_table,
+                prepared_views.0.len() as u64 * super::uniform::VIEW_CONSTANTS_STRIDE,
+            );
+        }
     }
-    render_state.current_frame_mut().command_buffer_needs_update = false;
-    assert_eq!(
-        render_state.windows.len(),
-        1,
-        "TODO: Only supports 1 window at the moment."
-    );
 
-    let extracted_window = render_state.windows.values().next();
-    if extracted_window.is_none() {
-        println!("Cannot find the window!");
-        return;
-    }
-    let extracted_window = extracted_window.unwrap();
-    let surface_state = extracted_window.state.as_ref();
-    if surface_state.is_none() {
-        println!("Record commands: Cannot find the surface state!");
-        return;
-    }
-    let surface_state = surface_state.unwrap();
-    let command_buffer = current_frame.command_buffer;
-    let swapchain_image = extracted_window
-        .swapchain_image
-        .as_ref()
-        .expect("Record commands: Cannot find the swapchain image");
+    for extracted_window in render_state.windows.values_mut() {
+        let surface_state = match extracted_window.state.as_mut() {
+            Some(surface_state) => surface_state,
+            None => {
+                println!("Record commands: Cannot find the surface state!");
+                continue;
+            }
+        };
+        if !surface_state.command_buffer_needs_update[current_frame.index as usize] {
+            continue;
+        }
+        surface_state.command_buffer_needs_update[current_frame.index as usize] = false;
+        let command_buffer = surface_state.command_buffers[current_frame.index as usize];
+        let views: Vec<_> = prepared_views
+            .0
+            .iter()
+            .filter(|view| view.window == extracted_window.id)
+            .collect();
+        let swapchain_image = extracted_window
+            .swapchain_image
+            .as_ref()
+            .expect("Record commands: Cannot find the swapchain image");
+
+        if entity_mapping_table.is_empty() || views.is_empty() {
+            unsafe {
+                device
+                    .reset_command_buffer(command_buffer, vk::CommandBufferResetFlags::empty())
+                    .unwrap();
+                record_empty_command_buffer(command_buffer, &device, &queues, swapchain_image)
+            }
+            continue;
+        }
+
+        unsafe {
+            let mut write_desc_set_as_ext =
+                vk::WriteDescriptorSetAccelerationStructureKHR::default();
+            write_desc_set_as_ext.acceleration_structure_count = 1;
+            write_desc_set_as_ext.p_acceleration_structures = &tlas_state.tlas;
+            let mut write_desc_set_as = vk::WriteDescriptorSet::builder()
+                .dst_set(swapchain_image.desc_set)
+                .dst_binding(2)
+                .descriptor_type(vk::DescriptorType::ACCELERATION_STRUCTURE_KHR)
+                .build();
+            write_desc_set_as.p_next =
+                &write_desc_set_as_ext as *const _ as *const std::ffi::c_void;
+            write_desc_set_as.descriptor_count = 1;
+            device.update_descriptor_sets(
+                &[
+                    write_desc_set_as,
+                    vk::WriteDescriptorSet::builder()
+                        .dst_set(swapchain_image.desc_set)
+                        .dst_binding(3)
+                        .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                        .buffer_info(&[vk::DescriptorBufferInfo {
+                            buffer: entity_mapping_table.get_buffer(),
+                            range: vk::WHOLE_SIZE,
+                            offset: 0,
+                        }])
+                        .build(),
+                    vk::WriteDescriptorSet::builder()
+                        .dst_set(swapchain_image.desc_set)
+                        .dst_binding(1)
+                        .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC)
+                        .buffer_info(&[vk::DescriptorBufferInfo {
+                            buffer: render_state.device_uniform_buffer,
+                            range: std::mem::size_of::<super::RaytracingNodeViewConstants>() as u64,
+                            offset: 0,
+                        }])
+                        .build(),
+                ],
+                &[],
+            );
+        }
 
-    if entity_mapping_table.is_empty() {
         unsafe {
             device
                 .reset_command_buffer(command_buffer, vk::CommandBufferResetFlags::empty())
                 .unwrap();
-            record_empty_command_buffer(command_buffer, &device, &queues, swapchain_image.image)
+            device
+                .begin_command_buffer(
+                    command_buffer,
+                    &vk::CommandBufferBeginInfo::builder()
+                        .flags(vk::CommandBufferUsageFlags::empty())
+                        .build(),
+                )
+                .unwrap();
+            device.cmd_bind_pipeline(
+                command_buffer,
+                vk::PipelineBindPoint::RAY_TRACING_KHR,
+                ray_shaders.pipeline,
+            );
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
+                vk::DependencyFlags::BY_REGION,
+                &[],
+                &[],
+                &[
+                    vk::ImageMemoryBarrier::builder()
+                        .src_access_mask(vk::AccessFlags::NONE_KHR)
+                        .dst_access_mask(vk::AccessFlags::SHADER_READ)
+                        .old_layout(vk::ImageLayout::UNDEFINED)
+                        .new_layout(vk::ImageLayout::GENERAL)
+                        .src_queue_family_index(queues.graphics_queue_family)
+                        .dst_queue_family_index(queues.graphics_queue_family)
+                        .image(swapchain_image.image)
+                        .subresource_range(COLOR_SUBRESOURCE_RANGE)
+                        .build(),
+                    vk::ImageMemoryBarrier::builder()
+                        .src_access_mask(vk::AccessFlags::NONE_KHR)
+                        .dst_access_mask(vk::AccessFlags::SHADER_WRITE)
+                        .old_layout(vk::ImageLayout::UNDEFINED)
+                        .new_layout(vk::ImageLayout::GENERAL)
+                        .src_queue_family_index(queues.graphics_queue_family)
+                        .dst_queue_family_index(queues.graphics_queue_family)
+                        .image(swapchain_image.depth_image)
+                        .subresource_range(COLOR_SUBRESOURCE_RANGE)
+                        .build(),
+                ],
+            );
+            for (i, view) in views.iter().enumerate() {
+                if i > 0 {
+                    // Viewports drawn later may overlap the ones before them.
+                    device.cmd_pipeline_barrier(
+                        command_buffer,
+                        vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
+                        vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
+                        vk::DependencyFlags::BY_REGION,
+                        &[vk::MemoryBarrier::builder()
+                            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+                            .dst_access_mask(vk::AccessFlags::SHADER_WRITE)
+                            .build()],
+                        &[],
+                        &[],
+                    );
+                }
+                device.cmd_bind_descriptor_sets(
+                    command_buffer,
+                    vk::PipelineBindPoint::RAY_TRACING_KHR,
+                    ray_shaders.pipeline_layout,
+                    0,
+                    &[swapchain_image.desc_set],
+                    &[view.uniform_offset],
+                );
+                // The swapchain may lag behind the size of the window while it's being resized.
+                let width =
+                    view.extent[0].min(surface_state.extent.width.saturating_sub(view.offset[0]));
+                let height =
+                    view.extent[1].min(surface_state.extent.height.saturating_sub(view.offset[1]));
+                raytracing_pipeline_loader.cmd_trace_rays(
+                    command_buffer,
+                    &ray_shaders.sbt.raygen_shader_binding_tables,
+                    &ray_shaders.sbt.miss_shader_binding_tables,
+                    &ray_shaders.sbt.hit_shader_binding_tables,
+                    &ray_shaders.sbt.callable_shader_binding_tables,
+                    width,
+                    height,
+                    1,
+                );
+            }
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                vk::DependencyFlags::BY_REGION,
+                &[],
+                &[],
+                &[vk::ImageMemoryBarrier::builder()
+                    .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+                    .dst_access_mask(vk::AccessFlags::NONE_KHR)
+                    .old_layout(vk::ImageLayout::GENERAL)
+                    .new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+                    .src_queue_family_index(queues.graphics_queue_family)
+                    .dst_queue_family_index(queues.graphics_queue_family)
+                    .image(swapchain_image.image)
+                    .subresource_range(COLOR_SUBRESOURCE_RANGE)
+                    .build()],
+            );
+            // Passes after this one sample the depth.
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
+                vk::PipelineStageFlags::ALL_COMMANDS,
+                vk::DependencyFlags::BY_REGION,
+                &[],
+                &[],
+                &[vk::ImageMemoryBarrier::builder()
+                    .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+                    .dst_access_mask(vk::AccessFlags::SHADER_READ)
+                    .old_layout(vk::ImageLayout::GENERAL)
+                    .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                    .src_queue_family_index(queues.graphics_queue_family)
+                    .dst_queue_family_index(queues.graphics_queue_family)
+                    .image(swapchain_image.depth_image)
+                    .subresource_range(COLOR_SUBRESOURCE_RANGE)
+                    .build()],
+            );
+            device.end_command_buffer(command_buffer).unwrap();
         }
-
-        return;
-    }
-
-    unsafe {
-        let mut write_desc_set_as_ext = vk::WriteDescriptorSetAccelerationStructureKHR::default();
-        write_desc_set_as_ext.acceleration_structure_count = 1;
-        write_desc_set_as_ext.p_acceleration_structures = &tlas_state.tlas;
-        let mut write_desc_set_as = vk::WriteDescriptorSet::builder()
-            .dst_set(swapchain_image.desc_set)
-            .dst_binding(2)
-            .descriptor_type(vk::DescriptorType::ACCELERATION_STRUCTURE_KHR)
-            .build();
-        write_desc_set_as.p_next = &write_desc_set_as_ext as *const _ as *const std::ffi::c_void;
-        write_desc_set_as.descriptor_count = 1;
-        device.update_descriptor_sets(
-            &[
-                write_desc_set_as,
-                vk::WriteDescriptorSet::builder()
-                    .dst_set(swapchain_image.desc_set)
-                    .dst_binding(3)
-                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
-                    .buffer_info(&[vk::DescriptorBufferInfo {
-                        buffer: entity_mapping_table.get_buffer(),
-                        range: vk::WHOLE_SIZE,
-                        offset: 0,
-                    }])
-                    .build(),
-                vk::WriteDescriptorSet::builder()
-                    .dst_set(swapchain_image.desc_set)
-                    .dst_binding(1)
-                    .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
-                    .buffer_info(&[vk::DescriptorBufferInfo {
-                        buffer: render_state.device_uniform_buffer,
-                        range: vk::WHOLE_SIZE,
-                        offset: 0,
-                    }])
-                    .build(),
-            ],
-            &[],
-        );
     }
+}
 
-    unsafe {
-        device
-            .reset_command_buffer(command_buffer, vk::CommandBufferResetFlags::empty())
-            .unwrap();
-        device
-            .begin_command_buffer(
-                command_buffer,
-                &vk::CommandBufferBeginInfo::builder()
-                    .flags(vk::CommandBufferUsageFlags::empty())
-                    .build(),
-            )
-            .unwrap();
-        device.cmd_bind_pipeline(
-            command_buffer,
-            vk::PipelineBindPoint::RAY_TRACING_KHR,
-            ray_shaders.pipeline,
-        );
-        device.cmd_bind_descriptor_sets(
+// The uploads shared by all windows. The render system submits them before the windows.
+unsafe fn record_upload_commands(
+    device: &ash::Device,
+    frame: &Frame,
+    device_uniform_buffer: vk::Buffer,
+    entity_mapping_table: &super::tlas::UniformArray,
+    uniform_size: u64,
+) {
+    let command_buffer = frame.command_buffer;
+    device
+        .reset_command_buffer(command_buffer, vk::CommandBufferResetFlags::empty())
+        .unwrap();
+    device
+        .begin_command_buffer(
             command_buffer,
-            vk::PipelineBindPoint::RAY_TRACING_KHR,
-            ray_shaders.pipeline_layout,
-            0,
-            &[swapchain_image.desc_set],
-            &[],
-        );
-
+            &vk::CommandBufferBeginInfo::builder()
+                .flags(vk::CommandBufferUsageFlags::empty())
+                .build(),
+        )
+        .unwrap();
+    // The previous frame may still be tracing rays with the buffers.
+    device.cmd_pipeline_barrier(
+        command_buffer,
+        vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
+        vk::PipelineStageFlags::TRANSFER,
+        vk::DependencyFlags::empty(),
+        &[],
+        &[],
+        &[],
+    );
+    if !entity_mapping_table.is_empty() {
         // Sync entity mapping table
         device.cmd_copy_buffer(
             command_buffer,
@@ -129,84 +283,40 @@ pub(super) fn record_raytracing_commands_system(
                 size: entity_mapping_table.get_full_size(),
             }],
         );
+    }
+    if uniform_size > 0 {
         device.cmd_copy_buffer(
             command_buffer,
-            current_frame.uniform_buffer,
-            render_state.device_uniform_buffer,
+            frame.uniform_buffer,
+            device_uniform_buffer,
             &[vk::BufferCopy {
                 src_offset: 0,
                 dst_offset: 0,
-                size: std::mem::size_of::<super::RaytracingNodeViewConstants>() as u64,
+                size: uniform_size,
             }],
         );
-
-        device.cmd_pipeline_barrier(
-            command_buffer,
-            vk::PipelineStageFlags::TOP_OF_PIPE,
-            vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
-            vk::DependencyFlags::BY_REGION,
-            &[],
-            &[],
-            &[vk::ImageMemoryBarrier::builder()
-                .src_access_mask(vk::AccessFlags::NONE_KHR)
-                .dst_access_mask(vk::AccessFlags::SHADER_READ)
-                .old_layout(vk::ImageLayout::UNDEFINED)
-                .new_layout(vk::ImageLayout::GENERAL)
-                .src_queue_family_index(queues.graphics_queue_family)
-                .dst_queue_family_index(queues.graphics_queue_family)
-                .image(swapchain_image.image)
-                .subresource_range(vk::ImageSubresourceRange {
-                    aspect_mask: vk::ImageAspectFlags::COLOR,
-                    base_mip_level: 0,
-                    level_count: 1,
-                    base_array_layer: 0,
-                    layer_count: 1,
-                })
-                .build()],
-        );
-        raytracing_pipeline_loader.cmd_trace_rays(
-            command_buffer,
-            &ray_shaders.sbt.raygen_shader_binding_tables,
-            &ray_shaders.sbt.miss_shader_binding_tables,
-            &ray_shaders.sbt.hit_shader_binding_tables,
-            &ray_shaders.sbt.callable_shader_binding_tables,
-            surface_state.extent.width,
-            surface_state.extent.height,
-            1,
-        );
-        device.cmd_pipeline_barrier(
-            command_buffer,
-            vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
-            vk::PipelineStageFlags::BOTTOM_OF_PIPE,
-            vk::DependencyFlags::BY_REGION,
-            &[],
-            &[],
-            &[vk::ImageMemoryBarrier::builder()
-                .src_access_mask(vk::AccessFlags::SHADER_WRITE)
-                .dst_access_mask(vk::AccessFlags::NONE_KHR)
-                .old_layout(vk::ImageLayout::GENERAL)
-                .new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
-                .src_queue_family_index(queues.graphics_queue_family)
-                .dst_queue_family_index(queues.graphics_queue_family)
-                .image(swapchain_image.image)
-                .subresource_range(vk::ImageSubresourceRange {
-                    aspect_mask: vk::ImageAspectFlags::COLOR,
-                    base_mip_level: 0,
-                    level_count: 1,
-                    base_array_layer: 0,
-                    layer_count: 1,
-                })
-                .build()],
-        );
-        device.end_command_buffer(command_buffer).unwrap();
     }
+    // The windows are later in the same submission.
+    device.cmd_pipeline_barrier(
+        command_buffer,
+        vk::PipelineStageFlags::TRANSFER,
+        vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
+        vk::DependencyFlags::empty(),
+        &[vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::UNIFORM_READ)
+            .build()],
+        &[],
+        &[],
+    );
+    device.end_command_buffer(command_buffer).unwrap();
 }
 
 unsafe fn record_empty_command_buffer(
     command_buffer: vk::CommandBuffer,
     device: &ash::Device,
     queues: &crate::Queues,
-    image: vk::Image,
+    swapchain_image: &SwapchainImage,
 ) {
     device
         .begin_command_buffer(
@@ -223,22 +333,29 @@ unsafe fn record_empty_command_buffer(
         vk::DependencyFlags::BY_REGION,
         &[],
         &[],
-        &[vk::ImageMemoryBarrier::builder()
-            .src_access_mask(vk::AccessFlags::NONE_KHR)
-            .dst_access_mask(vk::AccessFlags::NONE_KHR)
-            .old_layout(vk::ImageLayout::UNDEFINED)
-            .new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
-            .src_queue_family_index(queues.graphics_queue_family)
-            .dst_queue_family_index(queues.graphics_queue_family)
-            .image(image)
-            .subresource_range(vk::ImageSubresourceRange {
-                aspect_mask: vk::ImageAspectFlags::COLOR,
-                base_mip_level: 0,
-                level_count: 1,
-                base_array_layer: 0,
-                layer_count: 1,
-            })
-            .build()],
+        &[
+            vk::ImageMemoryBarrier::builder()
+                .src_access_mask(vk::AccessFlags::NONE_KHR)
+                .dst_access_mask(vk::AccessFlags::NONE_KHR)
+                .old_layout(vk::ImageLayout::UNDEFINED)
+                .new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+                .src_queue_family_index(queues.graphics_queue_family)
+                .dst_queue_family_index(queues.graphics_queue_family)
+                .image(swapchain_image.image)
+                .subresource_range(COLOR_SUBRESOURCE_RANGE)
+                .build(),
+            // Same layout as after tracing rays, with undefined content.
+            vk::ImageMemoryBarrier::builder()
+                .src_access_mask(vk::AccessFlags::NONE_KHR)
+                .dst_access_mask(vk::AccessFlags::NONE_KHR)
+                .old_layout(vk::ImageLayout::UNDEFINED)
+                .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                .src_queue_family_index(queues.graphics_queue_family)
+                .dst_queue_family_index(queues.graphics_queue_family)
+                .image(swapchain_image.depth_image)
+                .subresource_range(COLOR_SUBRESOURCE_RANGE)
+                .build(),
+        ],
     );
     device.end_command_buffer(command_buffer).unwrap();
 }
@@ -0,0 +1,56 @@
+/// The visibility layers of a `Raytraced` instance, as a bit mask over 8 layers.
+/// It becomes the instance mask of the instance in the TLAS.
+/// Instances without this component are on every layer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VisibilityLayers(pub u8);
+
+impl VisibilityLayers {
+    pub const ALL: VisibilityLayers = VisibilityLayers(0xFF);
+    pub const NONE: VisibilityLayers = VisibilityLayers(0);
+
+    pub fn layer(layer: u8) -> Self {
+        assert!(layer < 8, "There are only 8 visibility layers");
+        VisibilityLayers(1 << layer)
+    }
+    pub fn with(self, layer: u8) -> Self {
+        VisibilityLayers(self.0 | Self::layer(layer).0)
+    }
+    pub fn without(self, layer: u8) -> Self {
+        VisibilityLayers(self.0 & !Self::layer(layer).0)
+    }
+    pub fn intersects(&self, other: &VisibilityLayers) -> bool {
+        self.0 & other.0 != 0
+    }
+}
+
+impl Default for VisibilityLayers {
+    fn default() -> Self {
+        VisibilityLayers::ALL
+    }
+}
+
+/// The layers a camera sees. Put it next to the camera component.
+/// Cameras without this component see every layer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CameraLayers {
+    /// Primary rays only hit instances on these layers.
+    pub view: VisibilityLayers,
+    /// Shadows are only cast by instances on these layers.
+    pub shadow: VisibilityLayers,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VisibilityLayers;
+
+    #[test]
+    fn test_layers() {
+        let gizmos = VisibilityLayers::layer(7);
+        let world = VisibilityLayers::layer(0).with(1);
+        assert_eq!(world, VisibilityLayers(0b11));
+        assert!(!world.intersects(&gizmos));
+        assert!(VisibilityLayers::ALL.intersects(&gizmos));
+        assert_eq!(VisibilityLayers::ALL.without(7), VisibilityLayers(0x7F));
+        assert!(!VisibilityLayers::NONE.intersects(&VisibilityLayers::ALL));
+    }
+}
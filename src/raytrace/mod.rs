@@ -1,6 +1,8 @@
 mod arena_alloc;
 mod block_alloc;
 mod commands;
+mod layers;
+mod pick;
 mod ray_shaders;
 mod sbt;
 mod svdag;
@@ -10,14 +12,23 @@ mod vox;
 
 use ash::vk;
 
-pub use tlas::Raytraced;
-pub use vox::VoxelModel;
+pub use arena_alloc::ArenaLayout;
+pub use block_alloc::{AllocError, BlockAllocator, MemoryBudget, SystemBlockAllocator};
+pub use layers::{CameraLayers, VisibilityLayers};
+pub use pick::{PickHit, PickResult};
+pub use svdag::{Svdag, SvdagFileError, SvdagSnapshot};
+pub use tlas::{InstanceCulling, ModelBlasSettings, Raytraced};
+pub use vox::{
+    AnimationFrame, CsgOperation, VoxLoader, VoxPlugin, VoxelAnimationPlayer, VoxelEdit,
+    VoxelEditOp, VoxelModel, VoxelModelEvicted, VoxelResidency,
+};
 
-use crate::render::{RenderApp, RenderStage};
+use crate::render::{Garbage, GarbageBin, RenderApp, RenderStage};
 use bevy::prelude::*;
 
 use self::block_alloc::{
-    AllocatorCreateInfo, BlockAllocator, DiscreteBlockAllocator, IntegratedBlockAllocator,
+    AllocatorCreateInfo, BudgetedBlockAllocator, DiscreteBlockAllocator, IntegratedBlockAllocator,
+    RetiredBlocks, RetiringBlockAllocator,
 };
 pub use self::ray_shaders::RayShaders;
 use self::tlas::TlasState;
@@ -32,6 +43,12 @@ pub struct RaytracePlugin;
 
 impl RaytracePlugin {
     fn add_block_allocator(&self, app: &mut App) {
+        // Apps may insert their own budget before adding the plugin.
+        let budget = app
+            .world
+            .get_resource::<Arc<MemoryBudget>>()
+            .cloned()
+            .unwrap_or_else(|| Arc::new(MemoryBudget::unlimited()));
         let render_app = app.sub_app(RenderApp);
         let device_info = render_app.world.get_resource::<DeviceInfo>().unwrap();
         let device = render_app
@@ -50,35 +67,42 @@ impl RaytracePlugin {
                 .limits
                 .max_storage_buffer_range as u64,
         };
-        let block_allocator: Arc<dyn BlockAllocator> =
+        let device_allocator: Arc<dyn BlockAllocator> =
             match device_info.physical_device_properties.device_type {
                 vk::PhysicalDeviceType::DISCRETE_GPU => unsafe {
-                    let allocator = DiscreteBlockAllocator::new(
+                    Arc::new(DiscreteBlockAllocator::new(
                         device,
                         &device_info.memory_properties,
                         &create_info,
-                    );
-                    Arc::new(allocator)
+                    ))
                 },
                 vk::PhysicalDeviceType::INTEGRATED_GPU => unsafe {
-                    let allocator = IntegratedBlockAllocator::new(
+                    Arc::new(IntegratedBlockAllocator::new(
                         device,
                         &device_info.memory_properties,
                         &create_info,
-                    );
-                    Arc::new(allocator)
+                    ))
                 },
                 _ => panic!("Unsupported GPU"),
             };
+        // Frames in flight may still read the blocks of edited, reloaded or evicted models.
+        // They go through the garbage bin, but stop counting against the budget right away.
+        let retiring_allocator = RetiringBlockAllocator::new(device_allocator);
+        render_app.insert_resource(retiring_allocator.retired_blocks());
+        let block_allocator: Arc<dyn BlockAllocator> = Arc::new(BudgetedBlockAllocator::new(
+            retiring_allocator,
+            budget.clone(),
+        ));
         render_app.insert_resource(block_allocator.clone());
         app.insert_resource(block_allocator);
+        app.insert_resource(budget);
     }
 }
 
 impl Plugin for RaytracePlugin {
     fn build(&self, app: &mut App) {
         app.add_plugin(crate::render::RenderPlugin {
-            uniform_size: std::mem::size_of::<RaytracingNodeViewConstants>() as u64,
+            uniform_size: uniform::VIEW_CONSTANTS_STRIDE * uniform::MAX_CAMERAS as u64,
         });
 
         let render_app = app.sub_app(RenderApp);
@@ -86,11 +110,24 @@ impl Plugin for RaytracePlugin {
         //render_app.add_system_to_stage(RenderStage::Prepare, update_desc_sets);
 
         self.add_block_allocator(app);
-        app.add_plugin(vox::VoxPlugin::default());
+        app.add_plugin(vox::VoxPlugin::default())
+            .init_resource::<PickResult>()
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                tlas::update_raytraced_extents.label(tlas::RaytracedExtentsSystem),
+            )
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                pick::update_pick_result
+                    .after(bevy::transform::TransformSystem::TransformPropagate)
+                    .after(tlas::RaytracedExtentsSystem),
+            );
 
         app.sub_app(RenderApp)
+            .init_resource::<uniform::PreparedViews>()
             .add_system_to_stage(RenderStage::Extract, uniform::extract_uniform_data)
             .add_system_to_stage(RenderStage::Prepare, uniform::prepare_uniform_data)
+            .add_system_to_stage(RenderStage::Prepare, collect_retired_blocks)
             .init_resource::<ray_shaders::RayShaders>()
             .add_system_to_stage(
                 RenderStage::Queue,
@@ -98,3 +135,9 @@ impl Plugin for RaytracePlugin {
             );
     }
 }
+
+fn collect_retired_blocks(retired: Res<Arc<RetiredBlocks>>, mut garbage_bin: ResMut<GarbageBin>) {
+    if let Some(free) = retired.take() {
+        garbage_bin.collect(Garbage::Closure(Box::new(free)));
+    }
+}
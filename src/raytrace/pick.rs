@@ -0,0 +1,205 @@
+use super::{AnimationFrame, CameraLayers, Raytraced, VisibilityLayers};
+use crate::camera::Projection;
+use crate::{OrthographicCamera, PerspectiveCamera, Viewport, VoxelModel};
+use bevy::math::{Mat4, UVec3, Vec2, Vec3};
+use bevy::prelude::*;
+use bevy::window::Windows;
+
+/// The voxel under the cursor of the primary window,
+/// as seen by the camera of the topmost viewport under the cursor.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PickHit {
+    pub entity: Entity,
+    /// Coordinate of the voxel in the grid of the model.
+    pub voxel: UVec3,
+    /// World space normal of the face the cursor is over.
+    pub normal: Vec3,
+    /// World space distance from the camera, or from the plane of an orthographic camera.
+    pub distance: f32,
+}
+
+/// Updated every frame in `CoreStage::PostUpdate`.
+/// `hit` is None when the cursor is outside of the window or not over any voxel.
+#[derive(Clone, Debug, Default)]
+pub struct PickResult {
+    pub hit: Option<PickHit>,
+}
+
+// World space ray through the cursor, matching the ray generation shader.
+// The cursor position is in logical pixels from the bottom left corner of the viewport.
+fn camera_ray(
+    projection: &Projection,
+    transform: &GlobalTransform,
+    cursor: Vec2,
+    viewport_size: Vec2,
+) -> (Vec3, Vec3) {
+    let ndc = cursor / viewport_size * 2.0 - Vec2::ONE;
+    let (origin, dir) = projection.camera_ray(ndc, viewport_size.x / viewport_size.y);
+    (
+        transform.translation + transform.rotation * origin,
+        (transform.rotation * dir).normalize(),
+    )
+}
+
+pub(super) fn update_pick_result(
+    windows: Res<Windows>,
+    voxel_models: Res<Assets<VoxelModel>>,
+    mut pick_result: ResMut<PickResult>,
+    cameras: Query<(
+        Entity,
+        Option<&PerspectiveCamera>,
+        Option<&OrthographicCamera>,
+        &GlobalTransform,
+        Option<&CameraLayers>,
+        Option<&Viewport>,
+    )>,
+    instances: Query<(
+        Entity,
+        &GlobalTransform,
+        &Raytraced,
+        &Handle<VoxelModel>,
+        Option<&VisibilityLayers>,
+        Option<&AnimationFrame>,
+    )>,
+) {
+    pick_result.hit = None;
+    let window = match windows.get_primary() {
+        Some(window) => window,
+        None => return,
+    };
+    let cursor = match window.cursor_position() {
+        Some(cursor) => cursor,
+        None => return,
+    };
+    let window_size = Vec2::new(window.width(), window.height());
+    // Viewports are placed from the top left corner of the window.
+    let point = Vec2::new(cursor.x / window_size.x, 1.0 - cursor.y / window_size.y);
+    // Drawn last, so on top. Same order as the extracted views.
+    let (_, projection, camera_transform, camera_layers, viewport) = match cameras
+        .iter()
+        .filter_map(
+            |(entity, perspective, orthographic, transform, layers, viewport)| {
+                let projection = Projection::from_components(perspective, orthographic)?;
+                let viewport = viewport.cloned().unwrap_or_default();
+                (viewport.window == window.id() && viewport.contains(point))
+                    .then(|| (entity, projection, transform, layers, viewport))
+            },
+        )
+        .max_by_key(|(entity, _, _, _, viewport)| (viewport.order, *entity))
+    {
+        Some(camera) => camera,
+        None => return,
+    };
+    let viewport_size = viewport.size * window_size;
+    let viewport_min = Vec2::new(
+        viewport.position.x,
+        1.0 - viewport.position.y - viewport.size.y,
+    ) * window_size;
+    let (origin, dir) = camera_ray(
+        &projection,
+        camera_transform,
+        cursor - viewport_min,
+        viewport_size,
+    );
+    // Clip to the near and far planes like the ray generation shader,
+    // by starting the ray on the near plane.
+    let forward_cos = dir.dot(camera_transform.rotation * -Vec3::Z);
+    let near = projection.near() / forward_cos;
+    let far = projection.far() / forward_cos;
+    let origin = origin + dir * near;
+
+    let view_layers = camera_layers.cloned().unwrap_or_default().view;
+
+    for (entity, transform, aabb, model_handle, layers, frame) in instances.iter() {
+        // Only pick what the camera can see.
+        if !view_layers.intersects(&layers.cloned().unwrap_or_default()) {
+            continue;
+        }
+        let model = match voxel_models.get(model_handle) {
+            Some(model) => model,
+            None => continue,
+        };
+        // Same transform as the TLAS instance: the unit box stretched over the model.
+        let instance = Mat4::from_scale_rotation_translation(
+            transform.scale * aabb.aabb_extent,
+            transform.rotation,
+            transform.translation,
+        );
+        // A zero scale collapses the model, so there is nothing to hit and no inverse.
+        if !instance.determinant().is_normal() {
+            continue;
+        }
+        let world_to_unit = instance.inverse();
+        let gridsize = (1u32 << model.size) as f32;
+        let local_origin = world_to_unit.transform_point3(origin) * gridsize;
+        let local_dir = world_to_unit.transform_vector3(dir) * gridsize;
+
+        // The ray parameter is shared between the two spaces, and dir is normalized.
+        let (hit, distance) = match model
+            .svdag
+            .get_grid_accessor(model.size, AnimationFrame::clamp(frame, model) as usize)
+            .raycast(local_origin.into(), local_dir.into())
+        {
+            Some(hit) if near + hit.t <= far => (hit, near + hit.t),
+            _ => continue,
+        };
+        if matches!(pick_result.hit, Some(closest) if closest.distance <= distance) {
+            continue;
+        }
+        let normal = Vec3::new(
+            hit.normal[0] as f32,
+            hit.normal[1] as f32,
+            hit.normal[2] as f32,
+        );
+        pick_result.hit = Some(PickHit {
+            entity,
+            voxel: UVec3::from(hit.voxel),
+            normal: world_to_unit
+                .transpose()
+                .transform_vector3(normal)
+                .normalize_or_zero(),
+            distance,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::camera_ray;
+    use crate::camera::Projection;
+    use crate::{OrthographicCamera, PerspectiveCamera};
+    use bevy::math::{Vec2, Vec3};
+    use bevy::prelude::{GlobalTransform, Transform};
+
+    #[test]
+    fn test_camera_ray() {
+        let camera = PerspectiveCamera {
+            fov: std::f32::consts::PI / 2.0,
+            ..Default::default()
+        };
+        let camera = Projection::from_components(Some(&camera), None).unwrap();
+        let transform = GlobalTransform::from(
+            Transform::from_xyz(1.0, 2.0, 3.0).looking_at(Vec3::new(1.0, 2.0, 10.0), Vec3::Y),
+        );
+        let window_size = Vec2::new(200.0, 100.0);
+
+        let (origin, dir) = camera_ray(&camera, &transform, Vec2::new(100.0, 50.0), window_size);
+        assert_eq!(origin, Vec3::new(1.0, 2.0, 3.0));
+        assert!((dir - Vec3::Z).length() < 1e-5);
+
+        // The top right corner is 45 degrees up, and twice as far right because of the aspect ratio.
+        let (_, dir) = camera_ray(&camera, &transform, Vec2::new(200.0, 100.0), window_size);
+        let expected = Vec3::new(-2.0, 1.0, 1.0).normalize();
+        assert!((dir - expected).length() < 1e-5);
+
+        // Orthographic rays are parallel, from the pixels on the plane through the camera.
+        let camera = OrthographicCamera {
+            height: 10.0,
+            ..Default::default()
+        };
+        let camera = Projection::from_components(None, Some(&camera)).unwrap();
+        let (origin, dir) = camera_ray(&camera, &transform, Vec2::new(200.0, 100.0), window_size);
+        assert!((origin - Vec3::new(1.0 - 10.0, 2.0 + 5.0, 3.0)).length() < 1e-5);
+        assert!((dir - Vec3::Z).length() < 1e-5);
+    }
+}
@@ -9,6 +9,7 @@ pub struct RayShaders {
     pub pipeline: vk::Pipeline,
     pub pipeline_layout: vk::PipelineLayout,
     pub sbt: super::sbt::Sbt,
+    /// For sampling the depth images of the windows. See `SwapchainImage::depth_image`.
     pub depth_sampler: vk::Sampler,
 }
 
@@ -0,0 +1,4 @@
+// Handles of the arenas in arena_alloc.rs, as seen by the shaders.
+// Chunk n of an arena is placed at n * block size in its address space, so a handle is the
+// index of its slot whatever the ArenaLayout. The shaders never have to decode a handle.
+#define ARENA_HANDLE_NONE 0xFFFFFFFFu
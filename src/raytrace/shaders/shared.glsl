@@ -2,6 +2,7 @@ struct RayPayload {
     vec3 color;
     float t;
     bool didHit;
+    uint shadowMask; // Instance mask of the shadow rays
 };
 
 struct ShadowRayPayload {
@@ -0,0 +1,370 @@
+use super::Svdag;
+use crate::raytrace::arena_alloc::{ArenaLayout, Handle};
+use crate::raytrace::block_alloc::{AllocError, SystemBlockAllocator};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+// A staging DAG only holds a single octant, so it gets away with much smaller blocks.
+fn staging_layout() -> ArenaLayout {
+    ArenaLayout::new(16, 9)
+}
+
+/// Edits one grid of a DAG from multiple threads.
+/// Each thread locks one of the 8 top-level octants and writes to a private copy of it.
+/// `finish` merges the octants back in octant order, so the resulting DAG does not depend on
+/// how the threads were scheduled, and holds the same voxels as applying the edits sequentially.
+pub struct ConcurrentGridAccessor<'a> {
+    dag: &'a mut Svdag,
+    size: u8,
+    root_index: usize,
+    // The staging DAG of each octant. Created when the octant is first locked.
+    octants: [Mutex<Option<Svdag>>; 8],
+}
+
+/// Write access to one top-level octant, held by one thread at a time.
+/// Coordinates are in the space of the whole grid.
+pub struct OctantAccessorMut<'g> {
+    staging: MutexGuard<'g, Option<Svdag>>,
+    size: u8,
+    corner: u8,
+}
+
+// The top-level octant containing (x, y, z) in a grid of side length 2^size.
+fn corner_of(size: u8, x: u32, y: u32, z: u32) -> u8 {
+    let half = 1 << (size - 1);
+    let mut corner: u8 = 0;
+    if x >= half {
+        corner |= 0b100;
+    }
+    if y >= half {
+        corner |= 0b010;
+    }
+    if z >= half {
+        corner |= 0b001;
+    }
+    corner
+}
+
+// Occupancy and subtree of the root at `corner`.
+unsafe fn root_octant(dag: &Svdag, root: Handle, corner: u8) -> (bool, Handle) {
+    if root.is_none() {
+        return (false, Handle::none());
+    }
+    let header = &dag.arena.get(root).header;
+    let child = if header.has_child_at_corner_u8(corner) {
+        header.child_at_corner_u8(corner).handle
+    } else {
+        Handle::none()
+    };
+    (header.occupancy_at_corner_u8(corner), child)
+}
+
+impl<'a> ConcurrentGridAccessor<'a> {
+    // Blocks until no other thread holds the octant.
+    pub fn lock_octant(&self, corner: u8) -> Result<OctantAccessorMut, AllocError> {
+        assert!(corner < 8);
+        let mut staging = self.octants[corner as usize].lock().unwrap();
+        if staging.is_none() {
+            *staging = Some(self.stage_octant(corner)?);
+        }
+        Ok(OctantAccessorMut {
+            staging,
+            size: self.size,
+            corner,
+        })
+    }
+
+    // Copy one octant of the DAG into a new host DAG of the same grid size.
+    fn stage_octant(&self, corner: u8) -> Result<Svdag, AllocError> {
+        let layout = staging_layout();
+        let block_allocator =
+            Arc::new(SystemBlockAllocator::new(Svdag::block_size(layout) as usize));
+        let mut staging = Svdag::with_layout(block_allocator, 1, layout);
+        unsafe {
+            let (occupied, child) = root_octant(self.dag, self.dag.roots[self.root_index], corner);
+            let mut children = [Handle::none(); 8];
+            children[corner as usize] = staging.copy_subtree(self.dag, child)?;
+            staging.roots[0] = staging.alloc_node((occupied as u8) << corner, children)?;
+        }
+        Ok(staging)
+    }
+
+    /// Merge the locked octants back into the DAG.
+    /// On failure, the DAG is left untouched and the edits are discarded.
+    pub fn finish(self) -> Result<(), AllocError> {
+        let dag = self.dag;
+        let root = dag.roots[self.root_index];
+        let mut occupancy_mask = 0;
+        let mut children = [Handle::none(); 8];
+        let mut staged = [false; 8];
+        unsafe {
+            for corner in 0..8 {
+                let (occupied, child) = root_octant(dag, root, corner);
+                occupancy_mask |= (occupied as u8) << corner;
+                children[corner as usize] = child;
+            }
+            for (corner, octant) in self.octants.into_iter().enumerate() {
+                let staging = match octant.into_inner().unwrap() {
+                    Some(staging) => staging,
+                    None => continue,
+                };
+                let (occupied, child) = root_octant(&staging, staging.roots[0], corner as u8);
+                match dag.copy_subtree(&staging, child) {
+                    Ok(copy) => children[corner] = copy,
+                    Err(err) => {
+                        discard_staged(dag, &children, &staged);
+                        return Err(err);
+                    }
+                }
+                staged[corner] = true;
+                occupancy_mask &= !(1 << corner);
+                occupancy_mask |= (occupied as u8) << corner;
+            }
+            let new_root = match dag.alloc_root(occupancy_mask, children) {
+                Ok(new_root) => new_root,
+                Err(err) => {
+                    discard_staged(dag, &children, &staged);
+                    return Err(err);
+                }
+            };
+            // The untouched octants move over to the new root.
+            for corner in 0..8 {
+                if !staged[corner] {
+                    dag.retain(children[corner]);
+                }
+            }
+            dag.release(root);
+            dag.roots[self.root_index] = new_root;
+        }
+        Ok(())
+    }
+}
+
+unsafe fn discard_staged(dag: &mut Svdag, children: &[Handle; 8], staged: &[bool; 8]) {
+    for corner in 0..8 {
+        if staged[corner] {
+            dag.release(children[corner]);
+        }
+    }
+}
+
+impl<'g> OctantAccessorMut<'g> {
+    pub fn corner(&self) -> u8 {
+        self.corner
+    }
+    pub fn get(&self, x: u32, y: u32, z: u32) -> bool {
+        assert_eq!(corner_of(self.size, x, y, z), self.corner);
+        let staging = self.staging.as_ref().unwrap();
+        staging.get_grid_accessor(self.size, 0).get(x, y, z)
+    }
+    // On failure, the octant is left untouched.
+    pub fn set(&mut self, x: u32, y: u32, z: u32, occupancy: bool) -> Result<(), AllocError> {
+        assert_eq!(
+            corner_of(self.size, x, y, z),
+            self.corner,
+            "Voxel is outside of the locked octant"
+        );
+        let staging = self.staging.as_mut().unwrap();
+        staging
+            .get_grid_accessor_mut(self.size, 0)
+            .set(x, y, z, occupancy)
+    }
+}
+
+impl Svdag {
+    // Edit a certain frame of the DAG from multiple threads, one top-level octant per thread.
+    pub fn get_concurrent_accessor(&mut self, size: u8, frame: usize) -> ConcurrentGridAccessor {
+        assert!(size >= 1);
+        ConcurrentGridAccessor {
+            dag: self,
+            size,
+            root_index: frame,
+            octants: Default::default(),
+        }
+    }
+
+    // Copy the subtree at `handle` of `src` into this DAG. Returns the handle of the copy.
+    unsafe fn copy_subtree(&mut self, src: &Svdag, handle: Handle) -> Result<Handle, AllocError> {
+        if handle.is_none() {
+            return Ok(Handle::none());
+        }
+        let len = src.arena.get(handle).header.child_mask.count_ones() + 1;
+        let new_handle = self.arena.alloc(len)?;
+        std::ptr::copy(src.arena.get(handle), self.arena.get_mut(new_handle), 1);
+        for i in 1..len {
+            let child = src.arena.get(handle.offset(i)).body.handle;
+            match self.copy_subtree(src, child) {
+                Ok(copy) => self.arena.get_mut(new_handle.offset(i)).body.handle = copy,
+                Err(err) => {
+                    // Free what was copied so far.
+                    for j in i..len {
+                        self.arena.get_mut(new_handle.offset(j)).body.handle = Handle::none();
+                    }
+                    self.release(new_handle);
+                    return Err(err);
+                }
+            }
+        }
+        Ok(new_handle)
+    }
+
+    // Allocate a node with the given children, where `Handle::none()` means no child.
+    // Nodes without children and with uniform occupancy collapse, the same way edits do.
+    unsafe fn alloc_node(
+        &mut self,
+        occupancy_mask: u8,
+        children: [Handle; 8],
+    ) -> Result<Handle, AllocError> {
+        let mut child_mask: u8 = 0;
+        for (corner, child) in children.iter().enumerate() {
+            if !child.is_none() {
+                child_mask |= 1 << corner;
+            }
+        }
+        if child_mask == 0 && (occupancy_mask == 0 || occupancy_mask == 0xFF) {
+            return Ok(Handle::none());
+        }
+        let handle = self.arena.alloc(child_mask.count_ones() + 1)?;
+        let header = &mut self.arena.get_mut(handle).header;
+        header.child_mask = child_mask;
+        header.occupancy_mask = occupancy_mask;
+        let mut body_handle = handle.offset(1);
+        for child in children.iter().filter(|child| !child.is_none()) {
+            self.arena.get_mut(body_handle).body.handle = *child;
+            body_handle = body_handle.offset(1);
+        }
+        Ok(handle)
+    }
+
+    // Like `alloc_node`, but a full grid keeps its root node, since a missing root means
+    // an empty grid.
+    unsafe fn alloc_root(
+        &mut self,
+        occupancy_mask: u8,
+        children: [Handle; 8],
+    ) -> Result<Handle, AllocError> {
+        let handle = self.alloc_node(occupancy_mask, children)?;
+        if !handle.is_none() || occupancy_mask != 0xFF {
+            return Ok(handle);
+        }
+        let handle = self.arena.alloc(1)?;
+        let header = &mut self.arena.get_mut(handle).header;
+        header.child_mask = 0;
+        header.occupancy_mask = 0xFF;
+        Ok(handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Svdag;
+
+    // Voxels spread over all octants of a 16^3 grid.
+    fn pattern() -> Vec<(u32, u32, u32)> {
+        (0..16 * 16)
+            .map(|i| ((i * 7) % 16, (i * 3 + i / 16) % 16, (i * 11 + i / 5) % 16))
+            .collect()
+    }
+
+    fn edit_concurrently(dag: &mut Svdag) {
+        let voxels = pattern();
+        let grid = dag.get_concurrent_accessor(4, 0);
+        crossbeam::scope(|scope| {
+            // Lock the octants in a different order than they are merged in.
+            for corner in (0..8).rev() {
+                let grid = &grid;
+                let voxels = &voxels;
+                scope.spawn(move |_| {
+                    let mut octant = grid.lock_octant(corner).unwrap();
+                    for &(x, y, z) in voxels.iter() {
+                        if super::corner_of(4, x, y, z) == corner {
+                            octant.set(x, y, z, true).unwrap();
+                        }
+                    }
+                    if corner == 7 {
+                        octant.set(15, 15, 15, false).unwrap();
+                    }
+                });
+            }
+        })
+        .unwrap();
+        grid.finish().unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_edits() {
+        let voxels = pattern();
+        let mut sequential = Svdag::potato();
+        let mut concurrent = Svdag::potato();
+        for dag in [&mut sequential, &mut concurrent] {
+            // Existing content gets carried into the octants.
+            let mut grid = dag.get_grid_accessor_mut(4, 0);
+            grid.set(15, 15, 15, true).unwrap();
+            grid.set(0, 0, 1, true).unwrap();
+        }
+        let mut grid = sequential.get_grid_accessor_mut(4, 0);
+        for &(x, y, z) in voxels.iter() {
+            grid.set(x, y, z, true).unwrap();
+        }
+        grid.set(15, 15, 15, false).unwrap();
+        edit_concurrently(&mut concurrent);
+
+        let expected = sequential.get_grid_accessor(4, 0);
+        let actual = concurrent.get_grid_accessor(4, 0);
+        for x in 0..16 {
+            for y in 0..16 {
+                for z in 0..16 {
+                    assert_eq!(actual.get(x, y, z), expected.get(x, y, z));
+                }
+            }
+        }
+        assert!(actual.get(0, 0, 1));
+        assert!(!actual.get(15, 15, 15));
+        assert_eq!(concurrent.arena.get_size(), sequential.arena.get_size());
+    }
+
+    #[test]
+    fn test_concurrent_fill() {
+        let mut sequential = Svdag::potato();
+        let mut concurrent = Svdag::potato();
+        let mut grid = sequential.get_grid_accessor_mut(2, 0);
+        for i in 0..64 {
+            grid.set(i % 4, i / 4 % 4, i / 16, true).unwrap();
+        }
+        let grid = concurrent.get_concurrent_accessor(2, 0);
+        crossbeam::scope(|scope| {
+            for corner in 0..8 {
+                let grid = &grid;
+                scope.spawn(move |_| {
+                    let mut octant = grid.lock_octant(corner).unwrap();
+                    for i in 0..64 {
+                        let (x, y, z) = (i % 4, i / 4 % 4, i / 16);
+                        if super::corner_of(2, x, y, z) == corner {
+                            octant.set(x, y, z, true).unwrap();
+                        }
+                    }
+                });
+            }
+        })
+        .unwrap();
+        grid.finish().unwrap();
+
+        // The full grid keeps a root node, like it does after sequential edits.
+        assert!(!sequential.get_roots()[0].is_none());
+        assert!(!concurrent.get_roots()[0].is_none());
+        let actual = concurrent.get_grid_accessor(2, 0);
+        for i in 0..64 {
+            assert!(actual.get(i % 4, i / 4 % 4, i / 16));
+        }
+        assert_eq!(concurrent.arena.get_size(), sequential.arena.get_size());
+    }
+
+    #[test]
+    fn test_deterministic_layout() {
+        let mut a = Svdag::potato();
+        let mut b = Svdag::potato();
+        edit_concurrently(&mut a);
+        edit_concurrently(&mut b);
+        assert_eq!(a.get_roots(), b.get_roots());
+        assert_eq!(a.arena.metadata(), b.arena.metadata());
+    }
+}
@@ -1,15 +1,16 @@
-use super::Svdag;
+use super::{edit_reserved_slots, Svdag, SvdagSnapshot, MAX_GRID_SIZE};
 use crate::raytrace::arena_alloc::Handle;
+use crate::raytrace::block_alloc::AllocError;
 
 pub struct GridAccessor<'a> {
     pub(super) dag: &'a Svdag,
     pub(super) size: u8,
-    pub(super) root_index: usize,
+    pub(super) root: Handle,
 }
 
 impl<'a> GridAccessor<'a> {
     pub fn get(&self, mut x: u32, mut y: u32, mut z: u32) -> bool {
-        let root = self.dag.roots[self.root_index];
+        let root = self.root;
         if root.is_none() {
             return false;
         }
@@ -62,12 +63,125 @@ impl<'a> GridAccessor<'a> {
                 .occupancy_at_corner_u8(corner)
         }
     }
+
+    // Visit the whole grid as cubes of uniform occupancy, calling f(min, side_length, occupied)
+    // for each of them. Collapsed nodes are visited as one cube instead of voxel by voxel.
+    pub fn for_each_region(&self, mut f: impl FnMut([u32; 3], u32, bool)) {
+        let gridsize = 1 << self.size;
+        if self.root.is_none() {
+            f([0, 0, 0], gridsize, false);
+            return;
+        }
+        self.for_each_region_recursive(self.root, [0, 0, 0], gridsize, &mut f);
+    }
+
+    // Bounding box of the occupied voxels, from min (inclusive) to max (exclusive).
+    // None if the grid is empty.
+    pub fn occupied_bounds(&self) -> Option<([u32; 3], [u32; 3])> {
+        let mut bounds: Option<([u32; 3], [u32; 3])> = None;
+        self.for_each_region(|min, side, occupied| {
+            if !occupied {
+                return;
+            }
+            let max = [min[0] + side, min[1] + side, min[2] + side];
+            bounds = Some(match bounds {
+                None => (min, max),
+                Some((bmin, bmax)) => (
+                    [
+                        bmin[0].min(min[0]),
+                        bmin[1].min(min[1]),
+                        bmin[2].min(min[2]),
+                    ],
+                    [
+                        bmax[0].max(max[0]),
+                        bmax[1].max(max[1]),
+                        bmax[2].max(max[2]),
+                    ],
+                ),
+            });
+        });
+        bounds
+    }
+
+    // Cubes with side length 2^(size - depth) that contain occupied voxels, as (min, side_length).
+    // Fully occupied regions larger than that are returned as one cube.
+    pub fn occupied_bricks(&self, depth: u8) -> Vec<([u32; 3], u32)> {
+        let mut bricks = Vec::new();
+        if !self.root.is_none() {
+            self.occupied_bricks_recursive(
+                self.root,
+                [0, 0, 0],
+                1 << self.size,
+                depth,
+                &mut bricks,
+            );
+        }
+        bricks
+    }
+
+    fn occupied_bricks_recursive(
+        &self,
+        handle: Handle,
+        min: [u32; 3],
+        gridsize: u32,
+        depth: u8,
+        bricks: &mut Vec<([u32; 3], u32)>,
+    ) {
+        if depth == 0 {
+            // Nodes only exist for regions that aren't entirely empty.
+            bricks.push((min, gridsize));
+            return;
+        }
+        let half = gridsize / 2;
+        let header = unsafe { &self.dag.arena.get(handle).header };
+        for corner in 0..8 {
+            let child_min = [
+                min[0] + if corner & 0b100 != 0 { half } else { 0 },
+                min[1] + if corner & 0b010 != 0 { half } else { 0 },
+                min[2] + if corner & 0b001 != 0 { half } else { 0 },
+            ];
+            if header.has_child_at_corner_u8(corner) {
+                let child = unsafe { header.child_at_corner_u8(corner).handle };
+                self.occupied_bricks_recursive(child, child_min, half, depth - 1, bricks);
+            } else if header.occupancy_at_corner_u8(corner) {
+                bricks.push((child_min, half));
+            }
+        }
+    }
+
+    fn for_each_region_recursive(
+        &self,
+        handle: Handle,
+        min: [u32; 3],
+        gridsize: u32,
+        f: &mut impl FnMut([u32; 3], u32, bool),
+    ) {
+        let half = gridsize / 2;
+        let header = unsafe { &self.dag.arena.get(handle).header };
+        for corner in 0..8 {
+            let child_min = [
+                min[0] + if corner & 0b100 != 0 { half } else { 0 },
+                min[1] + if corner & 0b010 != 0 { half } else { 0 },
+                min[2] + if corner & 0b001 != 0 { half } else { 0 },
+            ];
+            if header.has_child_at_corner_u8(corner) {
+                let child = unsafe { header.child_at_corner_u8(corner).handle };
+                self.for_each_region_recursive(child, child_min, half, f);
+            } else {
+                f(child_min, half, header.occupancy_at_corner_u8(corner));
+            }
+        }
+    }
 }
 
 pub struct GridAccessorMut<'a> {
     pub(super) dag: &'a mut Svdag,
     pub(super) size: u8,
     pub(super) root_index: usize,
+    // Segments replaced and nodes released by the edit in progress. They are only freed once
+    // the new path is linked to the root, so a failed edit leaves the old path intact.
+    freed_segments: Vec<(Handle, u32)>,
+    released_nodes: Vec<Handle>,
 }
 
 impl<'a> GridAccessorMut<'a> {
@@ -75,20 +189,120 @@ impl<'a> GridAccessorMut<'a> {
         let accessor = GridAccessor {
             dag: self.dag,
             size: self.size,
-            root_index: self.root_index,
+            root: self.dag.roots[self.root_index],
         };
         accessor.get(x, y, z)
     }
-    pub fn set(&mut self, x: u32, y: u32, z: u32, occupancy: bool) {
+    // On failure, the DAG is left untouched.
+    pub fn set(&mut self, x: u32, y: u32, z: u32, occupancy: bool) -> Result<(), AllocError> {
+        self.set_cube(x, y, z, 1, occupancy)
+    }
+
+    // Set the cube of side length `side` at (x, y, z) to a uniform occupancy,
+    // replacing the node covering it. `side` is a power of two and the cube is aligned to it.
+    // On failure, the DAG is left untouched.
+    fn set_cube(
+        &mut self,
+        x: u32,
+        y: u32,
+        z: u32,
+        side: u32,
+        occupancy: bool,
+    ) -> Result<(), AllocError> {
         let mut root = self.dag.roots[self.root_index];
+        let result = unsafe { self.set_cube_from_root(&mut root, x, y, z, side, occupancy) };
+        let freed_segments = std::mem::take(&mut self.freed_segments);
+        let released_nodes = std::mem::take(&mut self.released_nodes);
+        // The old root still references the replaced nodes, so they stay allocated.
+        result?;
+        self.dag.roots[self.root_index] = root;
         unsafe {
-            self.set_recursive(&mut root, x, y, z, 1 << self.size, occupancy);
+            for (handle, len) in freed_segments {
+                self.dag.arena.free(handle, len);
+            }
+            for handle in released_nodes {
+                self.dag.release(handle);
+            }
         }
-        self.dag.roots[self.root_index] = root;
+        Ok(())
+    }
+
+    unsafe fn set_cube_from_root(
+        &mut self,
+        root: &mut Handle,
+        x: u32,
+        y: u32,
+        z: u32,
+        side: u32,
+        occupancy: bool,
+    ) -> Result<(), AllocError> {
+        let gridsize = 1 << self.size;
+        // Reserving every slot the edit may allocate up front means that
+        // set_recursive never fails halfway.
+        let reserved = edit_reserved_slots(self.size, self.dag.has_shared_nodes());
+        self.dag.arena.reserve(reserved)?;
+        let occupied = if side == gridsize {
+            self.released_nodes.push(*root);
+            *root = Handle::none();
+            occupancy
+        } else {
+            self.set_recursive(root, x, y, z, gridsize, side, occupancy, false)?
+        };
+        if root.is_none() && occupied {
+            // A missing root means an empty grid, so a full grid keeps its root node.
+            *root = self.alloc_uniform_node(true)?;
+        }
+        Ok(())
+    }
+
+    // Set every voxel from `min` (inclusive) to `max` (exclusive), clipped to the grid.
+    // On failure, the voxels set so far keep their new value.
+    pub fn fill_box(
+        &mut self,
+        min: [u32; 3],
+        max: [u32; 3],
+        occupancy: bool,
+    ) -> Result<(), AllocError> {
+        self.fill_box_recursive([0, 0, 0], 1 << self.size, min, max, occupancy)
+    }
+
+    // Nodes fully inside the box are replaced as a whole, so only the nodes
+    // along the faces of the box are subdivided.
+    fn fill_box_recursive(
+        &mut self,
+        origin: [u32; 3],
+        side: u32,
+        min: [u32; 3],
+        max: [u32; 3],
+        occupancy: bool,
+    ) -> Result<(), AllocError> {
+        let intersects = (0..3).all(|i| min[i] < origin[i] + side && origin[i] < max[i]);
+        if !intersects {
+            return Ok(());
+        }
+        let covered = (0..3).all(|i| min[i] <= origin[i] && origin[i] + side <= max[i]);
+        if covered {
+            return self.set_cube(origin[0], origin[1], origin[2], side, occupancy);
+        }
+        // A single voxel is either covered or outside, so side > 1 here.
+        let half = side / 2;
+        for corner in 0..8 {
+            let octant = [
+                origin[0] + if corner & 0b100 != 0 { half } else { 0 },
+                origin[1] + if corner & 0b010 != 0 { half } else { 0 },
+                origin[2] + if corner & 0b001 != 0 { half } else { 0 },
+            ];
+            self.fill_box_recursive(octant, half, min, max, occupancy)?;
+        }
+        Ok(())
     }
 
     // Returns: avg
-    // Base case: when gridsize = 2 and parent node is non-null, set the occupancy corner in the parent node.
+    // `inherited` is the occupancy of the subtree while `handle` is none, taken from the
+    // occupancy mask of the parent.
+    // The cube of side length `side` at (x, y, z) is set, `side` being smaller than `gridsize`.
+    // Base case: when gridsize = 2 * side and parent node is non-null, set the occupancy corner in the parent node
+    //            and drop the child node at that corner.
     //            if this causes the parent to have uniform occupancy and no children, collapse the parent by deallocating it.
     // Induction step: for gridsize > 2 and parent node is non-null, call avg = self(gridsize / 2) and set the occupancy in the parent node.
     //                 if this causes the parent node to have uniform
@@ -99,8 +313,16 @@ impl<'a> GridAccessorMut<'a> {
         mut y: u32,
         mut z: u32,
         mut gridsize: u32,
+        side: u32,
         occupancy: bool,
-    ) -> bool {
+        inherited: bool,
+    ) -> Result<bool, AllocError> {
+        if handle.is_none() && inherited == occupancy {
+            // The voxel already has the requested value.
+            return Ok(inherited);
+        }
+        // Nodes shared with a snapshot are copied before they are written to.
+        self.dag.make_unique(handle, &mut self.released_nodes)?;
         gridsize = gridsize / 2;
         let mut corner: u8 = 0;
         if x >= gridsize {
@@ -115,53 +337,67 @@ impl<'a> GridAccessorMut<'a> {
             corner |= 0b001;
             z -= gridsize;
         }
-        if gridsize <= 1 {
-            // is leaf node
+        if gridsize <= side {
+            // The octant at `corner` is the cube being set.
             if std::intrinsics::unlikely(handle.is_none()) {
-                // This happens only when gridsize = 2.
-                // TODO: set handle to be something.
-                *handle = self.dag.arena.alloc(1);
-                let header = &mut self.dag.arena.get_mut(*handle).header;
-                header.child_mask = 0;
-                header.occupancy_mask = 0;
+                // This happens only when gridsize = 2 * side.
+                *handle = self.alloc_uniform_node(inherited)?;
             }
-            let header = &mut self.dag.arena.get_mut(*handle).header;
-            header.set_occupancy_at_corner_u8(corner, occupancy);
+            let header = &self.dag.arena.get(*handle).header;
             if header.has_child_at_corner_u8(corner) {
                 // has children. Cut them off.
-                todo!()
+                let child = header.child_at_corner_u8(corner).handle;
+                self.remove_children(handle, corner)?;
+                self.released_nodes.push(child);
             }
+            let header = &mut self.dag.arena.get_mut(*handle).header;
+            header.set_occupancy_at_corner_u8(corner, occupancy);
         } else {
             let mut new_handle = Handle::none();
+            let mut child_inherited = inherited;
             if !handle.is_none() {
                 let header = &self.dag.arena.get(*handle).header;
                 if header.has_child_at_corner_u8(corner) {
                     new_handle = header.child_at_corner_u8(corner).handle;
                 }
+                child_inherited = header.occupancy_at_corner_u8(corner);
             }
-            let avg = self.set_recursive(&mut new_handle, x, y, z, gridsize, occupancy);
+            let avg = self.set_recursive(
+                &mut new_handle,
+                x,
+                y,
+                z,
+                gridsize,
+                side,
+                occupancy,
+                child_inherited,
+            )?;
 
             if new_handle.is_none() {
-                self.remove_children(handle, corner);
+                if handle.is_none() {
+                    // The child collapsed. Only the occupancy mask of this node is needed.
+                    *handle = self.alloc_uniform_node(inherited)?;
+                } else {
+                    self.remove_children(handle, corner)?;
+                }
             } else {
                 // children exists.
                 // put new_handle into the parent node
                 if handle.is_none() {
                     // Allocate new
-                    *handle = self.dag.arena.alloc(2);
+                    *handle = self.dag.arena.alloc(2)?;
                     let header = &mut self.dag.arena.get_mut(*handle).header;
                     header.child_mask = 1 << corner;
-                    header.occupancy_mask = 0;
+                    header.occupancy_mask = if inherited { 0xFF } else { 0 };
                 } else {
                     // Parent already exists.
-                    self.insert_children(handle, corner);
+                    self.insert_children(handle, corner)?;
                 }
-                self.dag
-                    .arena
-                    .get_mut(*handle)
-                    .header
-                    .child_at_corner_mut_u8(corner)
-                    .handle = new_handle;
+                // Write the body slot through its own handle so that it gets marked as dirty.
+                let child_mask = self.dag.arena.get(*handle).header.child_mask;
+                let body_handle =
+                    handle.offset(1 + super::mask_location_nth_one(child_mask, corner) as u32);
+                self.dag.arena.get_mut(body_handle).body.handle = new_handle;
             }
             let header = &mut self.dag.arena.get_mut(*handle).header;
             if avg {
@@ -177,13 +413,22 @@ impl<'a> GridAccessorMut<'a> {
             // collapse node
             let occupancy_mask = header.occupancy_mask;
             if occupancy_mask == 0 || occupancy_mask == 0xFF {
-                let block_size = header.child_mask.count_ones() as u8 + 1;
-                self.dag.arena.free(*handle, block_size);
+                let block_size = header.child_mask.count_ones() + 1;
+                self.freed_segments.push((*handle, block_size));
                 *handle = Handle::none();
-                return occupancy_mask == 0xFF;
+                return Ok(occupancy_mask == 0xFF);
             }
         }
-        header.occupancy_mask != 0
+        Ok(header.occupancy_mask != 0)
+    }
+
+    // A node without children, fully occupied or empty.
+    unsafe fn alloc_uniform_node(&mut self, occupied: bool) -> Result<Handle, AllocError> {
+        let handle = self.dag.arena.alloc(1)?;
+        let header = &mut self.dag.arena.get_mut(handle).header;
+        header.child_mask = 0;
+        header.occupancy_mask = if occupied { 0xFF } else { 0 };
+        Ok(handle)
     }
 
     // Change the childmask of the node located at node_handle
@@ -194,17 +439,17 @@ impl<'a> GridAccessorMut<'a> {
     // - If !old.has_child_at_corner_u8(n) and new.has_child_at_corner_u8(n), space will be reserved for the new node
     // - Otherwise, nothing happens.
     // TODO: make sure the freeing is recursive.
-    unsafe fn reshape(&mut self, old_handle: Handle, new_mask: u8) -> Handle {
+    unsafe fn reshape(&mut self, old_handle: Handle, new_mask: u8) -> Result<Handle, AllocError> {
         let old_slot = self.dag.arena.get(old_handle);
         let occupancy_mask = old_slot.header.occupancy_mask;
         let old_mask = old_slot.header.child_mask;
         if old_mask == new_mask {
-            return old_handle;
+            return Ok(old_handle);
         }
         let old_slot_num_child = old_slot.header.child_mask.count_ones() as u8;
 
         let new_slot_num_child = new_mask.count_ones() as u8;
-        let new_handle = self.dag.arena.alloc((new_slot_num_child + 1) as u32);
+        let new_handle = self.dag.arena.alloc((new_slot_num_child + 1) as u32)?;
         let _new_slot = self.dag.arena.get(new_handle);
 
         let mut old_slot_num: u8 = 0;
@@ -234,26 +479,37 @@ impl<'a> GridAccessorMut<'a> {
                 new_slot_num += 1;
             }
         }
-        self.dag.arena.free(old_handle, old_slot_num_child + 1);
+        self.freed_segments
+            .push((old_handle, old_slot_num_child as u32 + 1));
 
         let new_slot = self.dag.arena.get_mut(new_handle);
         new_slot.header.child_mask = new_mask;
         new_slot.header.occupancy_mask = occupancy_mask;
 
-        new_handle
+        Ok(new_handle)
     }
 
-    unsafe fn insert_children(&mut self, handle: &mut Handle, corner: u8) {
+    unsafe fn insert_children(
+        &mut self,
+        handle: &mut Handle,
+        corner: u8,
+    ) -> Result<(), AllocError> {
         let old_handle = *handle;
         let old_mask = self.dag.arena.get(old_handle).header.child_mask;
-        let new_handle = self.reshape(old_handle, old_mask | (1 << corner));
+        let new_handle = self.reshape(old_handle, old_mask | (1 << corner))?;
         *handle = new_handle;
+        Ok(())
     }
-    unsafe fn remove_children(&mut self, handle: &mut Handle, corner: u8) {
+    unsafe fn remove_children(
+        &mut self,
+        handle: &mut Handle,
+        corner: u8,
+    ) -> Result<(), AllocError> {
         let old_handle = *handle;
         let old_mask = self.dag.arena.get(old_handle).header.child_mask;
-        let new_handle = self.reshape(old_handle, old_mask & !(1 << corner));
+        let new_handle = self.reshape(old_handle, old_mask & !(1 << corner))?;
         *handle = new_handle;
+        Ok(())
     }
 }
 
@@ -263,14 +519,30 @@ impl Svdag {
         GridAccessor {
             dag: self,
             size,
-            root_index: frame,
+            root: self.roots[frame],
+        }
+    }
+    // Access a certain frame of a snapshot taken from this DAG.
+    pub fn get_snapshot_accessor<'a>(
+        &'a self,
+        snapshot: &SvdagSnapshot,
+        size: u8,
+        frame: usize,
+    ) -> GridAccessor<'a> {
+        GridAccessor {
+            dag: self,
+            size,
+            root: snapshot.roots[frame],
         }
     }
     pub fn get_grid_accessor_mut(&mut self, size: u8, frame: usize) -> GridAccessorMut {
+        assert!(size <= MAX_GRID_SIZE, "Grid size {} is too large", size);
         GridAccessorMut {
             dag: self,
             size,
             root_index: frame,
+            freed_segments: Vec::new(),
+            released_nodes: Vec::new(),
         }
     }
 }
@@ -278,6 +550,12 @@ impl Svdag {
 #[cfg(test)]
 mod tests {
     use super::Svdag;
+    use crate::raytrace::arena_alloc::{ArenaLayout, BLOCK_SIZE};
+    use crate::raytrace::block_alloc::{
+        AllocError, BudgetedBlockAllocator, MemoryBudget, MockBlockAllocator, MockEvent,
+        SystemBlockAllocator,
+    };
+    use std::sync::Arc;
 
     #[test]
     fn test_set() {
@@ -285,7 +563,7 @@ mod tests {
         let mut grid = dag.get_grid_accessor_mut(2, 0);
 
         assert!(!grid.get(0, 0, 0));
-        grid.set(0, 0, 0, true);
+        grid.set(0, 0, 0, true).unwrap();
         assert!(grid.get(0, 0, 0));
         assert!(!grid.get(1, 0, 0));
         assert!(!grid.get(0, 1, 0));
@@ -294,21 +572,463 @@ mod tests {
 
         for x in 0..=1 {
             for y in 0..=1 {
-                grid.set(x, y, 1, true);
+                grid.set(x, y, 1, true).unwrap();
             }
         }
-        grid.set(0, 1, 0, true);
-        grid.set(1, 0, 0, true);
+        grid.set(0, 1, 0, true).unwrap();
+        grid.set(1, 0, 0, true).unwrap();
         assert_eq!(grid.dag.arena.get_size(), 3);
 
-        grid.set(3, 3, 3, true);
+        grid.set(3, 3, 3, true).unwrap();
         assert!(grid.get(3, 3, 3));
         assert_eq!(grid.dag.arena.get_size(), 5);
 
         // Fill in the last peace before collapse
         assert!(!grid.get(1, 1, 0));
-        grid.set(1, 1, 0, true);
+        grid.set(1, 1, 0, true).unwrap();
         assert!(grid.get(1, 1, 0));
         assert_eq!(grid.dag.arena.get_size(), 3);
     }
+
+    #[test]
+    fn test_snapshot() {
+        let mut dag = Svdag::potato();
+        let mut grid = dag.get_grid_accessor_mut(3, 0);
+        grid.set(1, 2, 3, true).unwrap();
+        grid.set(6, 0, 5, true).unwrap();
+        let size = dag.arena.get_size();
+
+        let snapshot = dag.snapshot();
+        assert_eq!(dag.arena.get_size(), size);
+        let mut grid = dag.get_grid_accessor_mut(3, 0);
+        grid.set(1, 2, 3, false).unwrap();
+        grid.set(7, 7, 7, true).unwrap();
+        assert!(!grid.get(1, 2, 3));
+        assert!(grid.get(7, 7, 7));
+
+        // The snapshot still sees the old version.
+        let old_grid = dag.get_snapshot_accessor(&snapshot, 3, 0);
+        assert!(old_grid.get(1, 2, 3));
+        assert!(old_grid.get(6, 0, 5));
+        assert!(!old_grid.get(7, 7, 7));
+
+        // Releasing the snapshot frees the nodes the new version no longer uses.
+        dag.release_snapshot(snapshot);
+        let mut fresh = Svdag::potato();
+        let mut grid = fresh.get_grid_accessor_mut(3, 0);
+        grid.set(6, 0, 5, true).unwrap();
+        grid.set(7, 7, 7, true).unwrap();
+        assert_eq!(dag.arena.get_size(), fresh.arena.get_size());
+        assert!(dag.shared.is_empty());
+    }
+
+    #[test]
+    fn test_undo_redo() {
+        let mut dag = Svdag::potato();
+        assert!(!dag.undo());
+        dag.get_grid_accessor_mut(2, 0).set(0, 0, 0, true).unwrap();
+        dag.checkpoint();
+        dag.get_grid_accessor_mut(2, 0).set(3, 3, 3, true).unwrap();
+        dag.checkpoint();
+        dag.get_grid_accessor_mut(2, 0).set(0, 0, 0, false).unwrap();
+
+        assert!(dag.undo());
+        let grid = dag.get_grid_accessor(2, 0);
+        assert!(grid.get(0, 0, 0) && grid.get(3, 3, 3));
+        assert!(dag.undo());
+        let grid = dag.get_grid_accessor(2, 0);
+        assert!(grid.get(0, 0, 0) && !grid.get(3, 3, 3));
+        assert!(!dag.can_undo());
+
+        assert!(dag.redo());
+        assert!(dag.get_grid_accessor(2, 0).get(3, 3, 3));
+        // A new checkpoint discards the redo stack.
+        dag.checkpoint();
+        assert!(!dag.can_redo());
+        dag.get_grid_accessor_mut(2, 0).set(1, 0, 0, true).unwrap();
+        assert!(dag.undo());
+        assert!(!dag.get_grid_accessor(2, 0).get(1, 0, 0));
+
+        dag.clear_history();
+        assert!(dag.shared.is_empty());
+        assert_eq!(dag.arena.get_size(), 5);
+    }
+
+    #[test]
+    fn test_fill_box() {
+        let mut dag = Svdag::potato();
+        let mut grid = dag.get_grid_accessor_mut(3, 0);
+        grid.fill_box([0, 0, 0], [4, 4, 4], true).unwrap();
+        // The octant collapsed into the root.
+        assert_eq!(grid.dag.arena.get_size(), 1);
+        grid.fill_box([0, 0, 0], [8, 8, 8], true).unwrap();
+        assert!(grid.get(7, 0, 3));
+        grid.set(7, 0, 3, false).unwrap();
+        assert!(!grid.get(7, 0, 3) && grid.get(7, 0, 2));
+        grid.fill_box([4, 0, 0], [8, 8, 8], false).unwrap();
+        grid.fill_box([2, 3, 1], [5, 10, 2], false).unwrap();
+        grid.fill_box([6, 6, 6], [10, 10, 10], true).unwrap();
+        for x in 0..8 {
+            for y in 0..8 {
+                for z in 0..8 {
+                    let in_left_half = x < 4;
+                    let in_hole = (2..5).contains(&x) && y >= 3 && z == 1;
+                    let in_corner = x >= 6 && y >= 6 && z >= 6;
+                    assert_eq!(grid.get(x, y, z), (in_left_half && !in_hole) || in_corner);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_fill_box_whole_nodes() {
+        let mut dag = Svdag::potato();
+        let mut grid = dag.get_grid_accessor_mut(6, 0);
+        grid.fill_box([1, 2, 3], [60, 61, 62], true).unwrap();
+        let inside = |x: u32, y: u32, z: u32| {
+            (1..60).contains(&x) && (2..61).contains(&y) && (3..62).contains(&z)
+        };
+        for x in 0..64 {
+            for y in 0..64 {
+                for z in 0..64 {
+                    assert_eq!(grid.get(x, y, z), inside(x, y, z));
+                }
+            }
+        }
+        let snapshot = grid.dag.snapshot();
+        // Clearing the grid releases every node that isn't part of the snapshot.
+        let mut grid = dag.get_grid_accessor_mut(6, 0);
+        grid.fill_box([0, 0, 0], [64, 64, 64], false).unwrap();
+        assert!(dag.get_roots()[0].is_none());
+        assert!(dag.get_snapshot_accessor(&snapshot, 6, 0).get(1, 2, 3));
+        dag.release_snapshot(snapshot);
+        assert_eq!(dag.arena.get_size(), 0);
+    }
+
+    #[test]
+    fn test_for_each_region() {
+        let mut dag = Svdag::potato();
+        let mut grid = dag.get_grid_accessor_mut(3, 0);
+        grid.fill_box([4, 0, 0], [8, 4, 4], true).unwrap();
+        grid.set(1, 2, 3, true).unwrap();
+        let mut regions = Vec::new();
+        dag.get_grid_accessor(3, 0)
+            .for_each_region(|min, side, occupied| regions.push((min, side, occupied)));
+
+        // Every voxel is covered exactly once.
+        let volume: u32 = regions.iter().map(|(_, side, _)| side * side * side).sum();
+        assert_eq!(volume, 512);
+        assert!(regions.contains(&([4, 0, 0], 4, true)));
+        assert!(regions.contains(&([1, 2, 3], 1, true)));
+        assert_eq!(
+            regions.iter().filter(|(_, _, occupied)| *occupied).count(),
+            2
+        );
+        assert_eq!(
+            dag.get_grid_accessor(3, 0).occupied_bounds(),
+            Some(([1, 0, 0], [8, 4, 4]))
+        );
+
+        let mut regions = Vec::new();
+        Svdag::potato()
+            .get_grid_accessor(3, 0)
+            .for_each_region(|min, side, occupied| regions.push((min, side, occupied)));
+        assert_eq!(regions, vec![([0, 0, 0], 8, false)]);
+        assert_eq!(
+            Svdag::potato().get_grid_accessor(3, 0).occupied_bounds(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_occupied_bricks() {
+        let mut dag = Svdag::potato();
+        let mut grid = dag.get_grid_accessor_mut(3, 0);
+        grid.fill_box([4, 0, 0], [8, 4, 4], true).unwrap();
+        grid.set(1, 2, 3, true).unwrap();
+        let grid = dag.get_grid_accessor(3, 0);
+        assert_eq!(grid.occupied_bricks(0), vec![([0, 0, 0], 8)]);
+        assert_eq!(
+            grid.occupied_bricks(1),
+            vec![([0, 0, 0], 4), ([4, 0, 0], 4)]
+        );
+        // The full octant stays in one piece.
+        assert_eq!(
+            grid.occupied_bricks(2),
+            vec![([0, 2, 2], 2), ([4, 0, 0], 4)]
+        );
+        assert_eq!(
+            grid.occupied_bricks(3),
+            vec![([1, 2, 3], 1), ([4, 0, 0], 4)]
+        );
+        assert!(Svdag::potato()
+            .get_grid_accessor(3, 0)
+            .occupied_bricks(2)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_small_blocks() {
+        // 512 slots per block, the smallest layout a DAG accepts.
+        let layout = ArenaLayout::new(9, 9);
+        let block_allocator =
+            Arc::new(SystemBlockAllocator::new(Svdag::block_size(layout) as usize));
+        let mut dag = Svdag::with_layout(block_allocator, 1, layout);
+        let mut grid = dag.get_grid_accessor_mut(6, 0);
+        let voxels: Vec<(u32, u32, u32)> =
+            (0..64).map(|i| (i, (i * 7) % 64, (i * 13) % 64)).collect();
+        for &(x, y, z) in voxels.iter() {
+            grid.set(x, y, z, true).unwrap();
+        }
+        assert!(dag.arena.memory_usage() > Svdag::block_size(layout));
+        let grid = dag.get_grid_accessor(6, 0);
+        for x in 0..64 {
+            for y in 0..64 {
+                for z in 0..64 {
+                    assert_eq!(grid.get(x, y, z), voxels.contains(&(x, y, z)));
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "The arena layout is too small for the DAG")]
+    fn test_layout_too_small() {
+        // An edit of the deepest grid reserves more than the 256 slots of a block.
+        let layout = ArenaLayout::new(8, 9);
+        let block_allocator =
+            Arc::new(SystemBlockAllocator::new(Svdag::block_size(layout) as usize));
+        Svdag::with_layout(block_allocator, 1, layout);
+    }
+
+    #[test]
+    fn test_share_root() {
+        let mut dag = Svdag::new_host(2);
+        dag.enable_handle_validation();
+        let mut grid = dag.get_grid_accessor_mut(3, 0);
+        grid.set(1, 2, 3, true).unwrap();
+        let size = dag.arena.get_size();
+        dag.share_root(0, 1);
+        assert_eq!(dag.arena.get_size(), size);
+        assert!(dag.get_grid_accessor(3, 1).get(1, 2, 3));
+
+        // Editing one root leaves the other one untouched.
+        let mut grid = dag.get_grid_accessor_mut(3, 1);
+        grid.set(4, 5, 6, true).unwrap();
+        assert!(!dag.get_grid_accessor(3, 0).get(4, 5, 6));
+        assert!(dag.get_grid_accessor(3, 1).get(4, 5, 6));
+        let mut grid = dag.get_grid_accessor_mut(3, 0);
+        grid.set(1, 2, 3, false).unwrap();
+        assert!(dag.get_grid_accessor(3, 1).get(1, 2, 3));
+    }
+
+    #[test]
+    fn test_upload() {
+        let mut dag = Svdag::potato();
+        let mut grid = dag.get_grid_accessor_mut(3, 0);
+        grid.set(1, 2, 3, true).unwrap();
+        grid.set(7, 7, 7, true).unwrap();
+
+        let block_allocator = Arc::new(SystemBlockAllocator::new(BLOCK_SIZE as usize));
+        let uploaded = dag.upload(block_allocator).unwrap();
+        drop(dag);
+        let grid = uploaded.get_grid_accessor(3, 0);
+        assert!(grid.get(1, 2, 3));
+        assert!(grid.get(7, 7, 7));
+        assert!(!grid.get(0, 0, 0));
+    }
+
+    #[test]
+    fn test_memory_usage() {
+        let budget = Arc::new(MemoryBudget::new(BLOCK_SIZE));
+        let block_allocator = Arc::new(BudgetedBlockAllocator::new(
+            SystemBlockAllocator::new(BLOCK_SIZE as usize),
+            budget.clone(),
+        ));
+        let mut dag = Svdag::new(block_allocator.clone(), 1);
+        assert_eq!(dag.memory_usage(), 0);
+        dag.get_grid_accessor_mut(3, 0).set(1, 2, 3, true).unwrap();
+        assert_eq!(dag.memory_usage(), BLOCK_SIZE);
+        assert_eq!(budget.committed(), BLOCK_SIZE);
+        assert!(!budget.is_exceeded());
+
+        let uploaded = dag.upload(block_allocator).unwrap();
+        assert_eq!(uploaded.memory_usage(), BLOCK_SIZE);
+        assert_eq!(budget.excess(), BLOCK_SIZE);
+        drop(dag);
+        assert!(!budget.is_exceeded());
+        drop(uploaded);
+        assert_eq!(budget.committed(), 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_file_reopen() {
+        use crate::raytrace::block_alloc::FileBlockAllocator;
+        let path = std::env::temp_dir().join(format!("dust-svdag-{}.bin", std::process::id()));
+        {
+            let file = Arc::new(FileBlockAllocator::create(&path, BLOCK_SIZE).unwrap());
+            let mut dag = Svdag::new(file.clone(), 2);
+            let mut grid = dag.get_grid_accessor_mut(3, 0);
+            grid.set(1, 2, 3, true).unwrap();
+            grid.set(6, 0, 5, true).unwrap();
+            dag.share_root(0, 1);
+            dag.save(&file).unwrap();
+        }
+        {
+            let file = Arc::new(FileBlockAllocator::open(&path).unwrap());
+            assert_eq!(file.num_blocks(), 1);
+            let mut dag = Svdag::open(file.clone()).unwrap();
+            let mut grid = dag.get_grid_accessor_mut(3, 0);
+            assert!(grid.get(1, 2, 3));
+            assert!(grid.get(6, 0, 5));
+            assert!(!grid.get(0, 0, 0));
+            // The freelists were restored as well. Edits keep working after reopening.
+            grid.set(6, 0, 5, false).unwrap();
+            grid.set(7, 7, 7, true).unwrap();
+            assert!(!grid.get(6, 0, 5));
+            assert!(grid.get(7, 7, 7));
+            // The shared root was copied instead of being written to.
+            assert!(dag.get_grid_accessor(3, 1).get(6, 0, 5));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_file_open_invalid() {
+        use crate::raytrace::block_alloc::FileBlockAllocator;
+        use crate::raytrace::svdag::SvdagFileError;
+        let path = std::env::temp_dir().join(format!("dust-invalid-{}.bin", std::process::id()));
+        let file = Arc::new(FileBlockAllocator::create(&path, BLOCK_SIZE).unwrap());
+        // Nothing was saved yet.
+        assert!(matches!(
+            Svdag::open(file.clone()),
+            Err(SvdagFileError::InvalidFile(_))
+        ));
+
+        let mut dag = Svdag::new(file.clone(), 1);
+        dag.get_grid_accessor_mut(3, 0).set(1, 2, 3, true).unwrap();
+        dag.save(&file).unwrap();
+        drop(dag);
+        let metadata = file.metadata();
+        // Truncated metadata is rejected instead of read out of bounds.
+        for len in [4, 12, metadata.len() - 4] {
+            file.set_metadata(&metadata[..len]).unwrap();
+            assert!(matches!(
+                Svdag::open(file.clone()),
+                Err(SvdagFileError::InvalidFile(_))
+            ));
+        }
+        file.set_metadata(&metadata).unwrap();
+        assert!(Svdag::open(file.clone()).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_flushed_ranges() {
+        let block_allocator = Arc::new(MockBlockAllocator::new(BLOCK_SIZE as usize));
+        let mut dag = Svdag::new(block_allocator.clone(), 1);
+        let mut grid = dag.get_grid_accessor_mut(2, 0);
+        grid.set(0, 0, 0, true).unwrap();
+        assert_eq!(
+            block_allocator.take_events(),
+            vec![MockEvent::AllocateBlock(0)]
+        );
+        assert!(dag.flush_dirty().unwrap());
+        // One leaf node and a root node with one child, 4 bytes per slot.
+        assert_eq!(
+            block_allocator.take_events(),
+            vec![MockEvent::Flush(0, 0..12)]
+        );
+
+        // Nothing left to flush.
+        assert!(dag.flush_dirty().unwrap());
+        assert_eq!(block_allocator.take_events(), vec![]);
+
+        let mut grid = dag.get_grid_accessor_mut(2, 0);
+        grid.set(3, 3, 3, true).unwrap();
+        assert!(dag.flush_dirty().unwrap());
+        // The old root node at slot 1 now holds a freelist link.
+        // The new leaf node sits at slot 3, followed by the new root node with two children.
+        assert_eq!(
+            block_allocator.take_events(),
+            vec![MockEvent::Flush(0, 4..8), MockEvent::Flush(0, 12..28)]
+        );
+    }
+
+    #[test]
+    fn test_flush_while_busy() {
+        let block_allocator = Arc::new(MockBlockAllocator::new(BLOCK_SIZE as usize));
+        let mut dag = Svdag::new(block_allocator.clone(), 1);
+        dag.get_grid_accessor_mut(2, 0).set(0, 0, 0, true).unwrap();
+        block_allocator.take_events();
+
+        block_allocator.set_can_flush(false);
+        assert!(!dag.flush_dirty().unwrap());
+        assert_eq!(block_allocator.take_events(), vec![]);
+
+        // The dirty ranges carry over to the next flush.
+        block_allocator.set_can_flush(true);
+        assert!(dag.flush_dirty().unwrap());
+        assert!(!block_allocator.take_events().is_empty());
+    }
+
+    #[test]
+    fn test_alloc_failure() {
+        let block_allocator = Arc::new(MockBlockAllocator::new(BLOCK_SIZE as usize));
+        let mut dag = Svdag::new(block_allocator.clone(), 1);
+        block_allocator.inject_failure(0, AllocError::OutOfDeviceMemory);
+        let mut grid = dag.get_grid_accessor_mut(2, 0);
+        let result = grid.set(0, 0, 0, true);
+        assert!(matches!(result, Err(AllocError::OutOfDeviceMemory)));
+        assert!(!grid.get(0, 0, 0));
+        assert!(dag.get_roots()[0].is_none());
+        assert_eq!(block_allocator.num_live_blocks(), 0);
+
+        // The next allocation succeeds.
+        let mut grid = dag.get_grid_accessor_mut(2, 0);
+        grid.set(0, 0, 0, true).unwrap();
+        assert!(grid.get(0, 0, 0));
+        drop(dag);
+        assert_eq!(
+            block_allocator.take_events(),
+            vec![MockEvent::AllocateBlock(0), MockEvent::DeallocateBlock(0)]
+        );
+    }
+
+    #[test]
+    fn test_alloc_failure_mid_edit() {
+        // 512 slots per block, so edits soon need a second block.
+        let layout = ArenaLayout::new(9, 9);
+        let block_allocator = Arc::new(MockBlockAllocator::new(Svdag::block_size(layout) as usize));
+        let mut dag = Svdag::with_layout(block_allocator.clone(), 1, layout);
+        dag.enable_handle_validation();
+        // The first block is allocated, the second one fails.
+        block_allocator.inject_failure(1, AllocError::OutOfDeviceMemory);
+        let voxels: Vec<(u32, u32, u32)> = (0..512)
+            .map(|i| (i % 64, i / 64 * 7, (i * 13) % 64))
+            .collect();
+        let mut grid = dag.get_grid_accessor_mut(6, 0);
+        let mut num_set = 0;
+        for &(x, y, z) in voxels.iter() {
+            match grid.set(x, y, z, true) {
+                Ok(()) => num_set += 1,
+                Err(err) => {
+                    assert!(matches!(err, AllocError::OutOfDeviceMemory));
+                    break;
+                }
+            }
+        }
+        assert!(num_set < voxels.len());
+        assert_eq!(block_allocator.num_live_blocks(), 1);
+
+        // The failed edit left the DAG as it was before.
+        let size = dag.arena.get_size();
+        let grid = dag.get_grid_accessor(6, 0);
+        for (i, &(x, y, z)) in voxels.iter().enumerate() {
+            assert_eq!(grid.get(x, y, z), i < num_set);
+        }
+        assert_eq!(dag.arena.get_size(), size);
+    }
 }
@@ -1,9 +1,75 @@
+mod concurrent;
 mod grid;
+mod raycast;
 
+use std::collections::HashMap;
 use std::sync::Arc;
 
-use super::arena_alloc::{ArenaAllocated, ArenaAllocator, Handle};
-use super::block_alloc::BlockAllocator;
+use super::arena_alloc::{ArenaAllocated, ArenaAllocator, ArenaLayout, ArenaMetadata, Handle};
+#[cfg(unix)]
+use super::block_alloc::FileBlockAllocator;
+use super::block_alloc::{AllocError, BlockAllocator, SystemBlockAllocator};
+pub use concurrent::{ConcurrentGridAccessor, OctantAccessorMut};
+pub use grid::{GridAccessor, GridAccessorMut};
+pub use raycast::RaycastHit;
+
+// The metadata written by `Svdag::save` starts with these words.
+#[cfg(unix)]
+const SAVE_MAGIC: u32 = u32::from_le_bytes(*b"SVDG");
+#[cfg(unix)]
+const SAVE_VERSION: u32 = 1;
+
+/// Errors of `Svdag::open`.
+#[derive(Debug)]
+pub enum SvdagFileError {
+    Alloc(AllocError),
+    /// The file holds no saved DAG, or its content is truncated or corrupt.
+    InvalidFile(&'static str),
+}
+
+impl From<AllocError> for SvdagFileError {
+    fn from(err: AllocError) -> Self {
+        SvdagFileError::Alloc(err)
+    }
+}
+
+impl std::fmt::Display for SvdagFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SvdagFileError::Alloc(err) => err.fmt(f),
+            SvdagFileError::InvalidFile(reason) => write!(f, "invalid DAG file: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for SvdagFileError {}
+
+/// The largest grid size, in levels, that the ray tracing shaders can traverse.
+/// See CAST_STACK_DEPTH in esvo.rint.
+pub const MAX_GRID_SIZE: u8 = 23;
+
+// An edit allocates at most one node of 9 slots per level, plus a copy of the node
+// if it is shared with a snapshot.
+const NODE_SLOTS_PER_LEVEL: u32 = 9;
+const SHARED_NODE_SLOTS_PER_LEVEL: u32 = 2 * NODE_SLOTS_PER_LEVEL;
+
+// The slots reserved by an edit of a grid of `size` levels, plus a uniform root node.
+fn edit_reserved_slots(size: u8, has_shared_nodes: bool) -> u32 {
+    let slots_per_level = if has_shared_nodes {
+        SHARED_NODE_SLOTS_PER_LEVEL
+    } else {
+        NODE_SLOTS_PER_LEVEL
+    };
+    slots_per_level * size as u32 + 1
+}
+
+// Every slot an edit may need is reserved in a single block, so blocks must hold
+// the slots of the deepest grid. See `ArenaAllocator::reserve`.
+fn supports_layout(layout: ArenaLayout) -> bool {
+    layout.max_segment_len() >= NODE_SLOTS_PER_LEVEL
+        && layout.num_slots_in_block()
+            > edit_reserved_slots(MAX_GRID_SIZE, true) + layout.max_segment_len()
+}
 
 fn mask_location_nth_one(mask: u8, location: u8) -> u8 {
     (mask & ((1 << location) - 1)).count_ones() as u8
@@ -35,24 +101,12 @@ impl Header {
         &body_slot.body
     }
 
-    #[inline]
-    pub unsafe fn child_at_corner_mut_u8(&mut self, corner: u8) -> &mut Body {
-        // Given a mask and a location, returns n where the given '1' on the location
-        // is the nth '1' counting from the least significant bit.
-
-        let ptr = self as *mut Self as *mut Slot;
-        let body_slot_ptr =
-            ptr.add(1 + mask_location_nth_one(self.child_mask, corner as u8) as usize);
-        let body_slot = &mut *body_slot_ptr;
-        &mut body_slot.body
-    }
-
     #[inline]
     pub fn set_occupancy_at_corner_u8(&mut self, corner: u8, occupied: bool) {
         if occupied {
             self.occupancy_mask |= 1 << corner;
         } else {
-            self.occupancy_mask &= 1 << corner;
+            self.occupancy_mask &= !(1 << corner);
         }
     }
 }
@@ -74,30 +128,360 @@ impl ArenaAllocated for Slot {}
 pub struct Svdag {
     pub(crate) arena: ArenaAllocator<Slot>,
     roots: Vec<Handle>,
+    // Nodes referenced more than once, from snapshots or from nodes copied out of a snapshot,
+    // mapped to their number of additional references. Edits copy these nodes instead of
+    // writing to them.
+    shared: HashMap<Handle, u32>,
+    undo_stack: Vec<SvdagSnapshot>,
+    redo_stack: Vec<SvdagSnapshot>,
+}
+
+/// An immutable version of a DAG, sharing unchanged nodes with the DAG it was taken from.
+/// Give it back with `Svdag::release_snapshot`, otherwise its nodes stay allocated
+/// until the DAG is dropped.
+pub struct SvdagSnapshot {
+    roots: Vec<Handle>,
+}
+
+impl SvdagSnapshot {
+    pub fn get_roots(&self) -> &[Handle] {
+        &self.roots
+    }
 }
 
 impl Svdag {
     pub fn new(block_allocator: Arc<dyn BlockAllocator>, num_roots: u32) -> Self {
-        let arena: ArenaAllocator<Slot> = ArenaAllocator::new(block_allocator);
+        Self::with_layout(block_allocator, num_roots, ArenaLayout::DEFAULT)
+    }
+    /// The block size of `block_allocator` must match the layout. See `Svdag::block_size`.
+    /// Panics if segments can't hold a node of 9 slots, or if blocks can't hold the
+    /// slots reserved by an edit of a grid of `MAX_GRID_SIZE`.
+    pub fn with_layout(
+        block_allocator: Arc<dyn BlockAllocator>,
+        num_roots: u32,
+        layout: ArenaLayout,
+    ) -> Self {
+        assert!(
+            supports_layout(layout),
+            "The arena layout is too small for the DAG"
+        );
+        let arena: ArenaAllocator<Slot> = ArenaAllocator::with_layout(block_allocator, layout);
         Svdag {
             arena,
             roots: vec![Handle::none(); num_roots as usize],
+            shared: HashMap::new(),
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
         }
     }
+    /// Create a DAG stored in host memory. No Vulkan device is required.
+    pub fn new_host(num_roots: u32) -> Self {
+        let block_allocator = Arc::new(SystemBlockAllocator::new(Self::block_size(
+            ArenaLayout::DEFAULT,
+        ) as usize));
+        Self::new(block_allocator, num_roots)
+    }
+    /// The block size a block allocator needs to have for a DAG with the given layout.
+    pub fn block_size(layout: ArenaLayout) -> u64 {
+        layout.block_size(std::mem::size_of::<Slot>())
+    }
     #[cfg(test)]
     pub fn potato() -> Self {
-        let block_allocator = Arc::new(super::block_alloc::SystemBlockAllocator::new(
-            super::arena_alloc::BLOCK_SIZE as usize,
-        ));
+        let mut dag = Self::new_host(1);
+        dag.enable_handle_validation();
+        dag
+    }
+    /// Panic on invalid handles in debug builds, naming the handle and where it was allocated.
+    /// Must be called before the first edit. See `ArenaAllocator::enable_handle_validation`.
+    pub fn enable_handle_validation(&mut self) {
+        self.arena.enable_handle_validation();
+    }
 
-        Self::new(block_allocator, 1)
+    /// Copy the DAG into another block allocator, typically moving a host-built model onto the GPU.
+    /// The copy is flushed before it is returned.
+    pub fn upload(&self, block_allocator: Arc<dyn BlockAllocator>) -> Result<Self, AllocError> {
+        // Handles remain valid in the copy, so the history can be carried over.
+        let copy_snapshots = |stack: &Vec<SvdagSnapshot>| {
+            stack
+                .iter()
+                .map(|snapshot| SvdagSnapshot {
+                    roots: snapshot.roots.clone(),
+                })
+                .collect()
+        };
+        let mut svdag = Svdag {
+            arena: self.arena.copy_to(block_allocator)?,
+            roots: self.roots.clone(),
+            shared: self.shared.clone(),
+            undo_stack: copy_snapshots(&self.undo_stack),
+            redo_stack: copy_snapshots(&self.redo_stack),
+        };
+        svdag.flush_all()?;
+        Ok(svdag)
     }
 
-    pub fn flush_all(&self) {
-        self.arena.flush_all();
+    pub fn flush_all(&mut self) -> Result<(), AllocError> {
+        self.arena.flush_all()
+    }
+
+    // Flush the edits made since the last flush. Returns false if the flush was deferred.
+    pub fn flush_dirty(&mut self) -> Result<bool, AllocError> {
+        self.arena.flush_dirty()
+    }
+
+    /// Write all edits and the arena bookkeeping into the file backing this DAG,
+    /// so that it can be reopened with `Svdag::open`.
+    /// Panics if `file` is not the block allocator this DAG was created with.
+    /// Snapshots and the undo history are not saved.
+    #[cfg(unix)]
+    pub fn save(&mut self, file: &FileBlockAllocator) -> Result<(), AllocError> {
+        assert!(
+            std::ptr::eq(
+                Arc::as_ptr(self.arena.block_allocator()) as *const u8,
+                file as *const FileBlockAllocator as *const u8,
+            ),
+            "The DAG is not stored in this file"
+        );
+        // Flushes to a file are never deferred.
+        self.flush_dirty()?;
+        let mut words: Vec<u32> = vec![SAVE_MAGIC, SAVE_VERSION];
+        words.extend(self.arena.metadata().to_words());
+        words.push(self.roots.len() as u32);
+        words.extend(self.roots.iter().map(|root| root.get_value()));
+        let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        file.set_metadata(&bytes).map_err(AllocError::Io)
+    }
+
+    /// Reopen a DAG previously written with `Svdag::save`.
+    /// Files that were never saved, or whose metadata is truncated, are rejected.
+    /// The content of the nodes themselves is not validated.
+    #[cfg(unix)]
+    pub fn open(file: Arc<FileBlockAllocator>) -> Result<Self, SvdagFileError> {
+        let bytes = file.metadata();
+        if bytes.is_empty() {
+            return Err(SvdagFileError::InvalidFile("no DAG was saved"));
+        }
+        let words: Vec<u32> = bytes
+            .chunks_exact(4)
+            .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .collect();
+        if words.get(..2) != Some(&[SAVE_MAGIC, SAVE_VERSION]) {
+            return Err(SvdagFileError::InvalidFile(
+                "not a DAG, or saved by another version",
+            ));
+        }
+        let words = &words[2..];
+        let metadata = ArenaMetadata::from_words(words)
+            .ok_or(SvdagFileError::InvalidFile("invalid arena metadata"))?;
+        if !supports_layout(metadata.layout()) {
+            return Err(SvdagFileError::InvalidFile("unsupported arena layout"));
+        }
+        if Self::block_size(metadata.layout()) != file.get_blocksize() {
+            return Err(SvdagFileError::InvalidFile(
+                "the block size does not match the arena layout",
+            ));
+        }
+        if metadata.num_chunks() as u64 > file.num_blocks() {
+            return Err(SvdagFileError::InvalidFile("blocks are missing"));
+        }
+        let (&num_roots, root_words) = words[metadata.num_words()..]
+            .split_first()
+            .ok_or(SvdagFileError::InvalidFile("truncated roots"))?;
+        let roots: Vec<Handle> = root_words
+            .get(..num_roots as usize)
+            .ok_or(SvdagFileError::InvalidFile("truncated roots"))?
+            .iter()
+            .map(|&word| Handle::from_value(word))
+            .collect();
+        let layout = metadata.layout();
+        let root_in_file =
+            |root: &Handle| root.is_none() || layout.chunk_num(*root) < metadata.num_chunks();
+        if !roots.iter().all(root_in_file) {
+            return Err(SvdagFileError::InvalidFile("a root is outside of the file"));
+        }
+
+        let block_allocator: Arc<dyn BlockAllocator> = file.clone();
+        let arena = unsafe {
+            ArenaAllocator::from_metadata(block_allocator, &metadata, |chunk_index| {
+                file.map_block(chunk_index as u64)
+            })?
+        };
+        let mut svdag = Svdag {
+            arena,
+            roots,
+            shared: HashMap::new(),
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+        };
+        // Roots saved after `share_root` are shared again.
+        for i in 0..svdag.roots.len() {
+            let root = svdag.roots[i];
+            if svdag.roots[..i].contains(&root) {
+                svdag.retain(root);
+            }
+        }
+        Ok(svdag)
+    }
+
+    // Bytes of block memory held by this DAG.
+    pub fn memory_usage(&self) -> u64 {
+        self.arena.memory_usage()
     }
 
     pub fn get_roots(&self) -> &[Handle] {
         &self.roots
     }
+
+    /// Take an immutable version of the DAG. This only takes a reference on the roots.
+    /// Later edits copy the nodes on their path instead of modifying the shared ones.
+    pub fn snapshot(&mut self) -> SvdagSnapshot {
+        for root in self.roots.clone() {
+            self.retain(root);
+        }
+        SvdagSnapshot {
+            roots: self.roots.clone(),
+        }
+    }
+
+    /// Free the nodes only referenced by `snapshot`.
+    pub fn release_snapshot(&mut self, snapshot: SvdagSnapshot) {
+        for root in snapshot.roots {
+            unsafe { self.release(root) };
+        }
+    }
+
+    /// Make the DAG identical to `snapshot` without copying any nodes.
+    pub fn restore(&mut self, snapshot: &SvdagSnapshot) {
+        assert_eq!(snapshot.roots.len(), self.roots.len());
+        for &root in snapshot.roots.iter() {
+            self.retain(root);
+        }
+        let old_roots = std::mem::replace(&mut self.roots, snapshot.roots.clone());
+        for root in old_roots {
+            unsafe { self.release(root) };
+        }
+    }
+
+    /// Make root `dst` show the same grid as root `src`, sharing all of its nodes.
+    /// Later edits of either root copy the nodes they write to.
+    pub fn share_root(&mut self, src: usize, dst: usize) {
+        let root = self.roots[src];
+        self.retain(root);
+        let old_root = std::mem::replace(&mut self.roots[dst], root);
+        unsafe { self.release(old_root) };
+    }
+
+    /// Record the current state on the undo stack. Clears the redo stack.
+    pub fn checkpoint(&mut self) {
+        let snapshot = self.snapshot();
+        self.undo_stack.push(snapshot);
+        while let Some(snapshot) = self.redo_stack.pop() {
+            self.release_snapshot(snapshot);
+        }
+    }
+
+    /// Go back to the last checkpoint. Returns false if the undo stack is empty.
+    pub fn undo(&mut self) -> bool {
+        match self.undo_stack.pop() {
+            Some(snapshot) => {
+                let current = std::mem::replace(&mut self.roots, snapshot.roots);
+                self.redo_stack.push(SvdagSnapshot { roots: current });
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Reapply the state undone last. Returns false if the redo stack is empty.
+    pub fn redo(&mut self) -> bool {
+        match self.redo_stack.pop() {
+            Some(snapshot) => {
+                let current = std::mem::replace(&mut self.roots, snapshot.roots);
+                self.undo_stack.push(SvdagSnapshot { roots: current });
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo_stack.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo_stack.is_empty()
+    }
+
+    /// Drop the undo and redo stacks, freeing the nodes only they referenced.
+    pub fn clear_history(&mut self) {
+        let stacks = std::mem::take(&mut self.undo_stack)
+            .into_iter()
+            .chain(std::mem::take(&mut self.redo_stack));
+        for snapshot in stacks {
+            self.release_snapshot(snapshot);
+        }
+    }
+
+    fn retain(&mut self, handle: Handle) {
+        if !handle.is_none() {
+            *self.shared.entry(handle).or_insert(0) += 1;
+        }
+    }
+
+    // Drop one reference to the node at `handle`, freeing it and releasing its children
+    // once nothing references it anymore.
+    unsafe fn release(&mut self, handle: Handle) {
+        if handle.is_none() {
+            return;
+        }
+        if let Some(extra_refs) = self.shared.get_mut(&handle) {
+            *extra_refs -= 1;
+            if *extra_refs == 0 {
+                self.shared.remove(&handle);
+            }
+            return;
+        }
+        let num_children = self.arena.get(handle).header.child_mask.count_ones();
+        let children: Vec<Handle> = (1..=num_children)
+            .map(|i| self.arena.get(handle.offset(i)).body.handle)
+            .collect();
+        self.arena.free(handle, num_children + 1);
+        for child in children {
+            self.release(child);
+        }
+    }
+
+    fn has_shared_nodes(&self) -> bool {
+        !self.shared.is_empty()
+    }
+
+    // Replace a shared node with a private copy that can be written to.
+    // The children of the node become shared between the copy and the original.
+    // The original is pushed to `released`, for the caller to `release` once the copy
+    // is linked in its place.
+    unsafe fn make_unique(
+        &mut self,
+        handle: &mut Handle,
+        released: &mut Vec<Handle>,
+    ) -> Result<(), AllocError> {
+        if handle.is_none() || !self.shared.contains_key(handle) {
+            return Ok(());
+        }
+        let len = self.arena.get(*handle).header.child_mask.count_ones() + 1;
+        let new_handle = self.arena.alloc(len)?;
+        for i in 0..len {
+            std::ptr::copy(
+                self.arena.get(handle.offset(i)),
+                self.arena.get_mut(new_handle.offset(i)),
+                1,
+            );
+        }
+        for i in 1..len {
+            let child = self.arena.get(new_handle.offset(i)).body.handle;
+            self.retain(child);
+        }
+        released.push(*handle);
+        *handle = new_handle;
+        Ok(())
+    }
 }
@@ -0,0 +1,181 @@
+use super::grid::GridAccessor;
+use crate::raytrace::arena_alloc::Handle;
+
+/// The first occupied voxel along a ray, in grid coordinates.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RaycastHit {
+    pub voxel: [u32; 3],
+    /// Normal of the face the ray entered through. Zero if the ray starts inside the voxel.
+    pub normal: [i32; 3],
+    /// Ray parameter of the hit, in multiples of the ray direction.
+    pub t: f32,
+}
+
+// The span of t for which the ray lies between lo and hi along one axis.
+fn slab(origin: f32, dir: f32, lo: f32, hi: f32) -> (f32, f32) {
+    if dir == 0.0 {
+        if lo <= origin && origin <= hi {
+            (f32::NEG_INFINITY, f32::INFINITY)
+        } else {
+            (f32::INFINITY, f32::NEG_INFINITY)
+        }
+    } else {
+        let t0 = (lo - origin) / dir;
+        let t1 = (hi - origin) / dir;
+        (t0.min(t1), t0.max(t1))
+    }
+}
+
+// Entry and exit t of the ray for the cube at `min`, and the axis it enters through.
+fn intersect_cube(origin: [f32; 3], dir: [f32; 3], min: [f32; 3], side: f32) -> (f32, f32, usize) {
+    let mut t_enter = f32::NEG_INFINITY;
+    let mut t_exit = f32::INFINITY;
+    let mut axis = 0;
+    for i in 0..3 {
+        let (t0, t1) = slab(origin[i], dir[i], min[i], min[i] + side);
+        if t0 > t_enter {
+            t_enter = t0;
+            axis = i;
+        }
+        t_exit = t_exit.min(t1);
+    }
+    (t_enter, t_exit, axis)
+}
+
+impl<'a> GridAccessor<'a> {
+    /// Find the first occupied voxel hit by the ray `origin + t * dir` for t >= 0.
+    /// The grid spans from 0 to 2^size on each axis.
+    pub fn raycast(&self, origin: [f32; 3], dir: [f32; 3]) -> Option<RaycastHit> {
+        if self.root.is_none() {
+            return None;
+        }
+        let gridsize = (1u32 << self.size) as f32;
+        let (t_enter, t_exit, _) = intersect_cube(origin, dir, [0.0; 3], gridsize);
+        if t_enter > t_exit || t_exit < 0.0 {
+            return None;
+        }
+        self.raycast_recursive(self.root, origin, dir, [0, 0, 0], 1 << self.size)
+    }
+
+    fn raycast_recursive(
+        &self,
+        handle: Handle,
+        origin: [f32; 3],
+        dir: [f32; 3],
+        min: [u32; 3],
+        gridsize: u32,
+    ) -> Option<RaycastHit> {
+        let half = gridsize / 2;
+        let header = unsafe { &self.dag.arena.get(handle).header };
+
+        // Visit the children the ray passes through, front to back.
+        let mut children: Vec<(f32, usize, u8, [u32; 3])> = Vec::with_capacity(8);
+        for corner in 0..8 {
+            let child_min = [
+                min[0] + if corner & 0b100 != 0 { half } else { 0 },
+                min[1] + if corner & 0b010 != 0 { half } else { 0 },
+                min[2] + if corner & 0b001 != 0 { half } else { 0 },
+            ];
+            let min_f = [
+                child_min[0] as f32,
+                child_min[1] as f32,
+                child_min[2] as f32,
+            ];
+            let (t_enter, t_exit, axis) = intersect_cube(origin, dir, min_f, half as f32);
+            if t_enter <= t_exit && t_exit >= 0.0 {
+                children.push((t_enter, axis, corner, child_min));
+            }
+        }
+        children.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+        for (t_enter, axis, corner, child_min) in children {
+            if header.has_child_at_corner_u8(corner) {
+                let child = unsafe { header.child_at_corner_u8(corner).handle };
+                if let Some(hit) = self.raycast_recursive(child, origin, dir, child_min, half) {
+                    return Some(hit);
+                }
+            } else if header.occupancy_at_corner_u8(corner) {
+                let t = t_enter.max(0.0);
+                let mut normal = [0; 3];
+                if t_enter > 0.0 {
+                    normal[axis] = if dir[axis] > 0.0 { -1 } else { 1 };
+                }
+                // The cube may be a collapsed node. Find the voxel within it.
+                let mut voxel = [0; 3];
+                for i in 0..3 {
+                    let p = (origin[i] + t * dir[i]).floor().max(0.0) as u32;
+                    voxel[i] = p.clamp(child_min[i], child_min[i] + half - 1);
+                }
+                return Some(RaycastHit { voxel, normal, t });
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RaycastHit;
+    use crate::raytrace::svdag::Svdag;
+
+    #[test]
+    fn test_raycast() {
+        let mut svdag = Svdag::potato();
+        let mut grid = svdag.get_grid_accessor_mut(3, 0);
+        grid.set(5, 2, 3, true).unwrap();
+        grid.set(6, 2, 3, true).unwrap();
+        grid.fill_box([0, 0, 0], [8, 1, 8], true).unwrap();
+        let grid = svdag.get_grid_accessor(3, 0);
+
+        // Along +x, from outside of the grid.
+        assert_eq!(
+            grid.raycast([-2.0, 2.5, 3.5], [1.0, 0.0, 0.0]),
+            Some(RaycastHit {
+                voxel: [5, 2, 3],
+                normal: [-1, 0, 0],
+                t: 7.0,
+            })
+        );
+        // Along -x, from inside of the grid.
+        assert_eq!(
+            grid.raycast([7.5, 2.5, 3.5], [-1.0, 0.0, 0.0]),
+            Some(RaycastHit {
+                voxel: [6, 2, 3],
+                normal: [1, 0, 0],
+                t: 0.5,
+            })
+        );
+        // Down onto the floor, which is made of collapsed nodes.
+        assert_eq!(
+            grid.raycast([1.5, 6.0, 6.5], [0.0, -2.0, 0.0]),
+            Some(RaycastHit {
+                voxel: [1, 0, 6],
+                normal: [0, 1, 0],
+                t: 2.5,
+            })
+        );
+        // Starting inside of an occupied voxel.
+        let hit = grid.raycast([5.5, 2.5, 3.5], [0.0, 0.0, 1.0]).unwrap();
+        assert_eq!((hit.voxel, hit.normal, hit.t), ([5, 2, 3], [0, 0, 0], 0.0));
+        // Missing the grid, and passing over the voxels.
+        assert_eq!(grid.raycast([-1.0, 2.5, 3.5], [-1.0, 0.0, 0.0]), None);
+        assert_eq!(grid.raycast([-1.0, 4.5, 3.5], [1.0, 0.0, 0.0]), None);
+    }
+
+    #[test]
+    fn test_raycast_diagonal() {
+        let mut svdag = Svdag::potato();
+        let mut grid = svdag.get_grid_accessor_mut(2, 0);
+        grid.set(3, 3, 3, true).unwrap();
+        let grid = svdag.get_grid_accessor(2, 0);
+        let hit = grid.raycast([0.0, 0.0, 0.0], [1.0, 1.0, 1.0]).unwrap();
+        assert_eq!(hit.voxel, [3, 3, 3]);
+        assert_eq!(hit.t, 3.0);
+        assert_eq!(
+            Svdag::potato()
+                .get_grid_accessor(2, 0)
+                .raycast([0.0; 3], [1.0; 3]),
+            None
+        );
+    }
+}
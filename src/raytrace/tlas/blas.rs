@@ -0,0 +1,321 @@
+use crate::render::{Garbage, GarbageBin};
+use crate::{device_info::DeviceInfo, VoxelModel, VulkanAllocator};
+use ash::vk;
+use gpu_alloc_ash::AshMemoryDevice;
+
+/// Insert this resource to give each voxel model its own BLAS, made of one AABB per occupied brick.
+/// Rays then skip the empty bricks during hardware traversal instead of entering the intersection
+/// shader for the whole box of the instance. Without it, all instances share one unit box BLAS.
+#[derive(Clone, Debug)]
+pub struct ModelBlasSettings {
+    /// Bricks have a side length of 1 / 2^brick_depth of the grid. A depth of 1 gives one AABB
+    /// per occupied top-level octant.
+    pub brick_depth: u8,
+}
+
+impl Default for ModelBlasSettings {
+    fn default() -> Self {
+        ModelBlasSettings { brick_depth: 3 }
+    }
+}
+
+pub struct Blas {
+    pub acceleration_structure: vk::AccelerationStructureKHR,
+    pub device_address: u64,
+    pub(super) buf: vk::Buffer,
+    pub(super) mem: crate::MemoryBlock,
+    // The brick AABBs, kept for the intersection shader to clamp the traversal
+    // to the brick that was hit.
+    pub aabb_device_address: u64,
+    pub(super) aabb_buf: vk::Buffer,
+    pub(super) aabb_mem: crate::MemoryBlock,
+}
+
+// A BLAS build waiting to be recorded into the command buffer of the TLAS build.
+pub struct PendingBlasBuild {
+    geometry: vk::AccelerationStructureGeometryKHR,
+    dst: vk::AccelerationStructureKHR,
+    scratch_device_address: u64,
+    primitive_count: u32,
+}
+
+// The occupied bricks of a model, in the object space of an instance where the grid spans
+// from 0 to 1 on each axis. The BLAS is shared by all animation frames, so it covers the
+// bricks occupied in any of them.
+pub fn brick_aabbs(model: &VoxelModel, brick_depth: u8) -> Vec<vk::AabbPositionsKHR> {
+    let gridsize = model.grid_size() as f32;
+    let mut bricks: Vec<([u32; 3], u32)> = (0..model.num_frames() as usize)
+        .flat_map(|frame| {
+            model
+                .svdag
+                .get_grid_accessor(model.size, frame)
+                .occupied_bricks(brick_depth)
+        })
+        .collect();
+    bricks.sort_unstable();
+    bricks.dedup();
+    bricks
+        .into_iter()
+        .map(|(min, side)| vk::AabbPositionsKHR {
+            min_x: min[0] as f32 / gridsize,
+            min_y: min[1] as f32 / gridsize,
+            min_z: min[2] as f32 / gridsize,
+            max_x: (min[0] + side) as f32 / gridsize,
+            max_y: (min[1] + side) as f32 / gridsize,
+            max_z: (min[2] + side) as f32 / gridsize,
+        })
+        .collect()
+}
+
+// Create the BLAS of a model. The build itself is recorded later by `record_blas_builds`.
+// The scratch buffer is pushed to `transient`, to be freed once the build is finished.
+// Returns None for empty models.
+pub unsafe fn create_model_blas(
+    device: &ash::Device,
+    allocator: &mut crate::Allocator,
+    acceleration_structure_loader: &ash::extensions::khr::AccelerationStructure,
+    device_info: &DeviceInfo,
+    aabbs: &[vk::AabbPositionsKHR],
+    transient: &mut Vec<(vk::Buffer, crate::MemoryBlock)>,
+) -> Option<(Blas, PendingBlasBuild)> {
+    if aabbs.is_empty() {
+        return None;
+    }
+    let aabb_buf = device
+        .create_buffer(
+            &vk::BufferCreateInfo::builder()
+                .size(std::mem::size_of_val(aabbs) as u64)
+                .usage(
+                    vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR
+                        | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+                )
+                .sharing_mode(vk::SharingMode::EXCLUSIVE)
+                .build(),
+            None,
+        )
+        .unwrap();
+    let mut aabb_mem = allocator.alloc_for_buffer(
+        device,
+        aabb_buf,
+        gpu_alloc::UsageFlags::UPLOAD | gpu_alloc::UsageFlags::DEVICE_ADDRESS,
+    );
+    aabb_mem
+        .write_bytes(
+            AshMemoryDevice::wrap(device),
+            0,
+            std::slice::from_raw_parts(aabbs.as_ptr() as *const u8, std::mem::size_of_val(aabbs)),
+        )
+        .unwrap();
+    let aabb_device_address = device.get_buffer_device_address(
+        &vk::BufferDeviceAddressInfo::builder()
+            .buffer(aabb_buf)
+            .build(),
+    );
+
+    let geometry = vk::AccelerationStructureGeometryKHR::builder()
+        .geometry_type(vk::GeometryTypeKHR::AABBS)
+        .flags(vk::GeometryFlagsKHR::OPAQUE)
+        .geometry(vk::AccelerationStructureGeometryDataKHR {
+            aabbs: vk::AccelerationStructureGeometryAabbsDataKHR::builder()
+                .data(vk::DeviceOrHostAddressConstKHR {
+                    device_address: aabb_device_address,
+                })
+                .stride(std::mem::size_of::<vk::AabbPositionsKHR>() as u64)
+                .build(),
+        })
+        .build();
+    let build_geometry_info = vk::AccelerationStructureBuildGeometryInfoKHR::builder()
+        .ty(vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL)
+        .flags(vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE)
+        .mode(vk::BuildAccelerationStructureModeKHR::BUILD)
+        .geometries(std::slice::from_ref(&geometry))
+        .build();
+    let sizes = acceleration_structure_loader.get_acceleration_structure_build_sizes(
+        vk::AccelerationStructureBuildTypeKHR::DEVICE,
+        &build_geometry_info,
+        &[aabbs.len() as u32],
+    );
+
+    let scratch_alignment = device_info
+        .acceleration_structure_properties
+        .min_acceleration_structure_scratch_offset_alignment as u64;
+    let scratch_buf = device
+        .create_buffer(
+            &vk::BufferCreateInfo::builder()
+                .size(sizes.build_scratch_size + scratch_alignment)
+                .usage(
+                    vk::BufferUsageFlags::STORAGE_BUFFER
+                        | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+                )
+                .sharing_mode(vk::SharingMode::EXCLUSIVE)
+                .build(),
+            None,
+        )
+        .unwrap();
+    let scratch_mem = allocator.alloc_for_buffer(
+        device,
+        scratch_buf,
+        gpu_alloc::UsageFlags::FAST_DEVICE_ACCESS
+            | gpu_alloc::UsageFlags::TRANSIENT
+            | gpu_alloc::UsageFlags::DEVICE_ADDRESS,
+    );
+    let scratch_device_address = device.get_buffer_device_address(
+        &vk::BufferDeviceAddressInfo::builder()
+            .buffer(scratch_buf)
+            .build(),
+    );
+    let scratch_device_address = crate::util::round_up(scratch_device_address, scratch_alignment);
+    transient.push((scratch_buf, scratch_mem));
+
+    let buf = device
+        .create_buffer(
+            &vk::BufferCreateInfo::builder()
+                .size(sizes.acceleration_structure_size)
+                .usage(
+                    vk::BufferUsageFlags::ACCELERATION_STRUCTURE_STORAGE_KHR
+                        | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+                )
+                .sharing_mode(vk::SharingMode::EXCLUSIVE)
+                .build(),
+            None,
+        )
+        .unwrap();
+    let mem = allocator.alloc_for_buffer(device, buf, gpu_alloc::UsageFlags::FAST_DEVICE_ACCESS);
+    let acceleration_structure = acceleration_structure_loader
+        .create_acceleration_structure(
+            &vk::AccelerationStructureCreateInfoKHR::builder()
+                .buffer(buf)
+                .offset(0)
+                .size(sizes.acceleration_structure_size)
+                .ty(vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL)
+                .build(),
+            None,
+        )
+        .unwrap();
+    let device_address = acceleration_structure_loader.get_acceleration_structure_device_address(
+        &vk::AccelerationStructureDeviceAddressInfoKHR::builder()
+            .acceleration_structure(acceleration_structure)
+            .build(),
+    );
+    Some((
+        Blas {
+            acceleration_structure,
+            device_address,
+            buf,
+            mem,
+            aabb_device_address,
+            aabb_buf,
+            aabb_mem,
+        },
+        PendingBlasBuild {
+            geometry,
+            dst: acceleration_structure,
+            scratch_device_address,
+            primitive_count: aabbs.len() as u32,
+        },
+    ))
+}
+
+// Record the BLAS builds, followed by a barrier so that the TLAS build recorded next sees them.
+pub unsafe fn record_blas_builds(
+    device: &ash::Device,
+    acceleration_structure_loader: &ash::extensions::khr::AccelerationStructure,
+    command_buffer: vk::CommandBuffer,
+    builds: &[PendingBlasBuild],
+) {
+    if builds.is_empty() {
+        return;
+    }
+    let build_geometry_infos: Vec<vk::AccelerationStructureBuildGeometryInfoKHR> = builds
+        .iter()
+        .map(|build| {
+            let mut info = vk::AccelerationStructureBuildGeometryInfoKHR::builder()
+                .ty(vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL)
+                .flags(vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE)
+                .mode(vk::BuildAccelerationStructureModeKHR::BUILD)
+                .geometries(std::slice::from_ref(&build.geometry))
+                .dst_acceleration_structure(build.dst)
+                .build();
+            info.scratch_data.device_address = build.scratch_device_address;
+            info
+        })
+        .collect();
+    let build_ranges: Vec<[vk::AccelerationStructureBuildRangeInfoKHR; 1]> = builds
+        .iter()
+        .map(|build| {
+            [vk::AccelerationStructureBuildRangeInfoKHR {
+                primitive_count: build.primitive_count,
+                primitive_offset: 0,
+                first_vertex: 0,
+                transform_offset: 0,
+            }]
+        })
+        .collect();
+    let build_range_refs: Vec<&[vk::AccelerationStructureBuildRangeInfoKHR]> =
+        build_ranges.iter().map(|range| &range[..]).collect();
+    acceleration_structure_loader.cmd_build_acceleration_structures(
+        command_buffer,
+        &build_geometry_infos,
+        &build_range_refs,
+    );
+    device.cmd_pipeline_barrier(
+        command_buffer,
+        vk::PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_KHR,
+        vk::PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_KHR,
+        vk::DependencyFlags::empty(),
+        &[vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::ACCELERATION_STRUCTURE_WRITE_KHR)
+            .dst_access_mask(vk::AccessFlags::ACCELERATION_STRUCTURE_READ_KHR)
+            .build()],
+        &[],
+        &[],
+    );
+}
+
+// Free a BLAS that's no longer referenced by any pending build.
+pub fn collect_blas(garbage_bin: &mut GarbageBin, blas: Blas) {
+    garbage_bin.collect(Garbage::AccelerationStructure(blas.acceleration_structure));
+    garbage_bin.collect_buffer(blas.buf, Some(blas.mem));
+    garbage_bin.collect_buffer(blas.aabb_buf, Some(blas.aabb_mem));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::brick_aabbs;
+    use crate::{Svdag, VoxelModel};
+
+    #[test]
+    fn test_brick_aabbs() {
+        let mut svdag = Svdag::potato();
+        let mut grid = svdag.get_grid_accessor_mut(3, 0);
+        grid.set(1, 2, 3, true).unwrap();
+        grid.set(7, 7, 7, true).unwrap();
+        let model = VoxelModel::new(svdag, 3);
+
+        let aabbs = brick_aabbs(&model, 1);
+        assert_eq!(aabbs.len(), 2);
+        assert_eq!(
+            (aabbs[0].min_x, aabbs[0].min_y, aabbs[0].min_z),
+            (0.0, 0.0, 0.0)
+        );
+        assert_eq!(
+            (aabbs[0].max_x, aabbs[0].max_y, aabbs[0].max_z),
+            (0.5, 0.5, 0.5)
+        );
+        assert_eq!(
+            (aabbs[1].min_x, aabbs[1].min_y, aabbs[1].min_z),
+            (0.5, 0.5, 0.5)
+        );
+        assert_eq!(
+            (aabbs[1].max_x, aabbs[1].max_y, aabbs[1].max_z),
+            (1.0, 1.0, 1.0)
+        );
+
+        let aabbs = brick_aabbs(&model, 3);
+        assert_eq!(aabbs.len(), 2);
+        assert_eq!(
+            (aabbs[0].min_x, aabbs[0].max_x, aabbs[0].max_z),
+            (0.125, 0.25, 0.5)
+        );
+    }
+}
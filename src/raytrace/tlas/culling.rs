@@ -0,0 +1,232 @@
+use bevy::math::{Mat4, Quat, Vec2, Vec3};
+
+/// Insert this resource to leave instances far from the camera out of the TLAS.
+/// Without it, every `Raytraced` entity is in the TLAS.
+#[derive(Clone, Debug)]
+pub struct InstanceCulling {
+    /// Instances with bounds further than this from the camera are culled.
+    pub max_distance: Option<f32>,
+    /// Instances with bounds further than this outside of the view frustum are culled.
+    /// Instances just outside of the view still cast shadows into it, so keep some margin.
+    pub frustum_margin: Option<f32>,
+    /// Instances in the TLAS are only culled once they're this much further than the limits above,
+    /// so that instances near the limits don't flip in and out as the camera moves.
+    pub hysteresis: f32,
+}
+
+impl Default for InstanceCulling {
+    fn default() -> Self {
+        InstanceCulling {
+            max_distance: None,
+            frustum_margin: Some(16.0),
+            hysteresis: 4.0,
+        }
+    }
+}
+
+// The camera as seen by the culling.
+#[derive(Clone, Copy, Debug)]
+pub struct CullingView {
+    pub position: Vec3,
+    pub rotation: Quat,
+    pub forward: Vec3,
+    pub shape: ViewShape,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum ViewShape {
+    // The frustum of a perspective camera is approximated by the cone around it.
+    // Sine and cosine of the half angle of the cone.
+    Cone {
+        sin_half_angle: f32,
+        cos_half_angle: f32,
+    },
+    // The view of an orthographic camera is a box in front of the camera.
+    // Half of its width and height.
+    Box {
+        half_extent: Vec2,
+    },
+}
+
+impl CullingView {
+    pub fn new(position: Vec3, rotation: Quat, tan_half_fov: f32, aspect_ratio: f32) -> Self {
+        // Half angle through the corners of the frustum.
+        let tan_half_angle = tan_half_fov * (1.0 + aspect_ratio * aspect_ratio).sqrt();
+        let half_angle = tan_half_angle.atan();
+        CullingView {
+            position,
+            rotation,
+            forward: rotation * -Vec3::Z,
+            shape: ViewShape::Cone {
+                sin_half_angle: half_angle.sin(),
+                cos_half_angle: half_angle.cos(),
+            },
+        }
+    }
+    pub fn orthographic(
+        position: Vec3,
+        rotation: Quat,
+        half_height: f32,
+        aspect_ratio: f32,
+    ) -> Self {
+        CullingView {
+            position,
+            rotation,
+            forward: rotation * -Vec3::Z,
+            shape: ViewShape::Box {
+                half_extent: Vec2::new(half_height * aspect_ratio, half_height),
+            },
+        }
+    }
+}
+
+// Bounding sphere of an instance: the unit box transformed by the instance transform.
+pub fn instance_bounds(instance_transform: &Mat4) -> (Vec3, f32) {
+    let center = instance_transform.transform_point3(Vec3::splat(0.5));
+    let x = instance_transform.transform_vector3(Vec3::X);
+    let y = instance_transform.transform_vector3(Vec3::Y);
+    let z = instance_transform.transform_vector3(Vec3::Z);
+    // The longest of the diagonals of the box, which is sheared by non-uniform scales.
+    let diagonal = [x + y + z, x + y - z, x - y + z, x - y - z]
+        .iter()
+        .map(|diagonal| diagonal.length())
+        .fold(0.0, f32::max);
+    (center, diagonal * 0.5)
+}
+
+// Whether an instance with the bounding sphere (center, radius) should be in the TLAS.
+// `was_visible` is whether it was in the last TLAS build.
+pub fn is_instance_visible(
+    view: &CullingView,
+    settings: &InstanceCulling,
+    center: Vec3,
+    radius: f32,
+    was_visible: bool,
+) -> bool {
+    let slack = if was_visible {
+        settings.hysteresis
+    } else {
+        0.0
+    };
+    let to_center = center - view.position;
+    if let Some(max_distance) = settings.max_distance {
+        if to_center.length() - radius > max_distance + slack {
+            return false;
+        }
+    }
+    if let Some(frustum_margin) = settings.frustum_margin {
+        let outside = match view.shape {
+            ViewShape::Cone {
+                sin_half_angle,
+                cos_half_angle,
+            } => {
+                // Distance from the center to the surface of the cone. This underestimates
+                // the distance for points behind the camera, so the test stays conservative.
+                let along = to_center.dot(view.forward);
+                let across = (to_center - along * view.forward).length();
+                across * cos_half_angle - along * sin_half_angle
+            }
+            ViewShape::Box { half_extent } => {
+                // The largest distance to the planes of the box underestimates the distance
+                // to the box as well.
+                let local = view.rotation.inverse() * to_center;
+                (local.x.abs() - half_extent.x)
+                    .max(local.y.abs() - half_extent.y)
+                    .max(local.z)
+            }
+        };
+        if outside - radius > frustum_margin + slack {
+            return false;
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{instance_bounds, is_instance_visible, CullingView, InstanceCulling};
+    use bevy::math::{Mat4, Quat, Vec3};
+
+    #[test]
+    fn test_instance_bounds() {
+        let transform = Mat4::from_scale_rotation_translation(
+            Vec3::new(2.0, 4.0, 2.0),
+            Quat::IDENTITY,
+            Vec3::new(1.0, 0.0, 0.0),
+        );
+        let (center, radius) = instance_bounds(&transform);
+        assert_eq!(center, Vec3::new(2.0, 2.0, 1.0));
+        // The sphere contains the corners of the box.
+        assert!(radius >= Vec3::new(1.0, 2.0, 1.0).length());
+    }
+
+    #[test]
+    fn test_distance_culling() {
+        // Looking down -Z with a 90 degree field of view.
+        let view = CullingView::new(Vec3::ZERO, Quat::IDENTITY, 1.0, 1.0);
+        let settings = InstanceCulling {
+            max_distance: Some(100.0),
+            frustum_margin: None,
+            hysteresis: 10.0,
+        };
+        let visible = |z: f32, was_visible: bool| {
+            is_instance_visible(&view, &settings, Vec3::new(0.0, 0.0, z), 1.0, was_visible)
+        };
+        assert!(visible(-50.0, false));
+        assert!(visible(-100.0, false));
+        // Culled instances come back at the limit.
+        assert!(!visible(-105.0, false));
+        // Visible instances stay until they're past the hysteresis.
+        assert!(visible(-105.0, true));
+        assert!(!visible(-112.0, true));
+        // Without frustum culling, direction doesn't matter.
+        assert!(visible(50.0, false));
+    }
+
+    #[test]
+    fn test_frustum_culling() {
+        let view = CullingView::new(Vec3::ZERO, Quat::IDENTITY, 1.0, 1.0);
+        let settings = InstanceCulling {
+            max_distance: None,
+            frustum_margin: Some(2.0),
+            hysteresis: 3.0,
+        };
+        let visible = |center: Vec3, was_visible: bool| {
+            is_instance_visible(&view, &settings, center, 1.0, was_visible)
+        };
+        assert!(visible(Vec3::new(0.0, 0.0, -10.0), false));
+        // The corners of the frustum are inside of the cone.
+        assert!(visible(Vec3::new(10.0, 10.0, -10.0), false));
+        // The camera itself is always inside.
+        assert!(visible(Vec3::ZERO, false));
+        // Just outside of the margin.
+        assert!(!visible(Vec3::new(0.0, 0.0, 5.0), false));
+        assert!(visible(Vec3::new(0.0, 0.0, 5.0), true));
+        // Far behind the camera.
+        assert!(!visible(Vec3::new(0.0, 0.0, 100.0), true));
+        // Far to the side.
+        assert!(!visible(Vec3::new(0.0, 100.0, -10.0), true));
+    }
+
+    #[test]
+    fn test_orthographic_culling() {
+        // Looking down -Z at a view 20 wide and 10 high.
+        let view = CullingView::orthographic(Vec3::ZERO, Quat::IDENTITY, 5.0, 2.0);
+        let settings = InstanceCulling {
+            max_distance: None,
+            frustum_margin: Some(2.0),
+            hysteresis: 3.0,
+        };
+        let visible = |center: Vec3, was_visible: bool| {
+            is_instance_visible(&view, &settings, center, 1.0, was_visible)
+        };
+        assert!(visible(Vec3::new(0.0, 0.0, -100.0), false));
+        // The view doesn't widen with the distance.
+        assert!(visible(Vec3::new(12.0, 0.0, -100.0), false));
+        assert!(!visible(Vec3::new(14.0, 0.0, -100.0), false));
+        assert!(visible(Vec3::new(14.0, 0.0, -100.0), true));
+        assert!(!visible(Vec3::new(0.0, 9.0, -10.0), false));
+        // Behind the camera.
+        assert!(!visible(Vec3::new(0.0, 0.0, 4.0), false));
+    }
+}
@@ -0,0 +1,209 @@
+use bevy::asset::HandleId;
+use bevy::ecs::entity::Entity;
+
+// One instance of the TLAS, as written to the instance buffer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TlasInstance {
+    pub entity: Entity,
+    pub model: HandleId,
+    // Index of the model in the entity mapping table.
+    pub custom_index: u32,
+    pub mask: u8,
+    // Row major 3x4 matrix.
+    pub transform: [f32; 12],
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum InstanceDiff {
+    Unchanged,
+    // Only the transforms of the instances at these indices changed,
+    // so the TLAS can be refit instead of rebuilt.
+    Refit(Vec<usize>),
+    // Instances were added, removed, reordered, switched to another model or changed layers.
+    Rebuild,
+}
+
+// Compare the instances of the last TLAS build with the instances of this frame.
+pub fn diff_instances(old: &[TlasInstance], new: &[TlasInstance]) -> InstanceDiff {
+    if old.len() != new.len() {
+        return InstanceDiff::Rebuild;
+    }
+    let mut changed = Vec::new();
+    for (i, (old, new)) in old.iter().zip(new.iter()).enumerate() {
+        if old.entity != new.entity
+            || old.model != new.model
+            || old.custom_index != new.custom_index
+            || old.mask != new.mask
+        {
+            return InstanceDiff::Rebuild;
+        }
+        if old.transform != new.transform {
+            changed.push(i);
+        }
+    }
+    if changed.is_empty() {
+        InstanceDiff::Unchanged
+    } else {
+        InstanceDiff::Refit(changed)
+    }
+}
+
+// A refit keeps the hierarchy of the last full build, which gets worse as instances move.
+// A full build is forced after this many consecutive refits,
+pub const MAX_CONSECUTIVE_REFITS: u32 = 300;
+// or once the diagonal of the bounds of all instances grew by this factor since the last build.
+pub const MAX_REFIT_BOUNDS_GROWTH: f32 = 1.5;
+
+// Axis aligned bounds of the instances in world space. Each instance covers the unit cube.
+fn instance_bounds(instances: &[TlasInstance]) -> ([f32; 3], [f32; 3]) {
+    let mut min = [f32::INFINITY; 3];
+    let mut max = [f32::NEG_INFINITY; 3];
+    for instance in instances {
+        let m = &instance.transform;
+        for row in 0..3 {
+            let axes = &m[row * 4..row * 4 + 3];
+            let translation = m[row * 4 + 3];
+            min[row] = min[row].min(translation + axes.iter().map(|a| a.min(0.0)).sum::<f32>());
+            max[row] = max[row].max(translation + axes.iter().map(|a| a.max(0.0)).sum::<f32>());
+        }
+    }
+    (min, max)
+}
+
+fn diagonal((min, max): ([f32; 3], [f32; 3])) -> f32 {
+    (0..3)
+        .map(|i| (max[i] - min[i]).max(0.0).powi(2))
+        .sum::<f32>()
+        .sqrt()
+}
+
+// The refits made since the last full build of the TLAS.
+#[derive(Default)]
+pub struct RefitHistory {
+    num_refits: u32,
+    // Diagonal of the instance bounds at the last full build.
+    built_diagonal: Option<f32>,
+}
+
+impl RefitHistory {
+    // Turn a refit into a full build when the last build is too old or too far off.
+    // Records the result, so it has to be called once per TLAS update.
+    pub fn check(&mut self, diff: InstanceDiff, instances: &[TlasInstance]) -> InstanceDiff {
+        let diff = match (diff, self.built_diagonal) {
+            (InstanceDiff::Refit(changed), Some(built_diagonal))
+                if self.num_refits < MAX_CONSECUTIVE_REFITS
+                    && diagonal(instance_bounds(instances))
+                        <= built_diagonal * MAX_REFIT_BOUNDS_GROWTH =>
+            {
+                self.num_refits += 1;
+                return InstanceDiff::Refit(changed);
+            }
+            (InstanceDiff::Unchanged, _) => return InstanceDiff::Unchanged,
+            _ => InstanceDiff::Rebuild,
+        };
+        self.num_refits = 0;
+        self.built_diagonal = Some(diagonal(instance_bounds(instances)));
+        diff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff_instances, InstanceDiff, RefitHistory, TlasInstance, MAX_CONSECUTIVE_REFITS};
+    use crate::VoxelModel;
+    use bevy::asset::HandleId;
+    use bevy::ecs::entity::Entity;
+
+    fn instance(entity: u32, model: HandleId, custom_index: u32, x: f32) -> TlasInstance {
+        TlasInstance {
+            entity: Entity::new(entity),
+            model,
+            custom_index,
+            mask: 0xFF,
+            transform: [1.0, 0.0, 0.0, x, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0],
+        }
+    }
+
+    #[test]
+    fn test_diff_instances() {
+        let castle = HandleId::random::<VoxelModel>();
+        let tank = HandleId::random::<VoxelModel>();
+        let old = vec![
+            instance(0, castle, 0, 0.0),
+            instance(1, tank, 1, 0.0),
+            instance(2, tank, 1, 5.0),
+        ];
+
+        assert_eq!(diff_instances(&old, &old.clone()), InstanceDiff::Unchanged);
+        assert_eq!(diff_instances(&[], &[]), InstanceDiff::Unchanged);
+
+        let mut moved = old.clone();
+        moved[1].transform[3] = 2.0;
+        moved[2].transform[3] = 7.0;
+        assert_eq!(
+            diff_instances(&old, &moved),
+            InstanceDiff::Refit(vec![1, 2])
+        );
+
+        // Added and removed instances.
+        let mut added = old.clone();
+        added.push(instance(3, castle, 0, 1.0));
+        assert_eq!(diff_instances(&old, &added), InstanceDiff::Rebuild);
+        assert_eq!(diff_instances(&old, &old[..2]), InstanceDiff::Rebuild);
+
+        // Same number of instances, but a different entity.
+        let mut replaced = old.clone();
+        replaced[2] = instance(4, tank, 1, 5.0);
+        assert_eq!(diff_instances(&old, &replaced), InstanceDiff::Rebuild);
+
+        // An entity switched to another model.
+        let mut switched = old.clone();
+        switched[1] = instance(1, castle, 0, 0.0);
+        assert_eq!(diff_instances(&old, &switched), InstanceDiff::Rebuild);
+
+        // An entity moved to other visibility layers.
+        let mut hidden = old.clone();
+        hidden[0].mask = 0x01;
+        assert_eq!(diff_instances(&old, &hidden), InstanceDiff::Rebuild);
+    }
+
+    #[test]
+    fn test_refit_history() {
+        let castle = HandleId::random::<VoxelModel>();
+        let mut history = RefitHistory::default();
+        let built = vec![instance(0, castle, 0, 0.0), instance(1, castle, 0, 2.0)];
+        assert_eq!(
+            history.check(InstanceDiff::Rebuild, &built),
+            InstanceDiff::Rebuild
+        );
+
+        // Small moves are refit until too many refits were made in a row.
+        let mut moved = built.clone();
+        moved[1].transform[3] = 2.5;
+        for _ in 0..MAX_CONSECUTIVE_REFITS {
+            assert_eq!(
+                history.check(InstanceDiff::Refit(vec![1]), &moved),
+                InstanceDiff::Refit(vec![1])
+            );
+        }
+        assert_eq!(
+            history.check(InstanceDiff::Refit(vec![1]), &moved),
+            InstanceDiff::Rebuild
+        );
+        assert_eq!(
+            history.check(InstanceDiff::Unchanged, &moved),
+            InstanceDiff::Unchanged
+        );
+        assert_eq!(
+            history.check(InstanceDiff::Refit(vec![1]), &moved),
+            InstanceDiff::Refit(vec![1])
+        );
+
+        // Instances moving apart grow the bounds past the threshold.
+        moved[1].transform[3] = 10.0;
+        assert_eq!(
+            history.check(InstanceDiff::Refit(vec![1]), &moved),
+            InstanceDiff::Rebuild
+        );
+    }
+}
@@ -1,20 +1,99 @@
+mod blas;
+mod culling;
+mod instances;
 mod state;
 mod uniform;
 use crate::render::{Garbage, GarbageBin, RenderState};
 use ash::vk;
-use bevy::{ecs::system::SystemState, prelude::*, utils::HashMap};
+use bevy::{
+    asset::HandleId,
+    ecs::system::SystemState,
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
 pub use state::TlasState;
-use std::mem::MaybeUninit;
 pub use uniform::UniformArray;
 
 use crate::render::{RenderStage, RenderWorld};
+pub use blas::ModelBlasSettings;
+use blas::PendingBlasBuild;
+pub use culling::InstanceCulling;
 use gpu_alloc_ash::AshMemoryDevice;
+use instances::{diff_instances, InstanceDiff, RefitHistory, TlasInstance};
 
-use crate::{device_info::DeviceInfo, raytrace::tlas::uniform::UniformEntry, Queues, VoxelModel};
+use crate::{
+    camera::Projection, device_info::DeviceInfo, raytrace::tlas::uniform::UniformEntry,
+    raytrace::AnimationFrame, raytrace::VisibilityLayers, Queues, VoxelModel,
+};
 #[derive(Debug)]
 pub struct Raytraced {
     pub aabb_extent: bevy::math::Vec3,
+    /// When set, `aabb_extent` follows the grid of the model, with each voxel spanning
+    /// `voxel_size` world units.
+    pub voxel_size: Option<f32>,
 }
+
+impl Raytraced {
+    pub fn with_extent(aabb_extent: bevy::math::Vec3) -> Self {
+        Raytraced {
+            aabb_extent,
+            voxel_size: None,
+        }
+    }
+    /// The extent is derived from the model once it is loaded, and again whenever it changes.
+    pub fn with_voxel_size(voxel_size: f32) -> Self {
+        Raytraced {
+            aabb_extent: bevy::math::Vec3::ZERO,
+            voxel_size: Some(voxel_size),
+        }
+    }
+}
+
+// Label of `update_raytraced_extents`, for systems reading the extents in the same stage.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub(crate) struct RaytracedExtentsSystem;
+
+pub(crate) fn update_raytraced_extents(
+    voxel_models: Res<Assets<crate::VoxelModel>>,
+    mut query: Query<(&mut Raytraced, &Handle<crate::VoxelModel>)>,
+) {
+    for (mut raytraced, model_handle) in query.iter_mut() {
+        let voxel_size = match raytraced.voxel_size {
+            Some(voxel_size) => voxel_size,
+            None => continue,
+        };
+        let model = match voxel_models.get(model_handle) {
+            Some(model) => model,
+            None => continue,
+        };
+        let extent = bevy::math::Vec3::splat(model.grid_size() as f32 * voxel_size);
+        // Only write on changes, so that the TLAS doesn't get rebuilt every frame.
+        if raytraced.aabb_extent != extent {
+            raytraced.aabb_extent = extent;
+        }
+    }
+}
+
+// Transform of the unit box of an instance. We use the same unit box BLAS for all instances,
+// so we change the shape of the unit box by streching it. The BLAS of a model spans the same unit box.
+fn instance_transform(transform: &GlobalTransform, aabb: &Raytraced) -> Mat4 {
+    Mat4::from_scale_rotation_translation(
+        transform.scale * aabb.aabb_extent,
+        transform.rotation,
+        transform.translation,
+    )
+}
+
+// The entry of an animation frame of a model in the entity mapping table.
+fn uniform_entry(model: &VoxelModel, frame: u32, blas: Option<&blas::Blas>) -> UniformEntry {
+    UniformEntry {
+        device: uniform::DeviceAddress(model.svdag.arena.get_buffer_device_address()),
+        parent: model.svdag.get_roots()[frame as usize].get_value(),
+        brick_aabbs: uniform::DeviceAddress(blas.map_or(0, |blas| blas.aabb_device_address)),
+        padding: uniform::DeviceAddress(0),
+    }
+}
+
 #[derive(Default)]
 pub struct TlasPlugin;
 
@@ -300,6 +379,18 @@ fn tlas_setup(app: &mut App) {
             fence,
             have_updates_pending: true,
             needs_update_next_frame: false,
+            model_indices: HashMap::default(),
+            instances: Vec::new(),
+            needs_rebuild: false,
+            refits: RefitHistory::default(),
+            retired_tlas: None,
+            model_blases: HashMap::default(),
+            dirty_blases: HashSet::default(),
+            blas_brick_depth: None,
+            blas_transient: Vec::new(),
+            retired_blases: Vec::new(),
+            culled: HashSet::default(),
+            host_only_models: HashSet::default(),
         };
         app.insert_resource(tlas_state);
     }
@@ -315,9 +406,27 @@ fn tlas_update(
             Changed<GlobalTransform>,
             Changed<Raytraced>,
             Changed<Handle<crate::VoxelModel>>,
+            Changed<VisibilityLayers>,
+            Changed<AnimationFrame>,
         )>,
     >,
-    entities_query: Query<(&GlobalTransform, &Raytraced, &Handle<crate::VoxelModel>)>,
+    entities_query: Query<(
+        Entity,
+        &GlobalTransform,
+        &Raytraced,
+        &Handle<crate::VoxelModel>,
+        Option<&VisibilityLayers>,
+        Option<&AnimationFrame>,
+    )>,
+    blas_settings: Option<Res<ModelBlasSettings>>,
+    culling: Option<Res<InstanceCulling>>,
+    cameras: Query<(
+        Option<&crate::PerspectiveCamera>,
+        Option<&crate::OrthographicCamera>,
+        &GlobalTransform,
+        Option<&crate::Viewport>,
+    )>,
+    windows: Res<bevy::window::Windows>,
 ) {
     let render_world = &mut *render_world;
     let (
@@ -343,148 +452,355 @@ fn tlas_update(
     )>::new(render_world)
     .get_mut(render_world);
 
+    // Edited and hot reloaded models only need their entries in the entity mapping table refreshed.
+    // The blocks of a replaced model are retired through the garbage bin by its block allocator.
+    let mut models_changed = false;
+    let mut models_modified: Vec<HandleId> = Vec::new();
+    for event in voxel_model_events.iter() {
+        match event {
+            AssetEvent::Modified { handle } => models_modified.push(handle.id),
+            _ => models_changed = true,
+        }
+    }
+    for id in models_modified {
+        if state.model_blases.contains_key(&id) {
+            state.dirty_blases.insert(id);
+            models_changed = true;
+        }
+        state.host_only_models.remove(&id);
+        let model = match voxel_models.get(id) {
+            Some(model) => model,
+            None => continue,
+        };
+        if !model.has_device_buffer() {
+            // A host-only replacement has to drop out of the TLAS.
+            models_changed |= state
+                .model_indices
+                .keys()
+                .any(|(model_id, _)| *model_id == id);
+            continue;
+        }
+        for (&(_, frame), &index) in state
+            .model_indices
+            .iter()
+            .filter(|((model_id, _), _)| *model_id == id)
+        {
+            if frame >= model.num_frames() {
+                // The reloaded model has fewer frames, so instances have to move to other entries.
+                models_changed = true;
+                continue;
+            }
+            // The old BLAS stays in use until the next build, and so do its bricks.
+            let entry = uniform_entry(model, frame, state.model_blases.get(&id));
+            unsafe {
+                uniform_arr.write_entry(index, entry);
+            }
+        }
+    }
+
+    let brick_depth = blas_settings.map(|settings| settings.brick_depth);
+    if brick_depth != state.blas_brick_depth {
+        state.blas_brick_depth = brick_depth;
+        let models: Vec<HandleId> = state.model_blases.keys().cloned().collect();
+        state.dirty_blases.extend(models);
+        models_changed = true;
+    }
+    if models_changed {
+        // Models were loaded, unloaded or got a new BLAS,
+        // so the entity mapping table and the instances have to be rewritten.
+        state.needs_rebuild = true;
+    }
+    // Entities left out of the TLAS this frame. All cameras share the TLAS,
+    // so only instances that no camera can see are culled.
+    let culling_views: Vec<culling::CullingView> = cameras
+        .iter()
+        .filter_map(|(perspective, orthographic, transform, viewport)| {
+            let viewport = viewport.cloned().unwrap_or_default();
+            let aspect_ratio = windows.get(viewport.window).map_or(1.0, |window| {
+                (viewport.size.x * window.width()) / (viewport.size.y * window.height())
+            });
+            let (position, rotation) = (transform.translation, transform.rotation);
+            let view = match Projection::from_components(perspective, orthographic)? {
+                Projection::Perspective { tan_half_fov, .. } => {
+                    culling::CullingView::new(position, rotation, tan_half_fov, aspect_ratio)
+                }
+                Projection::Orthographic { half_height, .. } => culling::CullingView::orthographic(
+                    position,
+                    rotation,
+                    half_height,
+                    aspect_ratio,
+                ),
+            };
+            Some(view)
+        })
+        .collect();
+    let culled: HashSet<Entity> = match &culling {
+        Some(settings) if !culling_views.is_empty() => entities_query
+            .iter()
+            .filter(|(entity, transform, aabb, _, _, _)| {
+                let (center, radius) =
+                    culling::instance_bounds(&instance_transform(transform, aabb));
+                let was_visible = !state.culled.contains(entity);
+                !culling_views.iter().any(|view| {
+                    culling::is_instance_visible(view, settings, center, radius, was_visible)
+                })
+            })
+            .map(|(entity, _, _, _, _, _)| entity)
+            .collect(),
+        _ => HashSet::default(),
+    };
+    let culling_changed = culled != state.culled;
+
     let have_updates_this_frame =
-        !anything_changed_query.is_empty() || voxel_model_events.iter().next().is_some(); // have updates this frame
-    if !state.should_update(&device, &mut allocator, have_updates_this_frame) {
+        !anything_changed_query.is_empty() || models_changed || culling_changed; // have updates this frame
+    let should_update = state.should_update(&device, &mut garbage_bin, have_updates_this_frame);
+    if !state.have_updates_pending {
+        // The last refit has finished reading the TLAS it replaced.
+        if let Some((tlas, tlas_buf, tlas_mem)) = state.retired_tlas.take() {
+            garbage_bin.collect(Garbage::AccelerationStructure(tlas));
+            garbage_bin.collect_buffer(tlas_buf, tlas_mem);
+        }
+        // The last TLAS build no longer references these.
+        for blas in std::mem::take(&mut state.retired_blases) {
+            blas::collect_blas(&mut garbage_bin, blas);
+        }
+    }
+    if !should_update {
         return;
     }
+    state.culled = culled;
 
-    let mut models_in_use: Vec<Handle<VoxelModel>> = Vec::new();
-    let mut model_to_index: HashMap<Handle<VoxelModel>, u32> = HashMap::default();
+    // Each animation frame of a model in use gets its own entry in the entity mapping table.
+    let mut models_in_use: Vec<(Handle<VoxelModel>, u32)> = Vec::new();
+    let mut model_to_index: HashMap<(HandleId, u32), u32> = HashMap::default();
+    // Models built in host memory have no device buffer to trace against.
+    for (_, _, _, model_handle, _, _) in entities_query.iter() {
+        let host_only = voxel_models
+            .get(model_handle)
+            .map_or(false, |model| !model.has_device_buffer());
+        if host_only && state.host_only_models.insert(model_handle.id) {
+            warn!(
+                "Voxel model {:?} is in host memory and won't be rendered. Upload it with VoxelModel::upload first.",
+                model_handle.id
+            );
+        }
+    }
     // do updates
-    let data: Vec<_> = entities_query
+    let instances: Vec<TlasInstance> = entities_query
         .iter()
-        // Make sure that the model was loaded
-        .filter(|(_, _, model)| voxel_models.get(*model).is_some())
-        .map(|(transform, aabb, model_handle)| {
+        // Make sure that the model was loaded into device memory
+        .filter(|(_, _, _, model, _, _)| {
+            voxel_models
+                .get(*model)
+                .map_or(false, |model| model.has_device_buffer())
+        })
+        .filter(|(entity, _, _, _, _, _)| !state.culled.contains(entity))
+        .map(|(entity, transform, aabb, model_handle, layers, frame)| {
+            let frame = AnimationFrame::clamp(frame, voxel_models.get(model_handle).unwrap());
             let custom_index: u32 =
-                if let Some(index) = model_to_index.get(&Handle::weak(model_handle.id)) {
+                if let Some(index) = model_to_index.get(&(model_handle.id, frame)) {
                     *index
                 } else {
                     let index = models_in_use.len() as u32;
-                    models_in_use.push(Handle::weak(model_handle.id));
-                    model_to_index.insert(Handle::weak(model_handle.id), index);
+                    models_in_use.push((Handle::weak(model_handle.id), frame));
+                    model_to_index.insert((model_handle.id, frame), index);
                     index
                 };
             assert_eq!(custom_index & !0xFFFFFF, 0, "Index Overflow");
-            // We use the same unit box BLAS for all instances. So, we change the shape of the unit box by streching it.
-            let scale = transform.scale * aabb.aabb_extent;
-            let mat = Mat4::from_scale_rotation_translation(
-                scale,
-                transform.rotation,
-                transform.translation,
-            );
-            let mat = mat.transpose().to_cols_array();
-
-            let mask: u8 = 0xFF;
-            println!("data is {:?}, custom index is {}", aabb, custom_index);
-            unsafe {
-                let mut instance = vk::AccelerationStructureInstanceKHR {
-                    transform: vk::TransformMatrixKHR {
-                        matrix: MaybeUninit::uninit().assume_init(),
-                    },
-                    instance_custom_index_and_mask: ((mask as u32) << 24)
-                        | (custom_index & 0xFFFFFF),
-                    instance_shader_binding_table_record_offset_and_flags: 0,
-                    acceleration_structure_reference: vk::AccelerationStructureReferenceKHR {
-                        device_handle: state.unit_box_as_device_address,
-                    },
-                };
-                instance.transform.matrix.copy_from_slice(&mat[0..12]);
-                instance
-            }
+            let mat = instance_transform(transform, aabb)
+                .transpose()
+                .to_cols_array();
+            let mut instance = TlasInstance {
+                entity,
+                model: model_handle.id,
+                custom_index,
+                mask: layers.cloned().unwrap_or_default().0,
+                transform: [0.0; 12],
+            };
+            instance.transform.copy_from_slice(&mat[0..12]);
+            instance
         })
         .collect();
-    if data.len() == 0 {
+    if instances.len() == 0 {
         // No entity exist in the scene.
-        unsafe {
-            // TODO: figure out a way to delete the AS
-            if state.tlas_buf != vk::Buffer::null() {
-                device.destroy_buffer(state.tlas_buf, None);
-                state.tlas_buf = vk::Buffer::null();
-            }
-            if let Some(mem) = state.tlas_mem.take() {
-                allocator.dealloc(AshMemoryDevice::wrap(&*device), mem);
-            }
-        }
-        debug_assert!(state.tlas_mem.is_none());
-        debug_assert_eq!(state.tlas_buf, vk::Buffer::null());
         println!(
             "Deleted TLAS, before {:?}, after {:?}",
             state.tlas,
             vk::AccelerationStructureKHR::null()
         );
-        state.tlas = vk::AccelerationStructureKHR::null();
-        state.tlas_buf = vk::Buffer::null();
-        state.tlas_mem = None;
+        state.free_tlas(&mut garbage_bin);
+        state.free_instance_buffer(&mut garbage_bin);
+        state.instances.clear();
+        state.needs_rebuild = false;
         return;
     }
 
-    unsafe {
-        // Update the entity mapping table.
-        uniform_arr.write(
-            models_in_use.iter().map(|handle| {
-                let model = voxel_models.get(handle).unwrap(); // We already made sure that the model was loaded.
-                UniformEntry {
-                    device: uniform::DeviceAddress(model.svdag.arena.get_buffer_device_address()),
-                    parent: model.svdag.get_roots()[0].get_value(),
+    let diff = if state.needs_rebuild || state.tlas == vk::AccelerationStructureKHR::null() {
+        InstanceDiff::Rebuild
+    } else {
+        diff_instances(&state.instances, &instances)
+    };
+    let diff = state.refits.check(diff, &instances);
+    let mode = match diff {
+        InstanceDiff::Unchanged => return,
+        InstanceDiff::Refit(_) => vk::BuildAccelerationStructureModeKHR::UPDATE,
+        InstanceDiff::Rebuild => vk::BuildAccelerationStructureModeKHR::BUILD,
+    };
+
+    let mut blas_builds: Vec<PendingBlasBuild> = Vec::new();
+    if mode == vk::BuildAccelerationStructureModeKHR::BUILD {
+        // Retire the BLAS of models that changed or are no longer in use.
+        let retired: Vec<HandleId> = state
+            .model_blases
+            .keys()
+            .filter(|id| {
+                state.dirty_blases.contains(id)
+                    || !models_in_use.iter().any(|(handle, _)| handle.id == **id)
+            })
+            .cloned()
+            .collect();
+        for id in retired {
+            let blas = state.model_blases.remove(&id).unwrap();
+            state.retired_blases.push(blas);
+        }
+        state.dirty_blases.clear();
+        if let Some(brick_depth) = state.blas_brick_depth {
+            let mut visited: HashSet<HandleId> = HashSet::default();
+            for (handle, _) in models_in_use.iter() {
+                if state.model_blases.contains_key(&handle.id) || !visited.insert(handle.id) {
+                    continue;
                 }
-            }),
-            &device,
-            &mut allocator,
-        );
+                let model = voxel_models.get(handle).unwrap();
+                let aabbs = blas::brick_aabbs(model, brick_depth);
+                let state = &mut *state;
+                let created = unsafe {
+                    blas::create_model_blas(
+                        &device,
+                        &mut allocator,
+                        &acceleration_structure_loader,
+                        &device_info,
+                        &aabbs,
+                        &mut state.blas_transient,
+                    )
+                };
+                // Empty models keep using the unit box.
+                if let Some((blas, build)) = created {
+                    state.model_blases.insert(handle.id, blas);
+                    blas_builds.push(build);
+                }
+            }
+        }
     }
+    let data: Vec<vk::AccelerationStructureInstanceKHR> = instances
+        .iter()
+        .map(|instance| {
+            let mask: u8 = instance.mask;
+            vk::AccelerationStructureInstanceKHR {
+                transform: vk::TransformMatrixKHR {
+                    matrix: instance.transform,
+                },
+                instance_custom_index_and_mask: ((mask as u32) << 24)
+                    | (instance.custom_index & 0xFFFFFF),
+                instance_shader_binding_table_record_offset_and_flags: 0,
+                acceleration_structure_reference: vk::AccelerationStructureReferenceKHR {
+                    device_handle: state
+                        .model_blases
+                        .get(&instance.model)
+                        .map_or(state.unit_box_as_device_address, |blas| blas.device_address),
+                },
+            }
+        })
+        .collect();
+    let instance_size = std::mem::size_of::<vk::AccelerationStructureInstanceKHR>();
 
-    println!("models in use are {:?}", models_in_use);
-    let data_device_addr = unsafe {
-        let data_buf = device
-            .create_buffer(
-                &vk::BufferCreateInfo::builder()
-                    .size(std::mem::size_of_val(&data) as u64)
-                    .usage(
-                        vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR
-                            | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+    let data_device_addr = match diff {
+        InstanceDiff::Refit(changed) => unsafe {
+            // Only rewrite the instances that moved.
+            let data_mem = state.tlas_data_mem.as_mut().unwrap();
+            for i in changed {
+                data_mem
+                    .write_bytes(
+                        AshMemoryDevice::wrap(&*device),
+                        (i * instance_size) as u64,
+                        std::slice::from_raw_parts(
+                            &data[i] as *const _ as *const u8,
+                            instance_size,
+                        ),
                     )
-                    .sharing_mode(vk::SharingMode::EXCLUSIVE)
+                    .unwrap();
+            }
+            device.get_buffer_device_address(
+                &vk::BufferDeviceAddressInfo::builder()
+                    .buffer(state.tlas_data_buf)
                     .build(),
-                None,
-            )
-            .unwrap();
-        let data_buf_requirements = device.get_buffer_memory_requirements(data_buf);
-        let mut data_buf_mem = allocator
-            .alloc(
-                AshMemoryDevice::wrap(&device),
-                gpu_alloc::Request {
-                    size: data_buf_requirements.size,
-                    align_mask: data_buf_requirements.alignment,
-                    usage: gpu_alloc::UsageFlags::UPLOAD,
-                    memory_types: data_buf_requirements.memory_type_bits,
-                },
             )
-            .unwrap();
-        device
-            .bind_buffer_memory(data_buf, *data_buf_mem.memory(), data_buf_mem.offset())
-            .unwrap();
-        data_buf_mem
-            .write_bytes(
-                AshMemoryDevice::wrap(&*device),
-                0,
-                std::slice::from_raw_parts(
-                    data.as_slice() as *const _ as *const u8,
-                    std::mem::size_of_val(data.as_slice()),
-                ),
+        },
+        _ => unsafe {
+            // Update the entity mapping table.
+            uniform_arr.write(
+                models_in_use.iter().map(|(handle, frame)| {
+                    let model = voxel_models.get(handle).unwrap(); // We already made sure that the model was loaded.
+                    uniform_entry(model, *frame, state.model_blases.get(&handle.id))
+                }),
+                &device,
+                &mut allocator,
+                &mut garbage_bin,
+            );
+            state.model_indices = model_to_index;
+            println!("models in use are {:?}", models_in_use);
+
+            state.free_instance_buffer(&mut garbage_bin);
+            let data_buf = device
+                .create_buffer(
+                    &vk::BufferCreateInfo::builder()
+                        .size(std::mem::size_of_val(data.as_slice()) as u64)
+                        .usage(
+                            vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR
+                                | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+                        )
+                        .sharing_mode(vk::SharingMode::EXCLUSIVE)
+                        .build(),
+                    None,
+                )
+                .unwrap();
+            let data_buf_requirements = device.get_buffer_memory_requirements(data_buf);
+            let mut data_buf_mem = allocator
+                .alloc(
+                    AshMemoryDevice::wrap(&device),
+                    gpu_alloc::Request {
+                        size: data_buf_requirements.size,
+                        align_mask: data_buf_requirements.alignment,
+                        usage: gpu_alloc::UsageFlags::UPLOAD,
+                        memory_types: data_buf_requirements.memory_type_bits,
+                    },
+                )
+                .unwrap();
+            device
+                .bind_buffer_memory(data_buf, *data_buf_mem.memory(), data_buf_mem.offset())
+                .unwrap();
+            data_buf_mem
+                .write_bytes(
+                    AshMemoryDevice::wrap(&*device),
+                    0,
+                    std::slice::from_raw_parts(
+                        data.as_slice() as *const _ as *const u8,
+                        std::mem::size_of_val(data.as_slice()),
+                    ),
+                )
+                .unwrap();
+            debug_assert_eq!(state.tlas_data_buf, vk::Buffer::null());
+            debug_assert!(state.tlas_data_mem.is_none());
+            state.tlas_data_buf = data_buf;
+            state.tlas_data_mem = Some(data_buf_mem);
+            device.get_buffer_device_address(
+                &vk::BufferDeviceAddressInfo::builder()
+                    .buffer(data_buf)
+                    .build(),
             )
-            .unwrap();
-        debug_assert_eq!(state.tlas_data_buf, vk::Buffer::null());
-        debug_assert!(state.tlas_data_mem.is_none());
-        state.tlas_data_buf = data_buf;
-        state.tlas_data_mem = Some(data_buf_mem);
-        device.get_buffer_device_address(
-            &vk::BufferDeviceAddressInfo::builder()
-                .buffer(data_buf)
-                .build(),
-        )
+        },
     };
+    state.instances = instances;
+    state.needs_rebuild = false;
 
     let build_geometry = [vk::AccelerationStructureGeometryKHR::builder()
         .geometry_type(vk::GeometryTypeKHR::INSTANCES)
@@ -499,10 +815,19 @@ fn tlas_update(
         })
         .build()];
 
+    // Refits write into a new TLAS too, since frames in flight may still be reading the old one.
     let mut build_geometry_info = vk::AccelerationStructureBuildGeometryInfoKHR::builder()
         .ty(vk::AccelerationStructureTypeKHR::TOP_LEVEL)
-        .flags(vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE)
-        .mode(vk::BuildAccelerationStructureModeKHR::BUILD)
+        .flags(
+            vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE
+                | vk::BuildAccelerationStructureFlagsKHR::ALLOW_UPDATE,
+        )
+        .mode(mode)
+        .src_acceleration_structure(if mode == vk::BuildAccelerationStructureModeKHR::UPDATE {
+            state.tlas
+        } else {
+            vk::AccelerationStructureKHR::null()
+        })
         .geometries(&build_geometry)
         .build();
 
@@ -512,6 +837,11 @@ fn tlas_update(
             &build_geometry_info,
             &[data.len() as u32],
         );
+        let scratch_size = if mode == vk::BuildAccelerationStructureModeKHR::UPDATE {
+            sizes.update_scratch_size
+        } else {
+            sizes.build_scratch_size
+        };
         let scratch_alignment = device_info
             .acceleration_structure_properties
             .min_acceleration_structure_scratch_offset_alignment
@@ -520,7 +850,7 @@ fn tlas_update(
             .create_buffer(
                 &vk::BufferCreateInfo::builder()
                     .flags(vk::BufferCreateFlags::default())
-                    .size(sizes.build_scratch_size + scratch_alignment)
+                    .size(scratch_size + scratch_alignment)
                     .usage(
                         vk::BufferUsageFlags::STORAGE_BUFFER
                             | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
@@ -616,6 +946,12 @@ fn tlas_update(
                     .build(),
             )
             .unwrap();
+        blas::record_blas_builds(
+            &device,
+            &acceleration_structure_loader,
+            state.command_buffer,
+            &blas_builds,
+        );
         acceleration_structure_loader.cmd_build_acceleration_structures(
             state.command_buffer,
             &[build_geometry_info],
@@ -639,18 +975,14 @@ fn tlas_update(
             .unwrap();
 
         println!("We did it");
-        if state.tlas != vk::AccelerationStructureKHR::null() {
-            garbage_bin.collect(Garbage::AccelerationStructure(state.tlas));
+        if mode == vk::BuildAccelerationStructureModeKHR::UPDATE {
+            // The refit reads the old TLAS, so it has to stay alive until the build finishes.
+            debug_assert!(state.retired_tlas.is_none());
+            state.retired_tlas = Some((state.tlas, state.tlas_buf, state.tlas_mem.take()));
             state.tlas = vk::AccelerationStructureKHR::null();
-        }
-        // TODO: figure out a way to delete the AS
-        if state.tlas_buf != vk::Buffer::null() {
-            device.destroy_buffer(state.tlas_buf, None);
             state.tlas_buf = vk::Buffer::null();
         }
-        if let Some(mem) = state.tlas_mem.take() {
-            allocator.dealloc(AshMemoryDevice::wrap(&*device), mem);
-        }
+        state.free_tlas(&mut garbage_bin);
 
         debug_assert!(state.tlas_mem.is_none());
         debug_assert_eq!(state.tlas_buf, vk::Buffer::null());
@@ -1,5 +1,10 @@
+use super::blas::Blas;
+use super::instances::{RefitHistory, TlasInstance};
+use crate::render::{Garbage, GarbageBin};
 use ash::vk;
-use gpu_alloc_ash::AshMemoryDevice;
+use bevy::asset::HandleId;
+use bevy::ecs::entity::Entity;
+use bevy::utils::{HashMap, HashSet};
 
 pub struct TlasState {
     pub tlas: vk::AccelerationStructureKHR,
@@ -19,6 +24,33 @@ pub struct TlasState {
     pub(super) command_buffer: vk::CommandBuffer,
     pub(super) needs_update_next_frame: bool,
     pub(super) have_updates_pending: bool,
+    // Index of each model and animation frame in the entity mapping table,
+    // as of the last TLAS build.
+    pub(super) model_indices: HashMap<(HandleId, u32), u32>,
+    // The instances of the last TLAS build, in the order of the instance buffer.
+    pub(super) instances: Vec<TlasInstance>,
+    // Set when the entity mapping table needs to be rewritten with a full rebuild.
+    pub(super) needs_rebuild: bool,
+    pub(super) refits: RefitHistory,
+    // The TLAS replaced by a pending refit, which reads from it.
+    pub(super) retired_tlas: Option<(
+        vk::AccelerationStructureKHR,
+        vk::Buffer,
+        Option<crate::MemoryBlock>,
+    )>,
+    // The BLAS of each model, when `ModelBlasSettings` is present.
+    pub(super) model_blases: HashMap<HandleId, Blas>,
+    // Models whose BLAS has to be rebuilt with the next TLAS build.
+    pub(super) dirty_blases: HashSet<HandleId>,
+    pub(super) blas_brick_depth: Option<u8>,
+    // Scratch buffers of the pending BLAS builds.
+    pub(super) blas_transient: Vec<(vk::Buffer, crate::MemoryBlock)>,
+    // BLAS that were replaced, freed once the TLAS build that stopped using them is finished.
+    pub(super) retired_blases: Vec<Blas>,
+    // Entities left out of the last TLAS build by `InstanceCulling`.
+    pub(super) culled: HashSet<Entity>,
+    // Models in host memory only, which were left out and already warned about.
+    pub(super) host_only_models: HashSet<HandleId>,
     pub fence: vk::Fence,
 }
 
@@ -30,7 +62,7 @@ impl TlasState {
     pub fn should_update(
         &mut self,
         device: &ash::Device,
-        allocator: &mut crate::Allocator,
+        garbage_bin: &mut GarbageBin,
         have_updates_this_frame: bool,
     ) -> bool {
         let mut have_updates_pending = self.have_updates_pending;
@@ -46,29 +78,20 @@ impl TlasState {
                         .unwrap();
 
                     // Cleanup for Unit Box BLAS
-                    if self.unit_box_scratch_buf != vk::Buffer::null() {
-                        device.destroy_buffer(self.unit_box_scratch_buf, None);
-                        self.unit_box_scratch_buf = vk::Buffer::null();
-                    }
-                    if let Some(mem) = self.unit_box_scratch_mem.take() {
-                        allocator.dealloc(AshMemoryDevice::wrap(&*device), mem);
-                    }
+                    garbage_bin.collect_buffer(
+                        std::mem::replace(&mut self.unit_box_scratch_buf, vk::Buffer::null()),
+                        self.unit_box_scratch_mem.take(),
+                    );
 
-                    // Cleanup for TLAS
-                    if self.tlas_data_buf != vk::Buffer::null() {
-                        device.destroy_buffer(self.tlas_data_buf, None);
-                        self.tlas_data_buf = vk::Buffer::null();
-                    }
-                    if let Some(mem) = self.tlas_data_mem.take() {
-                        allocator.dealloc(AshMemoryDevice::wrap(&*device), mem);
-                    }
-                    if self.tlas_scratch_buf != vk::Buffer::null() {
-                        device.destroy_buffer(self.tlas_scratch_buf, None);
-                        self.tlas_scratch_buf = vk::Buffer::null();
-                    }
-                    if let Some(mem) = self.tlas_scratch_mem.take() {
-                        allocator.dealloc(AshMemoryDevice::wrap(&*device), mem);
+                    for (buf, mem) in self.blas_transient.drain(..) {
+                        garbage_bin.collect_buffer(buf, Some(mem));
                     }
+
+                    // Cleanup for TLAS. The instance buffer is kept around for refits.
+                    garbage_bin.collect_buffer(
+                        std::mem::replace(&mut self.tlas_scratch_buf, vk::Buffer::null()),
+                        self.tlas_scratch_mem.take(),
+                    );
                 }
             }
         }
@@ -88,4 +111,23 @@ impl TlasState {
     pub(super) fn did_updates(&mut self) {
         self.have_updates_pending = true;
     }
+    // Free the instance buffer. Only call this while no TLAS build is pending.
+    pub(super) fn free_instance_buffer(&mut self, garbage_bin: &mut GarbageBin) {
+        debug_assert!(!self.have_updates_pending);
+        garbage_bin.collect_buffer(
+            std::mem::replace(&mut self.tlas_data_buf, vk::Buffer::null()),
+            self.tlas_data_mem.take(),
+        );
+    }
+    // Free the TLAS. Only call this while no TLAS build is pending.
+    pub(super) fn free_tlas(&mut self, garbage_bin: &mut GarbageBin) {
+        garbage_bin.collect(Garbage::AccelerationStructure(std::mem::replace(
+            &mut self.tlas,
+            vk::AccelerationStructureKHR::null(),
+        )));
+        garbage_bin.collect_buffer(
+            std::mem::replace(&mut self.tlas_buf, vk::Buffer::null()),
+            self.tlas_mem.take(),
+        );
+    }
 }
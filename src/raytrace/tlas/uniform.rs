@@ -1,3 +1,4 @@
+use crate::render::GarbageBin;
 use ash::vk;
 use bevy::core::{Pod, Zeroable};
 use crevice::std140::{AsStd140, Std140, Std140Padded};
@@ -13,6 +14,10 @@ pub struct DeviceAddress(pub u64);
 pub struct UniformEntry {
     pub device: DeviceAddress,
     pub parent: u32,
+    // The brick AABBs of the model BLAS, or 0 for models using the unit box.
+    pub brick_aabbs: DeviceAddress,
+    // Pads the entry to a multiple of 16 bytes, so that the shaders read it with the same stride.
+    pub padding: DeviceAddress,
 }
 
 unsafe impl Std140 for DeviceAddress {
@@ -54,24 +59,21 @@ impl UniformArray {
         new_capacity: u32,
         device: &ash::Device,
         allocator: &mut crate::Allocator,
+        garbage_bin: &mut GarbageBin,
     ) {
-        // TODO: free the command buffer.
-        /*
-        if self.staging_buf != vk::Buffer::null() {
-            device.destroy_buffer(self.staging_buf, None);
-            self.staging_buf = vk::Buffer::null();
+        // The frames in flight may still copy from the old buffers.
+        let mut staging_mem = self.staging_mem.take();
+        if let Some(staging_mem) = staging_mem.as_mut() {
+            staging_mem.unmap(AshMemoryDevice::wrap(device));
         }
-        if self.device_buf != vk::Buffer::null() {
-            device.destroy_buffer(self.device_buf, None);
-            self.device_buf = vk::Buffer::null();
-        }
-        if let Some(staging_mem) = self.staging_mem.take() {
-            allocator.dealloc(AshMemoryDevice::wrap(device), staging_mem);
-        }
-        if let Some(device_mem) = self.device_mem.take() {
-            allocator.dealloc(AshMemoryDevice::wrap(device), device_mem);
-        }
-        */
+        garbage_bin.collect_buffer(
+            std::mem::replace(&mut self.staging_buf, vk::Buffer::null()),
+            staging_mem,
+        );
+        garbage_bin.collect_buffer(
+            std::mem::replace(&mut self.device_buf, vk::Buffer::null()),
+            self.device_mem.take(),
+        );
 
         let size = UniformEntry::std140_size_static();
         let array_size = size as u64 * new_capacity as u64;
@@ -147,12 +149,13 @@ impl UniformArray {
         items: impl ExactSizeIterator<Item = UniformEntry>,
         device: &ash::Device,
         allocator: &mut crate::Allocator,
+        garbage_bin: &mut GarbageBin,
     ) {
         if items.len() == 0 {
             return;
         }
         if items.len() as u32 > self.capacity {
-            self.resize_and_clear(items.len() as u32, device, allocator);
+            self.resize_and_clear(items.len() as u32, device, allocator, garbage_bin);
         }
         let entry_size = UniformEntry::std140_size_static();
         let mut dst = self.staging_ptr as *mut u8;
@@ -165,6 +168,14 @@ impl UniformArray {
             dst = dst.add(entry_size);
         }
     }
+    /// Overwrite a single entry. The recorded command buffers copy the staging buffer over
+    /// on every submission, so the change shows up on the next frame.
+    pub unsafe fn write_entry(&mut self, index: u32, entry: UniformEntry) {
+        assert!(index < self.capacity);
+        let entry_size = UniformEntry::std140_size_static();
+        let dst = (self.staging_ptr as *mut u8).add(index as usize * entry_size);
+        std::ptr::copy_nonoverlapping(&entry.as_std140() as *const _ as *const u8, dst, entry_size);
+    }
     pub fn get_buffer(&self) -> vk::Buffer {
         self.device_buf
     }
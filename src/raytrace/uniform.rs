@@ -1,10 +1,28 @@
 use crate::render::RenderState;
 use bevy::ecs::prelude::*;
+use bevy::log::warn;
 use bevy::math::{Mat3, Vec3};
 use bevy::prelude::GlobalTransform;
+use bevy::window::WindowId;
 
-use super::PerspectiveCamera;
+use super::{CameraLayers, PerspectiveCamera};
+use crate::camera::Projection;
+use crate::{OrthographicCamera, Viewport};
 
+// Cameras beyond this number are not rendered.
+pub(crate) const MAX_CAMERAS: usize = 8;
+// The view constants of each camera are bound with a dynamic offset, which has to be a multiple
+// of minUniformBufferOffsetAlignment. The spec limits it to at most 256.
+pub(crate) const VIEW_CONSTANTS_STRIDE: u64 = 256;
+const _: () =
+    assert!(std::mem::size_of::<RaytracingNodeViewConstants>() as u64 <= VIEW_CONSTANTS_STRIDE);
+
+// Values of `RaytracingNodeViewConstants::projection`. Keep in sync with raygen.rgen.
+const PROJECTION_PERSPECTIVE: u32 = 0;
+const PROJECTION_ORTHOGRAPHIC: u32 = 1;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
 pub(crate) struct RaytracingNodeViewConstants {
     pub camera_view_col0: [f32; 3],
     pub padding0: f32,
@@ -14,44 +32,156 @@ pub(crate) struct RaytracingNodeViewConstants {
     pub padding2: f32,
 
     pub camera_position: Vec3,
-    pub tan_half_fov: f32,
+    // Tangent of half the field of view for perspective cameras,
+    // half the height of the view for orthographic cameras.
+    pub projection_scale: f32,
+    pub view_mask: u32,
+    pub shadow_mask: u32,
+    // Top left pixel of the viewport. Rays are traced over the extent of the viewport.
+    pub viewport_offset: [u32; 2],
+    pub projection: u32,
+    // The clipping planes, which also bound the depth image.
+    pub near: f32,
+    pub far: f32,
+}
+
+pub(crate) struct ExtractedView {
+    pub viewport: Viewport,
+    pub constants: RaytracingNodeViewConstants,
 }
 
+// The cameras of this frame, in the order they are drawn in.
+#[derive(Default)]
+pub(crate) struct ExtractedViews(pub Vec<ExtractedView>);
+
+// A camera placed on its window, with its view constants at `uniform_offset` in the uniform buffer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct PreparedView {
+    pub window: WindowId,
+    pub offset: [u32; 2],
+    pub extent: [u32; 2],
+    pub uniform_offset: u32,
+}
+
+// The views recorded into the command buffers.
+#[derive(Default)]
+pub(crate) struct PreparedViews(pub Vec<PreparedView>);
+
 pub(super) fn extract_uniform_data(
     mut render_world: ResMut<crate::render::RenderWorld>,
-    query: Query<(&PerspectiveCamera, &GlobalTransform)>,
+    query: Query<
+        (
+            Entity,
+            Option<&PerspectiveCamera>,
+            Option<&OrthographicCamera>,
+            &GlobalTransform,
+            Option<&CameraLayers>,
+            Option<&Viewport>,
+        ),
+        Or<(With<PerspectiveCamera>, With<OrthographicCamera>)>,
+    >,
+    // Set while there are too many cameras, so that the warning is only logged once.
+    mut camera_limit_warned: Local<bool>,
 ) {
-    let mut cameras = query.iter();
-    let (camera, transform) = cameras.next().expect("Requires at least one camera");
-    if cameras.next().is_some() {
-        unimplemented!("Supports at most one camera for now");
+    let mut cameras: Vec<_> = query.iter().collect();
+    cameras.sort_by_key(|(entity, _, _, _, _, viewport)| {
+        (viewport.map_or(0, |viewport| viewport.order), *entity)
+    });
+    if cameras.len() > MAX_CAMERAS {
+        if !*camera_limit_warned {
+            warn!(
+                "Only {} of {} cameras are rendered",
+                MAX_CAMERAS,
+                cameras.len()
+            );
+            *camera_limit_warned = true;
+        }
+        // Keep the cameras drawn last, which are on top.
+        cameras.drain(..cameras.len() - MAX_CAMERAS);
+    } else {
+        *camera_limit_warned = false;
     }
 
-    let view_constants = unsafe {
-        let rotation_matrix = Mat3::from_quat(transform.rotation).to_cols_array_2d();
-        let mut contants: RaytracingNodeViewConstants =
-            std::mem::MaybeUninit::uninit().assume_init();
-        contants.camera_view_col0 = rotation_matrix[0];
-        contants.camera_view_col1 = rotation_matrix[1];
-        contants.camera_view_col2 = rotation_matrix[2];
-        contants.camera_position = transform.translation;
-        contants.tan_half_fov = (camera.fov / 2.0).tan(); // TODO
-        contants
-    };
-    render_world.insert_resource(view_constants);
+    let views = cameras
+        .into_iter()
+        .filter_map(
+            |(_, perspective, orthographic, transform, layers, viewport)| {
+                let camera_projection = Projection::from_components(perspective, orthographic)?;
+                let (projection, projection_scale) = match camera_projection {
+                    Projection::Perspective { tan_half_fov, .. } => {
+                        (PROJECTION_PERSPECTIVE, tan_half_fov)
+                    }
+                    Projection::Orthographic { half_height, .. } => {
+                        (PROJECTION_ORTHOGRAPHIC, half_height)
+                    }
+                };
+                let rotation_matrix = Mat3::from_quat(transform.rotation).to_cols_array_2d();
+                let layers = layers.cloned().unwrap_or_default();
+                Some(ExtractedView {
+                    viewport: viewport.cloned().unwrap_or_default(),
+                    constants: RaytracingNodeViewConstants {
+                        camera_view_col0: rotation_matrix[0],
+                        padding0: 0.0,
+                        camera_view_col1: rotation_matrix[1],
+                        padding1: 0.0,
+                        camera_view_col2: rotation_matrix[2],
+                        padding2: 0.0,
+                        camera_position: transform.translation,
+                        projection_scale,
+                        view_mask: layers.view.0 as u32,
+                        shadow_mask: layers.shadow.0 as u32,
+                        viewport_offset: [0, 0],
+                        projection,
+                        near: camera_projection.near(),
+                        far: camera_projection.far(),
+                    },
+                })
+            },
+        )
+        .collect();
+    render_world.insert_resource(ExtractedViews(views));
 }
 
 pub(super) fn prepare_uniform_data(
-    view_constants: Res<RaytracingNodeViewConstants>,
-    render_state: Res<RenderState>,
+    mut views: ResMut<ExtractedViews>,
+    mut prepared_views: ResMut<PreparedViews>,
+    mut render_state: ResMut<RenderState>,
 ) {
     let current_frame = render_state.current_frame().clone();
-    let view_constants = &*view_constants;
-    unsafe {
-        std::ptr::copy_nonoverlapping(
-            view_constants as *const RaytracingNodeViewConstants as *const u8,
-            current_frame.uniform_buffer_ptr as *mut u8,
-            std::mem::size_of_val(view_constants),
-        );
+    let mut new_prepared_views = Vec::with_capacity(views.0.len());
+    for view in views.0.iter_mut() {
+        let window = match render_state.windows.get(&view.viewport.window) {
+            Some(window) => window,
+            None => continue,
+        };
+        let (offset, extent) = match view
+            .viewport
+            .physical_rect(window.physical_width, window.physical_height)
+        {
+            Some(rect) => rect,
+            None => continue,
+        };
+        view.constants.viewport_offset = offset;
+
+        let uniform_offset = new_prepared_views.len() as u64 * VIEW_CONSTANTS_STRIDE;
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                &view.constants as *const RaytracingNodeViewConstants as *const u8,
+                (current_frame.uniform_buffer_ptr as *mut u8).add(uniform_offset as usize),
+                std::mem::size_of::<RaytracingNodeViewConstants>(),
+            );
+        }
+        new_prepared_views.push(PreparedView {
+            window: view.viewport.window,
+            offset,
+            extent,
+            uniform_offset: uniform_offset as u32,
+        });
+    }
+
+    // The viewports are baked into the command buffers.
+    if new_prepared_views != prepared_views.0 {
+        prepared_views.0 = new_prepared_views;
+        render_state.command_buffer_flush_all_frames();
     }
 }
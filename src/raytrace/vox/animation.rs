@@ -0,0 +1,137 @@
+use super::VoxelModel;
+use bevy::prelude::*;
+
+/// The frame of an animated voxel model shown by an instance, as an index into
+/// `Svdag::get_roots`. Frames past the last one show the last one.
+/// Instances without this component show the first frame.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AnimationFrame(pub u32);
+
+impl AnimationFrame {
+    // The root of the model shown for this frame.
+    pub(crate) fn clamp(frame: Option<&AnimationFrame>, model: &VoxelModel) -> u32 {
+        frame
+            .map_or(0, |frame| frame.0)
+            .min(model.num_frames().saturating_sub(1))
+    }
+}
+
+/// Plays the frames of the voxel model of its entity, updating its `AnimationFrame`.
+/// The `AnimationFrame` is inserted if it's missing.
+#[derive(Clone, Debug)]
+pub struct VoxelAnimationPlayer {
+    pub frames_per_second: f32,
+    /// Start over after the last frame. Otherwise, stay on the last frame.
+    pub looping: bool,
+    pub paused: bool,
+    elapsed: f32,
+}
+
+impl VoxelAnimationPlayer {
+    pub fn new(frames_per_second: f32) -> Self {
+        VoxelAnimationPlayer {
+            frames_per_second,
+            looping: true,
+            paused: false,
+            elapsed: 0.0,
+        }
+    }
+    pub fn once(frames_per_second: f32) -> Self {
+        VoxelAnimationPlayer {
+            looping: false,
+            ..Self::new(frames_per_second)
+        }
+    }
+    pub fn restart(&mut self) {
+        self.elapsed = 0.0;
+    }
+    /// Seconds since the start of the current loop.
+    pub fn elapsed(&self) -> f32 {
+        self.elapsed
+    }
+    fn advance(&mut self, delta: f32, num_frames: u32) {
+        if self.paused {
+            return;
+        }
+        self.elapsed += delta;
+        let duration = num_frames as f32 / self.frames_per_second;
+        if self.looping && duration > 0.0 {
+            // Keep the time small so that it doesn't lose precision.
+            self.elapsed = self.elapsed.rem_euclid(duration);
+        }
+    }
+    /// The frame to show for a model with `num_frames` frames.
+    pub fn frame(&self, num_frames: u32) -> u32 {
+        if num_frames == 0 {
+            return 0;
+        }
+        let frame = (self.elapsed * self.frames_per_second) as u32;
+        if self.looping {
+            frame % num_frames
+        } else {
+            frame.min(num_frames - 1)
+        }
+    }
+}
+
+pub(super) fn play_voxel_animations(
+    mut commands: Commands,
+    time: Res<Time>,
+    voxel_models: Res<Assets<VoxelModel>>,
+    mut query: Query<(
+        Entity,
+        &mut VoxelAnimationPlayer,
+        Option<&mut AnimationFrame>,
+        &Handle<VoxelModel>,
+    )>,
+) {
+    for (entity, mut player, animation_frame, model_handle) in query.iter_mut() {
+        let num_frames = match voxel_models.get(model_handle) {
+            Some(model) => model.num_frames(),
+            None => continue,
+        };
+        player.advance(time.delta_seconds(), num_frames);
+        let frame = player.frame(num_frames);
+        match animation_frame {
+            // Only write on changes, so that the TLAS doesn't get rebuilt every frame.
+            Some(mut animation_frame) => {
+                if animation_frame.0 != frame {
+                    animation_frame.0 = frame;
+                }
+            }
+            None => {
+                commands.entity(entity).insert(AnimationFrame(frame));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VoxelAnimationPlayer;
+
+    #[test]
+    fn test_animation_player() {
+        let mut player = VoxelAnimationPlayer::new(10.0);
+        assert_eq!(player.frame(4), 0);
+        player.advance(0.25, 4);
+        assert_eq!(player.frame(4), 2);
+        // Wraps around after 4 frames.
+        player.advance(0.2, 4);
+        assert_eq!(player.frame(4), 0);
+        assert!(player.elapsed() < 0.4);
+
+        player.paused = true;
+        player.advance(0.1, 4);
+        assert_eq!(player.frame(4), 0);
+
+        let mut player = VoxelAnimationPlayer::once(10.0);
+        player.advance(1.0, 4);
+        assert_eq!(player.frame(4), 3);
+        player.restart();
+        assert_eq!(player.frame(4), 0);
+
+        // Models without frames.
+        assert_eq!(player.frame(0), 0);
+    }
+}
@@ -0,0 +1,246 @@
+use super::VoxelModel;
+use crate::raytrace::block_alloc::AllocError;
+use crate::raytrace::svdag::Svdag;
+use bevy::asset::{Assets, Handle, HandleId};
+use bevy::math::UVec3;
+use bevy::prelude::*;
+use bevy::utils::HashSet;
+
+/// How a stamped model is combined with the voxels already in the target.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CsgOperation {
+    Union,
+    Subtract,
+    /// Clears the voxels inside the grid of the stamp that are empty in the stamp.
+    Intersect,
+}
+
+#[derive(Clone, Debug)]
+pub enum VoxelEditOp {
+    Set {
+        position: UVec3,
+        occupancy: bool,
+    },
+    /// Sets every voxel from `min` (inclusive) to `max` (exclusive).
+    FillBox {
+        min: UVec3,
+        max: UVec3,
+        occupancy: bool,
+    },
+    /// Combines the whole grid of `source`, placed at `offset`, with the target.
+    Stamp {
+        source: Handle<VoxelModel>,
+        offset: UVec3,
+        operation: CsgOperation,
+    },
+}
+
+/// Send this event to edit a loaded voxel model.
+/// Edits are applied in `CoreStage::PostUpdate` in the order they were sent, then flushed
+/// through the block allocator. Only the uniform entry of the edited model gets refreshed.
+/// Voxels outside of the grid of the model are ignored.
+/// Only the first frame of animated models is edited.
+pub struct VoxelEdit {
+    pub model: Handle<VoxelModel>,
+    pub op: VoxelEditOp,
+}
+
+// Models with edits that couldn't be flushed yet because the block allocator was busy.
+#[derive(Default)]
+pub struct UnflushedVoxelModels(HashSet<HandleId>);
+
+// A cube of uniform occupancy: the min corner, the side length and the occupancy.
+type Region = ([u32; 3], u32, bool);
+
+// Apply one edit to the first frame of a DAG with a grid of side length 2^size.
+// `stamp` holds the regions of the source model for `VoxelEditOp::Stamp`.
+fn apply_edit(
+    svdag: &mut Svdag,
+    size: u8,
+    op: &VoxelEditOp,
+    stamp: &[Region],
+) -> Result<(), AllocError> {
+    let gridsize = 1 << size;
+    let mut grid = svdag.get_grid_accessor_mut(size, 0);
+    match op {
+        VoxelEditOp::Set {
+            position,
+            occupancy,
+        } => {
+            if position.x < gridsize && position.y < gridsize && position.z < gridsize {
+                grid.set(position.x, position.y, position.z, *occupancy)?;
+            }
+        }
+        VoxelEditOp::FillBox {
+            min,
+            max,
+            occupancy,
+        } => {
+            grid.fill_box([min.x, min.y, min.z], [max.x, max.y, max.z], *occupancy)?;
+        }
+        VoxelEditOp::Stamp {
+            offset, operation, ..
+        } => {
+            for &(min, side, occupied) in stamp {
+                let occupancy = match (operation, occupied) {
+                    (CsgOperation::Union, true) => true,
+                    (CsgOperation::Subtract, true) | (CsgOperation::Intersect, false) => false,
+                    _ => continue,
+                };
+                let min = [
+                    min[0].saturating_add(offset.x),
+                    min[1].saturating_add(offset.y),
+                    min[2].saturating_add(offset.z),
+                ];
+                // The region would be clipped away entirely.
+                if min.iter().any(|&coord| coord >= gridsize) {
+                    continue;
+                }
+                let max = [min[0] + side, min[1] + side, min[2] + side];
+                grid.fill_box(min, max, occupancy)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn apply_voxel_edits(
+    mut edits: EventReader<VoxelEdit>,
+    mut voxel_models: ResMut<Assets<VoxelModel>>,
+    mut unflushed: ResMut<UnflushedVoxelModels>,
+) {
+    let mut edited: HashSet<HandleId> = HashSet::default();
+    for edit in edits.iter() {
+        let mut stamp: Vec<Region> = Vec::new();
+        if let VoxelEditOp::Stamp { source, .. } = &edit.op {
+            match voxel_models.get(source) {
+                Some(source) => source
+                    .svdag
+                    .get_grid_accessor(source.size, 0)
+                    .for_each_region(|min, side, occupied| stamp.push((min, side, occupied))),
+                None => {
+                    warn!("Skipped a voxel stamp: the source model is not loaded");
+                    continue;
+                }
+            }
+        }
+        let model = match voxel_models.get_mut(&edit.model) {
+            Some(model) => model,
+            None => {
+                warn!("Skipped a voxel edit: the model is not loaded");
+                continue;
+            }
+        };
+        let size = model.size;
+        if let Err(err) = apply_edit(&mut model.svdag, size, &edit.op, &stamp) {
+            // The voxels written before running out of memory are kept.
+            error!("Voxel edit failed: {}", err);
+        }
+        edited.insert(edit.model.id);
+    }
+    for id in edited {
+        voxel_models.get_mut(id).unwrap().update_bounds();
+        unflushed.0.insert(id);
+    }
+
+    // Models that were unloaded in the meantime have nothing left to flush.
+    unflushed.0.retain(|id| match voxel_models.get_mut(*id) {
+        Some(model) => match model.svdag.flush_dirty() {
+            Ok(flushed) => !flushed,
+            Err(err) => {
+                error!("Flushing a voxel edit failed: {}", err);
+                false
+            }
+        },
+        None => false,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_edit, CsgOperation, Region, VoxelEditOp};
+    use crate::raytrace::svdag::Svdag;
+    use bevy::asset::Handle;
+    use bevy::math::UVec3;
+
+    fn voxels(svdag: &Svdag) -> Vec<(u32, u32, u32)> {
+        let grid = svdag.get_grid_accessor(2, 0);
+        let mut voxels = Vec::new();
+        for x in 0..4 {
+            for y in 0..4 {
+                for z in 0..4 {
+                    if grid.get(x, y, z) {
+                        voxels.push((x, y, z));
+                    }
+                }
+            }
+        }
+        voxels
+    }
+
+    fn stamp(operation: CsgOperation, offset: UVec3) -> Vec<(u32, u32, u32)> {
+        let mut target = Svdag::potato();
+        let fill = VoxelEditOp::FillBox {
+            min: UVec3::new(0, 0, 0),
+            max: UVec3::new(3, 1, 1),
+            occupancy: true,
+        };
+        apply_edit(&mut target, 2, &fill, &[]).unwrap();
+
+        // A 2^1 grid with voxels at (0, 0, 0) and (1, 1, 1), placed at `offset`.
+        let mut source = Svdag::potato();
+        let mut grid = source.get_grid_accessor_mut(1, 0);
+        grid.set(0, 0, 0, true).unwrap();
+        grid.set(1, 1, 1, true).unwrap();
+        let mut regions: Vec<Region> = Vec::new();
+        source
+            .get_grid_accessor(1, 0)
+            .for_each_region(|min, side, occupied| regions.push((min, side, occupied)));
+        let op = VoxelEditOp::Stamp {
+            source: Handle::default(),
+            offset,
+            operation,
+        };
+        apply_edit(&mut target, 2, &op, &regions).unwrap();
+        voxels(&target)
+    }
+
+    #[test]
+    fn test_set_out_of_bounds() {
+        let mut svdag = Svdag::potato();
+        for position in [UVec3::new(3, 3, 3), UVec3::new(4, 0, 0)] {
+            let op = VoxelEditOp::Set {
+                position,
+                occupancy: true,
+            };
+            apply_edit(&mut svdag, 2, &op, &[]).unwrap();
+        }
+        assert_eq!(voxels(&svdag), vec![(3, 3, 3)]);
+    }
+
+    #[test]
+    fn test_stamp() {
+        let offset = UVec3::new(1, 0, 0);
+        assert_eq!(
+            stamp(CsgOperation::Union, offset),
+            vec![(0, 0, 0), (1, 0, 0), (2, 0, 0), (2, 1, 1)]
+        );
+        assert_eq!(
+            stamp(CsgOperation::Subtract, offset),
+            vec![(0, 0, 0), (2, 0, 0)]
+        );
+        assert_eq!(
+            stamp(CsgOperation::Intersect, offset),
+            vec![(0, 0, 0), (1, 0, 0)]
+        );
+    }
+
+    #[test]
+    fn test_stamp_out_of_bounds() {
+        let offset = UVec3::new(u32::MAX, 0, 0);
+        assert_eq!(
+            stamp(CsgOperation::Union, offset),
+            vec![(0, 0, 0), (1, 0, 0), (2, 0, 0)]
+        );
+    }
+}
@@ -1,7 +1,7 @@
 use bevy::{
     asset::{AssetLoader, LoadContext, LoadedAsset},
     prelude::*,
-    utils::BoxedFuture,
+    utils::{BoxedFuture, HashMap},
 };
 use dot_vox::{DotVoxData, SceneNode};
 
@@ -9,7 +9,8 @@ use std::sync::Arc;
 
 use super::VoxelModel;
 
-use crate::raytrace::block_alloc::BlockAllocator;
+use crate::raytrace::arena_alloc::BLOCK_SIZE;
+use crate::raytrace::block_alloc::{BlockAllocator, SystemBlockAllocator};
 use crate::raytrace::svdag::Svdag;
 
 pub struct VoxLoader {
@@ -19,8 +20,8 @@ pub struct VoxLoader {
 impl FromWorld for VoxLoader {
     fn from_world(world: &mut World) -> Self {
         let block_allocator = world
-            .get_resource_mut::<Arc<dyn BlockAllocator>>()
-            .unwrap()
+            .get_resource::<Arc<dyn BlockAllocator>>()
+            .expect("VoxPlugin has to be added after the render plugin, which provides the block allocator")
             .clone();
         VoxLoader { block_allocator }
     }
@@ -33,25 +34,64 @@ impl AssetLoader for VoxLoader {
         load_context: &'a mut LoadContext,
     ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
         Box::pin(async move {
-            let mut svdag = Svdag::new(self.block_allocator.clone(), 1);
-            println!("started loading vox");
-            let scene = dot_vox::load_bytes(bytes).map_err(|err| anyhow::Error::msg(err))?;
-            println!("end loading vox");
+            let model = self.load_model(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(model));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["vox"]
+    }
+}
+
+impl VoxLoader {
+    pub fn new(block_allocator: Arc<dyn BlockAllocator>) -> Self {
+        VoxLoader { block_allocator }
+    }
+    /// A loader building models in host memory, for tools running without a Vulkan device.
+    /// The models are not rendered until uploaded with `VoxelModel::upload`.
+    pub fn new_host() -> Self {
+        VoxLoader::new(Arc::new(SystemBlockAllocator::new(BLOCK_SIZE as usize)))
+    }
+    /// Build a model from the content of a .vox file.
+    /// Each animation frame of the scene gets its own root in the DAG. Frames showing the
+    /// same state of the scene share their nodes.
+    pub fn load_model(&self, bytes: &[u8]) -> Result<VoxelModel, anyhow::Error> {
+        println!("started loading vox");
+        let scene = dot_vox::load_bytes(bytes).map_err(|err| anyhow::Error::msg(err))?;
+        println!("end loading vox");
+        let num_frames = num_frames(&scene);
+        let mut svdag = Svdag::new(self.block_allocator.clone(), num_frames);
 
-            let _translation_min = Vec3 {
-                x: i32::MAX,
-                y: i32::MAX,
-                z: i32::MAX,
-            };
-            let _translation_max = Vec3 {
-                x: i32::MIN,
-                y: i32::MIN,
-                z: i32::MIN,
-            };
+        let _translation_min = Vec3 {
+            x: i32::MAX,
+            y: i32::MAX,
+            z: i32::MAX,
+        };
+        let _translation_max = Vec3 {
+            x: i32::MIN,
+            y: i32::MIN,
+            z: i32::MIN,
+        };
 
-            let mut translation_min = Vec3::MAX;
-            let mut translation_max = Vec3::MIN;
-            self.traverse(&scene, |model_id, translation, rotation| {
+        // The models placed in the scene at each animation frame.
+        let placements: Vec<Vec<Placement>> = (0..num_frames)
+            .map(|frame| {
+                let mut placed = Vec::new();
+                self.traverse(&scene, frame, |model_id, translation, rotation| {
+                    placed.push((model_id, translation, rotation))
+                });
+                placed
+            })
+            .collect();
+        let source_frames = source_frames(&placements);
+        let built_frames = (0..num_frames as usize).filter(|&frame| source_frames[frame] == frame);
+
+        let mut translation_min = Vec3::MAX;
+        let mut translation_max = Vec3::MIN;
+        for frame in built_frames.clone() {
+            for &(model_id, translation, rotation) in placements[frame].iter() {
                 let model = &scene.models[model_id as usize];
                 let size: Vec3 = Vec3 {
                     x: model.size.x as i32,
@@ -66,17 +106,15 @@ impl AssetLoader for VoxLoader {
                 translation_max.x = translation_max.x.max(translation.x + halfsize.x.abs());
                 translation_max.y = translation_max.y.max(translation.y + halfsize.y.abs());
                 translation_max.z = translation_max.z.max(translation.z + halfsize.z.abs());
-            });
-            let scene_size = translation_max - translation_min;
-            let scene_size = scene_size.x.max(scene_size.y).max(scene_size.z);
-            let mut grid = svdag
-                .get_grid_accessor_mut(crate::util::next_pow2_sqrt(scene_size as u32) as u8, 0);
-            let offset = -translation_min;
-            self.traverse(&scene, |model_id, translation, rotation| {
-                /*
-
-                */
-
+            }
+        }
+        let scene_size = translation_max - translation_min;
+        let scene_size = scene_size.x.max(scene_size.y).max(scene_size.z);
+        let size = crate::util::next_pow2_sqrt(scene_size as u32) as u8;
+        let offset = -translation_min;
+        for frame in built_frames {
+            let mut grid = svdag.get_grid_accessor_mut(size, frame);
+            for &(model_id, translation, rotation) in placements[frame].iter() {
                 let model = &scene.models[model_id as usize];
                 let half_size = Vec3 {
                     x: model.size.x as i32,
@@ -99,31 +137,37 @@ impl AssetLoader for VoxLoader {
                         location.z as u32,
                         location.y as u32,
                         true,
-                    );
+                    )?;
                 }
-            });
-            svdag.flush_all();
-            load_context.set_default_asset(LoadedAsset::new(VoxelModel { svdag }));
-            Ok(())
-        })
-    }
-
-    fn extensions(&self) -> &[&str] {
-        &["vox"]
+            }
+        }
+        for (frame, &source_frame) in source_frames.iter().enumerate() {
+            if source_frame != frame {
+                svdag.share_root(source_frame, frame);
+            }
+        }
+        svdag.flush_all()?;
+        Ok(VoxelModel::new(svdag, size))
     }
-}
-
-impl VoxLoader {
-    fn traverse<F>(&self, scene: &DotVoxData, mut callback: F)
+    // Visit the models of the scene as of an animation frame.
+    fn traverse<F>(&self, scene: &DotVoxData, frame: u32, mut callback: F)
     where
         F: FnMut(u32, Vec3, Rotation),
     {
-        self.traverse_recursive(scene, 0, Vec3::ZERO, Rotation::IDENTITY, &mut callback)
+        self.traverse_recursive(
+            scene,
+            0,
+            frame,
+            Vec3::ZERO,
+            Rotation::IDENTITY,
+            &mut callback,
+        )
     }
     fn traverse_recursive<F>(
         &self,
         scene: &DotVoxData,
         node: u32,
+        frame_index: u32,
         mut translation: Vec3,
         mut rotation: Rotation,
         callback: &mut F,
@@ -137,10 +181,7 @@ impl VoxLoader {
                 frames,
                 child,
             } => {
-                if frames.len() != 1 {
-                    unimplemented!("Multiple frame in transform node");
-                }
-                let frame = &frames[0];
+                let frame = &frames[keyframe(frames.iter().map(frame_attribute), frame_index)];
                 if let Some(value) = frame.get("_t") {
                     let values: Vec<&str> = value.split(" ").collect();
                     assert_eq!(values.len(), 3);
@@ -154,14 +195,21 @@ impl VoxLoader {
                     rotation = Rotation(value.parse::<u8>().unwrap());
                 }
 
-                self.traverse_recursive(scene, *child, translation, rotation, callback);
+                self.traverse_recursive(
+                    scene,
+                    *child,
+                    frame_index,
+                    translation,
+                    rotation,
+                    callback,
+                );
             }
             SceneNode::Group {
                 attributes: _,
                 children,
             } => {
                 for &i in children {
-                    self.traverse_recursive(scene, i, translation, rotation, callback);
+                    self.traverse_recursive(scene, i, frame_index, translation, rotation, callback);
                 }
             }
             SceneNode::Shape {
@@ -169,16 +217,78 @@ impl VoxLoader {
                 models,
             } => {
                 // Shape nodes are leafs and correspond to models
-                if models.len() != 1 {
-                    unimplemented!("Multiple shape models in Shape node");
-                }
-                let model = &models[0];
+                let model = &models[keyframe(
+                    models
+                        .iter()
+                        .map(|model| frame_attribute(&model.attributes)),
+                    frame_index,
+                )];
                 callback(model.model_id, translation, rotation);
             }
         }
     }
 }
 
+// The animation frame of a keyframe in a transform or shape node. Defaults to the first frame.
+fn frame_attribute(attributes: &dot_vox::Dict) -> u32 {
+    attributes
+        .get("_f")
+        .and_then(|value| value.parse::<u32>().ok())
+        .unwrap_or(0)
+}
+
+// Number of animation frames of the scene, from the last keyframe of any node.
+fn num_frames(scene: &DotVoxData) -> u32 {
+    scene
+        .scene
+        .iter()
+        .flat_map(|node| match node {
+            SceneNode::Transform { frames, .. } => frames.iter().map(frame_attribute).collect(),
+            SceneNode::Shape { models, .. } => models
+                .iter()
+                .map(|model| frame_attribute(&model.attributes))
+                .collect(),
+            SceneNode::Group { .. } => Vec::new(),
+        })
+        .max()
+        .map_or(1, |last| last + 1)
+}
+
+// A model placed in the scene, with its translation and rotation.
+type Placement = (u32, Vec3, Rotation);
+
+// Frames between keyframes repeat an earlier state of the scene. Maps each frame to the
+// first frame showing the same models at the same places, which is the only one built.
+fn source_frames(placements: &[Vec<Placement>]) -> Vec<usize> {
+    let mut first_frames: HashMap<Vec<(u32, [i32; 3], u8)>, usize> = HashMap::default();
+    placements
+        .iter()
+        .enumerate()
+        .map(|(frame, placed)| {
+            let state = placed
+                .iter()
+                .map(|(model_id, translation, rotation)| {
+                    (*model_id, *translation.as_slice(), rotation.0)
+                })
+                .collect();
+            *first_frames.entry(state).or_insert(frame)
+        })
+        .collect()
+}
+
+// Index of the keyframe shown at `frame`: the last one starting at or before it.
+// Frames before the first keyframe show the first keyframe.
+fn keyframe(keyframes: impl Iterator<Item = u32>, frame: u32) -> usize {
+    let keyframes: Vec<u32> = keyframes.collect();
+    let first = (0..keyframes.len())
+        .min_by_key(|&i| keyframes[i])
+        .expect("Nodes have at least one keyframe");
+    (0..keyframes.len())
+        .filter(|&i| keyframes[i] <= frame)
+        .max_by_key(|&i| keyframes[i])
+        .unwrap_or(first)
+}
+
 #[derive(Clone, Copy)]
 struct Rotation(u8);
 impl Rotation {
@@ -301,3 +411,38 @@ impl std::ops::Mul<Vec3> for Rotation {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{keyframe, source_frames, Rotation, Vec3};
+
+    #[test]
+    fn test_keyframe() {
+        // Nodes without animations have a single keyframe.
+        assert_eq!(keyframe([0].into_iter(), 0), 0);
+        assert_eq!(keyframe([0].into_iter(), 5), 0);
+
+        let keyframes = [0, 4, 2];
+        assert_eq!(keyframe(keyframes.into_iter(), 1), 0);
+        assert_eq!(keyframe(keyframes.into_iter(), 2), 2);
+        assert_eq!(keyframe(keyframes.into_iter(), 3), 2);
+        assert_eq!(keyframe(keyframes.into_iter(), 9), 1);
+
+        // The first keyframe is shown until the animation of the node starts.
+        assert_eq!(keyframe([3, 6].into_iter(), 1), 0);
+    }
+
+    #[test]
+    fn test_source_frames() {
+        let at = |x| (0, Vec3 { x, y: 0, z: 0 }, Rotation::IDENTITY);
+        let placements = vec![
+            vec![at(0)],
+            vec![at(0)],
+            vec![at(3)],
+            vec![at(3), at(5)],
+            vec![at(0)],
+            vec![at(3)],
+        ];
+        assert_eq!(source_frames(&placements), vec![0, 0, 2, 3, 0, 2]);
+    }
+}
@@ -1,8 +1,18 @@
+use super::block_alloc::{AllocError, BlockAllocator};
 use super::svdag::Svdag;
+use std::sync::Arc;
 
+mod animation;
+mod edit;
 mod loader;
+mod residency;
+pub use animation::{AnimationFrame, VoxelAnimationPlayer};
+pub use edit::{CsgOperation, VoxelEdit, VoxelEditOp};
+pub use loader::VoxLoader;
+pub use residency::{VoxelModelEvicted, VoxelResidency};
 
-use bevy::app::App;
+use bevy::app::{App, CoreStage};
+use bevy::math::UVec3;
 use bevy::prelude::AddAsset;
 use bevy::reflect::TypeUuid;
 
@@ -10,6 +20,58 @@ use bevy::reflect::TypeUuid;
 #[uuid = "a6fbaf37-f393-4d5e-92ba-4b0944f7c9cf"]
 pub struct VoxelModel {
     pub svdag: Svdag,
+    /// The DAG covers a grid of side length 2^size.
+    pub size: u8,
+    /// Bounding box of the occupied voxels over all frames, from min (inclusive) to max (exclusive).
+    /// None if the model is empty. `VoxelEdit` keeps it up to date. Call `update_bounds` after
+    /// editing the DAG directly.
+    pub occupied_bounds: Option<(UVec3, UVec3)>,
+}
+
+impl VoxelModel {
+    pub fn new(svdag: Svdag, size: u8) -> Self {
+        let mut model = VoxelModel {
+            svdag,
+            size,
+            occupied_bounds: None,
+        };
+        model.update_bounds();
+        model
+    }
+    /// Side length of the grid, in voxels.
+    pub fn grid_size(&self) -> u32 {
+        1 << self.size
+    }
+    /// Number of animation frames, one per root of the DAG.
+    pub fn num_frames(&self) -> u32 {
+        self.svdag.get_roots().len() as u32
+    }
+    pub fn update_bounds(&mut self) {
+        self.occupied_bounds = (0..self.num_frames())
+            .filter_map(|frame| {
+                self.svdag
+                    .get_grid_accessor(self.size, frame as usize)
+                    .occupied_bounds()
+                    .map(|(min, max)| (UVec3::from(min), UVec3::from(max)))
+            })
+            .reduce(|(min_a, max_a), (min_b, max_b)| (min_a.min(min_b), max_a.max(max_b)));
+    }
+    /// Copy the model into another block allocator. See `Svdag::upload`.
+    pub fn upload(&self, block_allocator: Arc<dyn BlockAllocator>) -> Result<Self, AllocError> {
+        Ok(VoxelModel {
+            svdag: self.svdag.upload(block_allocator)?,
+            size: self.size,
+            occupied_bounds: self.occupied_bounds,
+        })
+    }
+    /// False for models built in host memory, which are not rendered until uploaded.
+    pub fn has_device_buffer(&self) -> bool {
+        self.svdag.arena.has_device_buffer()
+    }
+    /// Bytes of block memory held by the model.
+    pub fn memory_usage(&self) -> u64 {
+        self.svdag.memory_usage()
+    }
 }
 
 #[derive(Default)]
@@ -18,6 +80,15 @@ pub struct VoxPlugin;
 impl bevy::app::Plugin for VoxPlugin {
     fn build(&self, app: &mut App) {
         app.init_asset_loader::<loader::VoxLoader>()
-            .add_asset::<VoxelModel>();
+            .add_asset::<VoxelModel>()
+            .add_event::<VoxelModelEvicted>()
+            .add_event::<VoxelEdit>()
+            .init_resource::<VoxelResidency>()
+            .init_resource::<edit::UnflushedVoxelModels>()
+            .add_system_to_stage(CoreStage::PostUpdate, edit::apply_voxel_edits)
+            .add_system_to_stage(CoreStage::Update, animation::play_voxel_animations)
+            .add_system_to_stage(CoreStage::PreUpdate, residency::reload_evicted_models)
+            .add_system_to_stage(CoreStage::PostUpdate, residency::track_rendered_models)
+            .add_system_to_stage(CoreStage::Last, residency::evict_models);
     }
 }
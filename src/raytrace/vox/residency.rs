@@ -0,0 +1,157 @@
+use super::VoxelModel;
+use crate::raytrace::block_alloc::MemoryBudget;
+use crate::raytrace::Raytraced;
+use bevy::asset::{AssetServer, Assets, Handle, HandleId};
+use bevy::prelude::*;
+use bevy::utils::{HashMap, HashSet};
+use std::sync::Arc;
+
+/// Sent for every voxel model unloaded because the memory budget was exceeded.
+/// The handle is weak. The model will be loaded again once a `Raytraced` entity uses it.
+pub struct VoxelModelEvicted {
+    pub handle: Handle<VoxelModel>,
+    pub memory_usage: u64,
+}
+
+/// Keeps track of when each voxel model was last rendered, and which models were evicted.
+#[derive(Default)]
+pub struct VoxelResidency {
+    frame: u64,
+    last_rendered: HashMap<HandleId, u64>,
+    evicted: HashSet<HandleId>,
+    // Set while the budget is exceeded by models that are all in use, so that it's reported once.
+    over_budget: bool,
+}
+
+impl VoxelResidency {
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+    pub fn last_rendered(&self, handle: &Handle<VoxelModel>) -> Option<u64> {
+        self.last_rendered.get(&handle.id).copied()
+    }
+    pub fn is_evicted(&self, handle: &Handle<VoxelModel>) -> bool {
+        self.evicted.contains(&handle.id)
+    }
+}
+
+// Pick the least recently rendered models until at least `excess` bytes were freed.
+// `models` contains the key, the frame the model was last rendered and its memory usage.
+// Models rendered in `current_frame` are never picked.
+pub fn select_evictions<K: Copy>(
+    models: &[(K, u64, u64)],
+    excess: u64,
+    current_frame: u64,
+) -> Vec<K> {
+    let mut candidates: Vec<&(K, u64, u64)> = models
+        .iter()
+        .filter(|(_, last_rendered, _)| *last_rendered < current_frame)
+        .collect();
+    candidates.sort_by_key(|(_, last_rendered, _)| *last_rendered);
+    let mut freed = 0;
+    let mut selected = Vec::new();
+    for (key, _, memory_usage) in candidates {
+        if freed >= excess {
+            break;
+        }
+        freed += memory_usage;
+        selected.push(*key);
+    }
+    selected
+}
+
+pub fn track_rendered_models(
+    mut residency: ResMut<VoxelResidency>,
+    voxel_models: Res<Assets<VoxelModel>>,
+    query: Query<&Handle<VoxelModel>, With<Raytraced>>,
+) {
+    residency.frame += 1;
+    let frame = residency.frame;
+    for handle in query.iter() {
+        if voxel_models.contains(handle) {
+            residency.last_rendered.insert(handle.id, frame);
+        }
+    }
+}
+
+pub fn evict_models(
+    mut residency: ResMut<VoxelResidency>,
+    budget: Option<Res<Arc<MemoryBudget>>>,
+    mut voxel_models: ResMut<Assets<VoxelModel>>,
+    mut evicted_events: EventWriter<VoxelModelEvicted>,
+) {
+    let budget = match budget {
+        Some(budget) => budget,
+        None => return,
+    };
+    let excess = budget.excess();
+    if excess == 0 {
+        residency.over_budget = false;
+        return;
+    }
+    let models: Vec<(HandleId, u64, u64)> = voxel_models
+        .iter()
+        .map(|(id, model)| {
+            let last_rendered = residency.last_rendered.get(&id).copied().unwrap_or(0);
+            (id, last_rendered, model.memory_usage())
+        })
+        .collect();
+    let frame = residency.frame;
+    let selected = select_evictions(&models, excess, frame);
+    if selected.is_empty() {
+        if !residency.over_budget {
+            warn!(
+                "Voxel memory budget exceeded by {} bytes, but all models are in use",
+                excess
+            );
+        }
+        residency.over_budget = true;
+        return;
+    }
+    residency.over_budget = false;
+    for id in selected {
+        let model = voxel_models.remove(id).unwrap();
+        evicted_events.send(VoxelModelEvicted {
+            handle: Handle::weak(id),
+            memory_usage: model.memory_usage(),
+        });
+        residency.last_rendered.remove(&id);
+        residency.evicted.insert(id);
+        // Frames in flight may still read the model. Its blocks are freed through the garbage bin,
+        // so it can be dropped right away.
+    }
+}
+
+pub fn reload_evicted_models(
+    mut residency: ResMut<VoxelResidency>,
+    voxel_models: Res<Assets<VoxelModel>>,
+    asset_server: Res<AssetServer>,
+    query: Query<&Handle<VoxelModel>, With<Raytraced>>,
+) {
+    for handle in query.iter() {
+        if !residency.evicted.contains(&handle.id) || voxel_models.contains(handle) {
+            continue;
+        }
+        if let Some(path) = asset_server.get_handle_path(handle) {
+            asset_server.reload_asset(path);
+        }
+        residency.evicted.remove(&handle.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::select_evictions;
+
+    #[test]
+    fn test_select_evictions() {
+        let models = [('a', 3, 100), ('b', 1, 100), ('c', 5, 100), ('d', 2, 300)];
+        assert_eq!(select_evictions(&models, 0, 5), vec![]);
+        assert_eq!(select_evictions(&models, 50, 5), vec!['b']);
+        assert_eq!(select_evictions(&models, 150, 5), vec!['b', 'd']);
+        assert_eq!(select_evictions(&models, 450, 5), vec!['b', 'd', 'a']);
+        // Models rendered in the current frame stay loaded, even if the budget is still exceeded.
+        assert_eq!(select_evictions(&models, 10000, 5), vec!['b', 'd', 'a']);
+        assert_eq!(select_evictions(&models, 10000, 3), vec!['b', 'd']);
+    }
+}
@@ -11,7 +11,8 @@ use bevy::ecs::schedule::{Stage, StageLabel};
 use bevy::ecs::world::World;
 use bevy::prelude::IntoExclusiveSystem;
 pub use recycle::{Garbage, GarbageBin};
-pub use window::RenderState;
+pub use swapchain::{SwapchainImage, DEPTH_FORMAT};
+pub use window::{Frame, RenderState, NUM_FRAMES_IN_FLIGHT};
 
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, AppLabel)]
 pub struct RenderApp;
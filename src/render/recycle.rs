@@ -1,14 +1,39 @@
 use ash::vk;
 use bevy::ecs::prelude::*;
+use gpu_alloc_ash::AshMemoryDevice;
 
 use super::{window::NUM_FRAMES_IN_FLIGHT, RenderState};
 
 pub enum Garbage {
     AccelerationStructure(vk::AccelerationStructureKHR),
+    Buffer(vk::Buffer),
+    Image(vk::Image),
+    MemoryBlock(crate::MemoryBlock),
+    /// The pool has to be created with `FREE_DESCRIPTOR_SET`.
+    DescriptorSet(vk::DescriptorPool, vk::DescriptorSet),
+    /// Called once the frames in flight are finished. For anything the other variants don't cover.
+    Closure(Box<dyn FnOnce() + Send + Sync>),
+}
+
+impl Garbage {
+    // Null handles don't need to be deleted.
+    fn is_null(&self) -> bool {
+        match self {
+            Garbage::AccelerationStructure(acceleration_structure) => {
+                *acceleration_structure == vk::AccelerationStructureKHR::null()
+            }
+            Garbage::Buffer(buffer) => *buffer == vk::Buffer::null(),
+            Garbage::Image(image) => *image == vk::Image::null(),
+            Garbage::DescriptorSet(_, descriptor_set) => {
+                *descriptor_set == vk::DescriptorSet::null()
+            }
+            Garbage::MemoryBlock(_) | Garbage::Closure(_) => false,
+        }
+    }
 }
 
 /// Rendering resources that you put into the Garbage Bin won't be deleted immediately.
-/// They will be deleted exactly NUM_FRAMES_IN_FLIGHT frames later.
+/// They will be deleted exactly NUM_FRAMES_IN_FLIGHT frames later, in the order they were collected.
 /// Doing so ensures that all frames using the resource are finished,
 /// and that the resource is no longer in use.
 pub struct GarbageBin {
@@ -25,21 +50,39 @@ impl GarbageBin {
     }
 
     pub fn collect(&mut self, garbage: Garbage) {
+        if garbage.is_null() {
+            return;
+        }
         self.current_frame_garbage.push(garbage);
     }
+
+    /// Collect a buffer and the memory bound to it.
+    pub fn collect_buffer(&mut self, buffer: vk::Buffer, memory: Option<crate::MemoryBlock>) {
+        self.collect(Garbage::Buffer(buffer));
+        if let Some(memory) = memory {
+            self.collect(Garbage::MemoryBlock(memory));
+        }
+    }
+
+    // Called at the end of the frame with the given index. Returns the garbage collected
+    // NUM_FRAMES_IN_FLIGHT frames ago, the last time this frame index was in use.
+    fn take_expired(&mut self, frame_index: usize) -> Vec<Garbage> {
+        let expired = std::mem::take(&mut self.garbage[frame_index]);
+        self.garbage[frame_index] = std::mem::take(&mut self.current_frame_garbage);
+        expired
+    }
 }
 
 // To be added to CLEANUP system.
 pub(super) fn garbage_collection_system(
     mut garbage_bin: ResMut<GarbageBin>,
     render_state: Res<RenderState>,
-
+    device: Res<ash::Device>,
+    mut allocator: ResMut<crate::Allocator>,
     acceleration_structure_loader: Res<ash::extensions::khr::AccelerationStructure>,
 ) {
-    let garbage_bin = &mut *garbage_bin;
     let current_frame_index = render_state.current_frame().index;
-    let last_frame_garbage = &mut garbage_bin.garbage[current_frame_index as usize];
-    for garbage in last_frame_garbage.drain(..) {
+    for garbage in garbage_bin.take_expired(current_frame_index as usize) {
         unsafe {
             match garbage {
                 Garbage::AccelerationStructure(acceleration_structure) => {
@@ -47,9 +90,87 @@ pub(super) fn garbage_collection_system(
                     acceleration_structure_loader
                         .destroy_acceleration_structure(acceleration_structure, None);
                 }
+                Garbage::Buffer(buffer) => device.destroy_buffer(buffer, None),
+                Garbage::Image(image) => device.destroy_image(image, None),
+                Garbage::MemoryBlock(memory) => {
+                    allocator.dealloc(AshMemoryDevice::wrap(&*device), memory)
+                }
+                Garbage::DescriptorSet(pool, descriptor_set) => {
+                    device.free_descriptor_sets(pool, &[descriptor_set]);
+                }
+                Garbage::Closure(closure) => closure(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Garbage, GarbageBin, NUM_FRAMES_IN_FLIGHT};
+    use ash::vk;
+    use ash::vk::Handle;
+    use std::sync::{Arc, Mutex};
+
+    // Run the expired closures of a frame, and count the other garbage.
+    fn end_frame(garbage_bin: &mut GarbageBin, frame_index: usize) -> usize {
+        let mut handles = 0;
+        for garbage in garbage_bin.take_expired(frame_index) {
+            match garbage {
+                Garbage::Closure(closure) => closure(),
+                _ => handles += 1,
             }
         }
+        handles
+    }
+
+    #[test]
+    fn test_frame_delay() {
+        let deleted = Arc::new(Mutex::new(Vec::new()));
+        let mut garbage_bin = GarbageBin::new();
+        let frames = NUM_FRAMES_IN_FLIGHT as usize;
+
+        for frame in 0..frames * 3 {
+            let frame_index = frame % frames;
+            let deleted_clone = deleted.clone();
+            garbage_bin.collect(Garbage::Closure(Box::new(move || {
+                deleted_clone.lock().unwrap().push(frame);
+            })));
+            end_frame(&mut garbage_bin, frame_index);
+
+            // Garbage is deleted when its frame index comes around again.
+            let expected: Vec<usize> = (0..(frame + 1).saturating_sub(frames)).collect();
+            assert_eq!(*deleted.lock().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_collect_order_and_nulls() {
+        let deleted = Arc::new(Mutex::new(Vec::new()));
+        let mut garbage_bin = GarbageBin::new();
+        let frames = NUM_FRAMES_IN_FLIGHT as usize;
+
+        garbage_bin.collect(Garbage::Buffer(vk::Buffer::null()));
+        garbage_bin.collect(Garbage::Image(vk::Image::null()));
+        garbage_bin.collect(Garbage::AccelerationStructure(
+            vk::AccelerationStructureKHR::null(),
+        ));
+        garbage_bin.collect_buffer(vk::Buffer::from_raw(1), None);
+        for i in 0..3 {
+            let deleted = deleted.clone();
+            garbage_bin.collect(Garbage::Closure(Box::new(move || {
+                deleted.lock().unwrap().push(i);
+            })));
+        }
+
+        let mut handles = end_frame(&mut garbage_bin, 0);
+        for frame_index in 1..frames {
+            handles += end_frame(&mut garbage_bin, frame_index);
+        }
+        assert_eq!(handles, 0);
+        assert!(deleted.lock().unwrap().is_empty());
+
+        // Only the buffer with a handle was kept.
+        assert_eq!(end_frame(&mut garbage_bin, 0), 1);
+        assert_eq!(*deleted.lock().unwrap(), vec![0, 1, 2]);
     }
-    let current_frame_garbage = &mut garbage_bin.current_frame_garbage;
-    std::mem::swap(last_frame_garbage, current_frame_garbage);
 }
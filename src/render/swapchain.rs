@@ -1,10 +1,16 @@
 use super::window::Frame;
+use crate::VulkanAllocator;
 use ash::vk;
 use bevy::window::RawWindowHandleWrapper;
 
 const SWAPCHAIN_LEN: u32 = 3;
 use super::window::NUM_FRAMES_IN_FLIGHT;
 
+/// Format of the depth images. Each texel is the distance along the -Z axis of the camera,
+/// from the camera for perspective cameras and from its plane for orthographic cameras.
+/// Texels where nothing was hit are at the far plane of their camera.
+pub const DEPTH_FORMAT: vk::Format = vk::Format::R32_SFLOAT;
+
 #[derive(Clone)]
 pub struct SwapchainImage {
     pub index: u32,
@@ -15,6 +21,11 @@ pub struct SwapchainImage {
     // is that cmd_begin_render_pass contains a reference to the framebuffer
     // which is unique to each swapchain image.
     pub desc_set: vk::DescriptorSet, // A desc set that binds to the target image.
+    /// Depth written alongside the image, in `DEPTH_FORMAT`. It's in `SHADER_READ_ONLY_OPTIMAL`
+    /// layout after the ray tracing commands, so passes after them can sample it
+    /// with `RayShaders::depth_sampler` for compositing and post effects.
+    pub depth_image: vk::Image,
+    pub depth_view: vk::ImageView,
 }
 
 pub struct SurfaceState {
@@ -25,7 +36,12 @@ pub struct SurfaceState {
     pub format: vk::Format,
     pub extent: vk::Extent2D,
     pub swapchain_images: Vec<SwapchainImage>,
+    pub depth_memory: Vec<crate::MemoryBlock>, // The memory of the depth images, one per swapchain image.
     pub image_available_semaphore: [vk::Semaphore; NUM_FRAMES_IN_FLIGHT as usize], // This should really be per frame, per window
+    pub render_finished_semaphore: [vk::Semaphore; NUM_FRAMES_IN_FLIGHT as usize],
+    // The rendering commands of the window for each frame in flight, allocated from the command pool of the RenderState.
+    pub command_buffers: [vk::CommandBuffer; NUM_FRAMES_IN_FLIGHT as usize],
+    pub command_buffer_needs_update: [bool; NUM_FRAMES_IN_FLIGHT as usize],
 }
 
 impl SurfaceState {
@@ -36,16 +52,33 @@ impl SurfaceState {
         physical_device: vk::PhysicalDevice,
         surface_loader: &ash::extensions::khr::Surface,
         window_handle: &RawWindowHandleWrapper,
+        command_pool: vk::CommandPool,
     ) -> Self {
         let window_handle = window_handle.get_handle();
         let surface = ash_window::create_surface(entry, instance, &window_handle, None).unwrap();
 
         let mut image_available_semaphore = [vk::Semaphore::null(); NUM_FRAMES_IN_FLIGHT as usize];
-        for semaphore in image_available_semaphore.iter_mut() {
+        let mut render_finished_semaphore = [vk::Semaphore::null(); NUM_FRAMES_IN_FLIGHT as usize];
+        for semaphore in image_available_semaphore
+            .iter_mut()
+            .chain(render_finished_semaphore.iter_mut())
+        {
             *semaphore = device
                 .create_semaphore(&vk::SemaphoreCreateInfo::default(), None)
                 .unwrap();
         }
+        let mut command_buffers = [vk::CommandBuffer::null(); NUM_FRAMES_IN_FLIGHT as usize];
+        command_buffers.copy_from_slice(
+            &device
+                .allocate_command_buffers(
+                    &vk::CommandBufferAllocateInfo::builder()
+                        .command_pool(command_pool)
+                        .level(vk::CommandBufferLevel::PRIMARY)
+                        .command_buffer_count(NUM_FRAMES_IN_FLIGHT)
+                        .build(),
+                )
+                .unwrap(),
+        );
 
         let caps = surface_loader
             .get_physical_device_surface_capabilities(physical_device, surface)
@@ -57,7 +90,7 @@ impl SurfaceState {
                     .max_sets(caps.max_image_count)
                     .pool_sizes(&[vk::DescriptorPoolSize {
                         ty: vk::DescriptorType::STORAGE_IMAGE,
-                        descriptor_count: caps.max_image_count, // Swapchain can contain any number of images >= SWAPCHAIN_LEN
+                        descriptor_count: caps.max_image_count * 2, // Swapchain can contain any number of images >= SWAPCHAIN_LEN. Colour and depth for each.
                     }])
                     .build(),
                 None,
@@ -70,14 +103,22 @@ impl SurfaceState {
             format: vk::Format::default(),
             extent: vk::Extent2D::default(),
             swapchain_images: Vec::new(),
+            depth_memory: Vec::new(),
             image_available_semaphore,
+            render_finished_semaphore,
+            command_buffers,
+            command_buffer_needs_update: [true; NUM_FRAMES_IN_FLIGHT as usize],
             desc_pool,
         }
     }
+    pub fn command_buffer_flush_all_frames(&mut self) {
+        self.command_buffer_needs_update = [true; NUM_FRAMES_IN_FLIGHT as usize];
+    }
     pub unsafe fn destroy_swapchain(
         &mut self,
         device: &ash::Device,
         swapchain_loader: &ash::extensions::khr::Swapchain,
+        allocator: &mut crate::Allocator,
     ) {
         device.device_wait_idle().unwrap();
         // We have to reallocate descriptor sets here, because we might have a different number of desc sets in our next frame.
@@ -86,6 +127,11 @@ impl SurfaceState {
             .unwrap();
         for image in self.swapchain_images.iter() {
             device.destroy_image_view(image.view, None);
+            device.destroy_image_view(image.depth_view, None);
+            device.destroy_image(image.depth_image, None);
+        }
+        for memory in self.depth_memory.drain(..) {
+            allocator.dealloc(gpu_alloc_ash::AshMemoryDevice::wrap(device), memory);
         }
         self.swapchain_images.clear();
         swapchain_loader.destroy_swapchain(self.swapchain, None);
@@ -100,6 +146,7 @@ impl SurfaceState {
         swapchain_loader: &ash::extensions::khr::Swapchain,
         physical_device: vk::PhysicalDevice,
         queues: &crate::Queues,
+        allocator: &mut crate::Allocator,
     ) {
         if !surface_loader
             .get_physical_device_surface_support(
@@ -168,6 +215,11 @@ impl SurfaceState {
             )
             .unwrap();
 
+        let depth_images: Vec<(vk::Image, vk::ImageView)> = images
+            .iter()
+            .map(|_| self.create_depth_image(device, allocator))
+            .collect();
+
         self.swapchain_images = images
             .iter()
             .enumerate()
@@ -202,36 +254,92 @@ impl SurfaceState {
                     view,
                     fence: vk::Fence::null(),
                     desc_set: desc_sets[i],
+                    depth_image: depth_images[i].0,
+                    depth_view: depth_images[i].1,
                 }
             })
             .collect();
 
-        let image_infos: Vec<vk::DescriptorImageInfo> = self
+        // Colour at binding 0 and depth at binding 4.
+        let image_infos: Vec<[vk::DescriptorImageInfo; 2]> = self
             .swapchain_images
             .iter()
-            .map(|image| vk::DescriptorImageInfo {
-                sampler: vk::Sampler::null(),
-                image_layout: vk::ImageLayout::GENERAL,
-                image_view: image.view,
+            .map(|image| {
+                [image.view, image.depth_view].map(|image_view| vk::DescriptorImageInfo {
+                    sampler: vk::Sampler::null(),
+                    image_layout: vk::ImageLayout::GENERAL,
+                    image_view,
+                })
             })
             .collect();
         let desc_writes: Vec<vk::WriteDescriptorSet> = self
             .swapchain_images
             .iter()
             .enumerate()
-            .map(|(i, image)| {
-                vk::WriteDescriptorSet::builder()
-                    .dst_set(image.desc_set)
-                    .dst_binding(0)
-                    .dst_array_element(0)
-                    .image_info(&image_infos[i..=i])
-                    .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
-                    .build()
+            .flat_map(|(i, image)| {
+                [(0, 0), (4, 1)].map(|(binding, j)| {
+                    vk::WriteDescriptorSet::builder()
+                        .dst_set(image.desc_set)
+                        .dst_binding(binding)
+                        .dst_array_element(0)
+                        .image_info(&image_infos[i][j..=j])
+                        .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                        .build()
+                })
             })
             .collect();
         device.update_descriptor_sets(&desc_writes, &[]);
     }
 
+    unsafe fn create_depth_image(
+        &mut self,
+        device: &ash::Device,
+        allocator: &mut crate::Allocator,
+    ) -> (vk::Image, vk::ImageView) {
+        let image = device
+            .create_image(
+                &vk::ImageCreateInfo::builder()
+                    .image_type(vk::ImageType::TYPE_2D)
+                    .format(DEPTH_FORMAT)
+                    .extent(vk::Extent3D {
+                        width: self.extent.width,
+                        height: self.extent.height,
+                        depth: 1,
+                    })
+                    .mip_levels(1)
+                    .array_layers(1)
+                    .samples(vk::SampleCountFlags::TYPE_1)
+                    .tiling(vk::ImageTiling::OPTIMAL)
+                    .usage(vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED)
+                    .sharing_mode(vk::SharingMode::EXCLUSIVE)
+                    .initial_layout(vk::ImageLayout::UNDEFINED)
+                    .build(),
+                None,
+            )
+            .unwrap();
+        let memory =
+            allocator.alloc_for_image(device, image, gpu_alloc::UsageFlags::FAST_DEVICE_ACCESS);
+        self.depth_memory.push(memory);
+        let view = device
+            .create_image_view(
+                &vk::ImageViewCreateInfo::builder()
+                    .image(image)
+                    .view_type(vk::ImageViewType::TYPE_2D)
+                    .format(DEPTH_FORMAT)
+                    .subresource_range(vk::ImageSubresourceRange {
+                        aspect_mask: vk::ImageAspectFlags::COLOR,
+                        base_mip_level: 0,
+                        level_count: 1,
+                        base_array_layer: 0,
+                        layer_count: 1,
+                    })
+                    .build(),
+                None,
+            )
+            .unwrap();
+        (image, view)
+    }
+
     pub unsafe fn next_image(
         &mut self,
         device: &ash::Device,
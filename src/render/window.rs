@@ -11,6 +11,7 @@ use bevy::window::{WindowId, Windows};
 use std::ffi::c_void;
 use std::mem::MaybeUninit;
 use std::ops::DerefMut;
+use std::sync::Arc;
 
 use super::swapchain::SurfaceState;
 pub const NUM_FRAMES_IN_FLIGHT: u32 = 3;
@@ -25,7 +26,8 @@ pub struct RenderState {
     pub device_uniform_buffer: vk::Buffer,
     pub device_uniform_memory: crate::MemoryBlock,
     pub host_uniform_memory: crate::MemoryBlock,
-    // The command pool for per-frame rendering commands. NUM_FRAMES_IN_FLIGHT commands will be allocated from this.
+    // The command pool for per-frame rendering commands. NUM_FRAMES_IN_FLIGHT commands will be allocated from this,
+    // plus NUM_FRAMES_IN_FLIGHT for each window.
     pub command_pool: vk::CommandPool,
 
     pub per_window_desc_set_layout: vk::DescriptorSetLayout,
@@ -78,9 +80,6 @@ impl RenderState {
                 .unwrap();
             frame.write(Frame {
                 index: i as u32,
-                render_finished_semaphore: device
-                    .create_semaphore(&vk::SemaphoreCreateInfo::default(), None)
-                    .unwrap(),
                 fence: device
                     .create_fence(
                         &vk::FenceCreateInfo::builder()
@@ -108,7 +107,7 @@ impl RenderState {
                             .build(),
                         vk::DescriptorSetLayoutBinding::builder()
                             .binding(1)
-                            .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                            .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC)
                             .descriptor_count(1)
                             .stage_flags(vk::ShaderStageFlags::RAYGEN_KHR)
                             .build(),
@@ -127,6 +126,12 @@ impl RenderState {
                             .descriptor_count(1)
                             .stage_flags(vk::ShaderStageFlags::INTERSECTION_KHR)
                             .build(),
+                        vk::DescriptorSetLayoutBinding::builder()
+                            .binding(4)
+                            .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                            .descriptor_count(1)
+                            .stage_flags(vk::ShaderStageFlags::RAYGEN_KHR)
+                            .build(),
                     ])
                     .build(),
                 None,
@@ -212,14 +217,19 @@ impl RenderState {
          for frame in self.frames_in_flight.iter_mut() {
             frame.command_buffer_needs_update = true;
          }
+        for window in self.windows.values_mut() {
+            if let Some(state) = window.state.as_mut() {
+                state.command_buffer_flush_all_frames();
+            }
+        }
     }
 }
 
 #[derive(Clone)]
 pub struct Frame {
     pub(crate) index: u32,
-    pub(crate) render_finished_semaphore: vk::Semaphore,
     pub(crate) fence: vk::Fence,
+    // Commands shared by all windows, submitted before the commands of the windows.
     pub(crate) command_buffer: vk::CommandBuffer,
     pub(crate) command_buffer_needs_update: bool,
 
@@ -317,6 +327,7 @@ pub fn prepare_windows(
     swapchain_loader: Res<ash::extensions::khr::Swapchain>,
     physical_device: Res<vk::PhysicalDevice>,
     queues: Res<crate::Queues>,
+    mut allocator: ResMut<crate::Allocator>,
 ) {
     let render_state = render_state.deref_mut();
     let frame_in_flight = render_state.current_frame().clone();
@@ -329,14 +340,12 @@ pub fn prepare_windows(
             .unwrap();
     }
 
-    let mut needs_update_command_buffer = false;
-
     for window in render_state.windows.values_mut() {
         let surface_state = match &mut window.state {
             Some(state) => unsafe {
                 if window.size_changed {
-                    needs_update_command_buffer = true;
-                    state.destroy_swapchain(&device, &swapchain_loader);
+                    state.command_buffer_flush_all_frames();
+                    state.destroy_swapchain(&device, &swapchain_loader, &mut allocator);
                     state.build_swapchain(
                         render_state.per_window_desc_set_layout,
                         &instance,
@@ -345,6 +354,7 @@ pub fn prepare_windows(
                         &swapchain_loader,
                         *physical_device,
                         &queues,
+                        &mut allocator,
                     );
                 }
                 state
@@ -357,8 +367,8 @@ pub fn prepare_windows(
                     *physical_device,
                     &surface_loader,
                     &window.handle,
+                    render_state.command_pool,
                 );
-                needs_update_command_buffer = true;
                 state.build_swapchain(
                     render_state.per_window_desc_set_layout,
                     &instance,
@@ -367,6 +377,7 @@ pub fn prepare_windows(
                     &swapchain_loader,
                     *physical_device,
                     &queues,
+                    &mut allocator,
                 );
                 window.state = Some(state);
                 window.state.as_mut().unwrap()
@@ -375,73 +386,117 @@ pub fn prepare_windows(
 
         let swapchain_image =
             unsafe { surface_state.next_image(&device, &frame_in_flight, &swapchain_loader) };
-
-        unsafe {
-            device.reset_fences(&[frame_in_flight.fence]).unwrap();
-        }
         window.swapchain_image = Some(swapchain_image)
     }
 
-    if needs_update_command_buffer {
-        render_state.command_buffer_flush_all_frames();
+    // The render system submits the frame even without any window.
+    unsafe {
+        device.reset_fences(&[frame_in_flight.fence]).unwrap();
     }
 }
 
 pub fn render_system(world: &mut bevy::ecs::world::World) {
-    let (mut render_state, device, swapchain_loader, queues) = SystemState::<(
+    let (mut render_state, device, swapchain_loader, queues, block_allocator) = SystemState::<(
         ResMut<RenderState>,
         Res<ash::Device>,
         Res<ash::extensions::khr::Swapchain>,
         Res<crate::Queues>,
+        Option<Res<Arc<dyn crate::raytrace::BlockAllocator>>>,
     )>::new(world)
     .get_mut(world);
 
     let current_frame = render_state.current_frame().clone();
-
+    let frame_index = current_frame.index as usize;
+    // Wait for the sparse bindings of the voxel memory submitted so far.
+    let bind_timeline = block_allocator.and_then(|allocator| allocator.bind_timeline());
+
+    // Per-window submissions and presentations, in the order of the windows.
+    let mut wait_semaphores: Vec<Vec<vk::Semaphore>> = Vec::new();
+    let mut wait_values: Vec<Vec<u64>> = Vec::new();
+    let mut wait_stages: Vec<Vec<vk::PipelineStageFlags>> = Vec::new();
+    let mut command_buffers: Vec<vk::CommandBuffer> = Vec::new();
+    let mut render_finished_semaphores: Vec<vk::Semaphore> = Vec::new();
+    let mut swapchains: Vec<vk::SwapchainKHR> = Vec::new();
+    let mut image_indices: Vec<u32> = Vec::new();
     for window in render_state.windows.values_mut() {
-        if window.state.is_none() {
-            println!("Surface not initiallized... skipped");
-        }
         // Per-window state with information regarding the current window
-        let surface_state = window.state.as_ref().unwrap();
+        let surface_state = match window.state.as_ref() {
+            Some(state) => state,
+            None => {
+                println!("Surface not initiallized... skipped");
+                continue;
+            }
+        };
 
         // Per-window image obtained from the prepare stage with current swapchain frame information.
         let swapchain_image = window
             .swapchain_image
             .take()
             .expect("The swapchain texture was never generated or already consumed.");
-        let swapchain_image_available_semaphore =
-            surface_state.image_available_semaphore[current_frame.index as usize];
-        unsafe {
-            // TODO: If we were to support multiple windows in the future, we can potentially batch submissions here.
-            device
-                .queue_submit(
-                    queues.graphics_queue,
-                    &[vk::SubmitInfo::builder()
-                        .wait_semaphores(&[swapchain_image_available_semaphore]) // swapchain image available semaphore
-                        // Wait for swapchain image to become available before starting ray tracing
-                        .wait_dst_stage_mask(&[vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR])
-                        .command_buffers(&[current_frame.command_buffer])
-                        .signal_semaphores(&[current_frame.render_finished_semaphore])
-                        .build()],
-                    current_frame.fence,
-                )
-                .unwrap();
+        // Wait for swapchain image to become available before starting ray tracing
+        let mut semaphores = vec![surface_state.image_available_semaphore[frame_index]];
+        // The value is ignored for binary semaphores.
+        let mut values = vec![0];
+        if let Some((semaphore, value)) = bind_timeline {
+            semaphores.push(semaphore);
+            values.push(value);
+        }
+        wait_stages.push(vec![
+            vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR;
+            semaphores.len()
+        ]);
+        wait_semaphores.push(semaphores);
+        wait_values.push(values);
+        command_buffers.push(surface_state.command_buffers[frame_index]);
+        render_finished_semaphores.push(surface_state.render_finished_semaphore[frame_index]);
+        swapchains.push(surface_state.swapchain);
+        image_indices.push(swapchain_image.index);
+    }
 
-            let suboptimal = swapchain_loader
-                .queue_present(
-                    queues.graphics_queue,
-                    &vk::PresentInfoKHR::builder()
-                        .wait_semaphores(&[current_frame.render_finished_semaphore])
-                        .swapchains(&[surface_state.swapchain])
-                        .image_indices(&[swapchain_image.index])
-                        .build(),
-                )
-                .unwrap();
+    let mut timeline_infos: Vec<vk::TimelineSemaphoreSubmitInfo> = wait_values
+        .iter()
+        .map(|values| {
+            vk::TimelineSemaphoreSubmitInfo::builder()
+                .wait_semaphore_values(values)
+                .build()
+        })
+        .collect();
+    // The uploads shared by the windows go first. Everything is submitted at once.
+    let mut submits = vec![vk::SubmitInfo::builder()
+        .command_buffers(std::slice::from_ref(&current_frame.command_buffer))
+        .build()];
+    for (i, timeline_info) in timeline_infos.iter_mut().enumerate() {
+        submits.push(
+            vk::SubmitInfo::builder()
+                .wait_semaphores(&wait_semaphores[i])
+                .wait_dst_stage_mask(&wait_stages[i])
+                .command_buffers(&command_buffers[i..=i])
+                .signal_semaphores(&render_finished_semaphores[i..=i])
+                .push_next(timeline_info)
+                .build(),
+        );
+    }
+    unsafe {
+        device
+            .queue_submit(queues.graphics_queue, &submits, current_frame.fence)
+            .unwrap();
 
-            if suboptimal {
-                println!("Suboptimal~!!!");
-            }
+        if swapchains.is_empty() {
+            return;
+        }
+        let suboptimal = swapchain_loader
+            .queue_present(
+                queues.graphics_queue,
+                &vk::PresentInfoKHR::builder()
+                    .wait_semaphores(&render_finished_semaphores)
+                    .swapchains(&swapchains)
+                    .image_indices(&image_indices)
+                    .build(),
+            )
+            .unwrap();
+
+        if suboptimal {
+            println!("Suboptimal~!!!");
         }
     }
 }